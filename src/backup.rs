@@ -0,0 +1,639 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use aes::Aes256;
+use aes_kw::Kek;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::{enc_reader, enc_writer, manifest, manifestdb, sqliteview, vfs};
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum SnapshotPolicy {
+    // Refuse to open a backup whose snapshot is still in progress
+    Refuse,
+    // Ignore the in-progress Snapshot/ directory and use the last completed backup
+    Consistent,
+}
+
+impl std::str::FromStr for SnapshotPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refuse" => Ok(SnapshotPolicy::Refuse),
+            "consistent" => Ok(SnapshotPolicy::Consistent),
+            _ => Err(format!("Unknown snapshot policy: {}", s)),
+        }
+    }
+}
+
+pub(crate) struct Backup {
+    pub path: PathBuf,
+    pub manifest: manifest::Manifest,
+    pub status: Option<manifest::Status>,
+    pub keys: BTreeMap<u32, Kek<Aes256>>,
+    pub con: Connection,
+    vfs: &'static str,
+}
+
+impl Backup {
+    pub fn open(path: PathBuf, password: &str, policy: SnapshotPolicy) -> Result<Self, String> {
+        eprintln!("** READING {}", path.join("Manifest.plist").display());
+
+        let manifest = manifest::read_manifest(&path)?;
+        // A Status.plist that can't be read may be in the middle of being
+        // written, so the backup is taken as in progress
+        let (status, in_progress) = match manifest::read_status(&path) {
+            Ok(status) => {
+                let unfinished = status.as_ref().is_some_and(|s| !s.is_finished());
+                (status, unfinished || path.join("Snapshot").is_dir())
+            }
+            Err(e) => {
+                eprintln!(
+                    "warning: unreadable_status path={} error={}",
+                    path.display(),
+                    e
+                );
+                (None, true)
+            }
+        };
+
+        if in_progress {
+            match policy {
+                SnapshotPolicy::Refuse => {
+                    return Err(format!(
+                        "Backup snapshot in progress: {} (use --snapshot=consistent to mount the last completed backup)",
+                        path.display()
+                    ))
+                }
+                SnapshotPolicy::Consistent => {
+                    if !path.join("Manifest.db").is_file() {
+                        return Err(format!(
+                            "Backup snapshot in progress and no completed backup to fall back to: {}",
+                            path.display()
+                        ));
+                    }
+                    eprintln!(
+                        "Backup snapshot in progress, ignoring {}",
+                        path.join("Snapshot").display()
+                    );
+                }
+            }
+        }
+
+        eprintln!("** VERIFYING PASSPHRASE");
+
+        let keys = verify_passphrase(&manifest.backup_key_bag, password.as_bytes())
+            .map_err(|e| format!("{}: {}", e, path.display()))?;
+
+        let mut manifestdb_key = [0u8; 32];
+        let manifest_key = manifest.manifest_key.as_ref();
+        manifest_key
+            .get(..4)
+            .and_then(|class| keys.get(&u32::from_le_bytes(class.try_into().unwrap())))
+            .and_then(|kek| kek.unwrap(&manifest_key[4..], &mut manifestdb_key).ok())
+            .ok_or_else(|| format!("Can't unwrap the key of Manifest.db: {}", path.display()))?;
+
+        let vfs = vfs::register(manifestdb_key);
+
+        let con = Connection::open_with_flags_and_vfs(
+            path.join("Manifest.db"),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            vfs,
+        )
+        .and_then(|con| con.pragma_update(None, "temp_store", "MEMORY").map(|_| con))
+        .map_err(|e| format!("Can't open Manifest.db of {}: {}", path.display(), e))?;
+
+        Ok(Backup {
+            path,
+            manifest,
+            status,
+            keys,
+            con,
+            vfs,
+        })
+    }
+
+    // Reopens Manifest.db for writing. Journals and temporary files are kept in
+    // memory, and each committed transaction encrypts the database again.
+    pub fn make_writable(&mut self) -> rusqlite::Result<()> {
+        self.con = Connection::open_with_flags_and_vfs(
+            self.path.join("Manifest.db"),
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            self.vfs,
+        )?;
+        self.con
+            .pragma_update_and_check(None, "locking_mode", "EXCLUSIVE", |_| Ok(()))?;
+        self.con
+            .pragma_update_and_check(None, "journal_mode", "MEMORY", |_| Ok(()))?;
+        self.con.pragma_update(None, "temp_store", "MEMORY")?;
+        Ok(())
+    }
+
+    // Name used for this backup when several are mounted side by side
+    pub fn label(&self) -> String {
+        self.manifest.date.format("%Y-%m-%d_%H-%M-%S").to_string()
+    }
+
+    // Labels of backups mounted side by side. Backups made in the same second
+    // also get the name of their folder (the UDID of the device), then their
+    // position if that isn't enough.
+    pub fn labels(backups: &[Backup]) -> Vec<String> {
+        let dates: Vec<String> = backups.iter().map(Backup::label).collect();
+        let mut used = std::collections::HashSet::new();
+        let mut labels = Vec::new();
+        for (i, (backup, date)) in backups.iter().zip(&dates).enumerate() {
+            let mut label = date.clone();
+            if dates.iter().filter(|x| *x == date).count() > 1 {
+                let folder = backup.path.file_name().unwrap_or_default();
+                label = format!("{}_{}", date, folder.to_string_lossy());
+            }
+            if used.contains(&label) {
+                label = format!("{}_{}", label, i + 1);
+            }
+            used.insert(label.clone());
+            labels.push(label);
+        }
+        labels
+    }
+
+    pub fn blob_path(&self, id: &str) -> PathBuf {
+        let mut path = self.path.join(&id[0..2]);
+        path.push(id);
+        path
+    }
+
+    // None if the key is missing, too short to hold its class, or can't be
+    // unwrapped
+    pub fn file_key(&self, mbfile: &manifestdb::MBFile) -> Option<[u8; 32]> {
+        let encdata = &mbfile.encryption_key.as_ref()?.data;
+        if encdata.as_ref().len() < 4 {
+            return None;
+        }
+
+        let mut key = [0; 32];
+
+        self.keys
+            .get(&u32::from_le_bytes(encdata.as_ref()[0..4].try_into().unwrap()))?
+            .unwrap(&encdata.as_ref()[4..], &mut key)
+            .ok()?;
+
+        Some(key)
+    }
+
+    // Plaintext length given by the blob's padding (None if invalid) and the
+    // length of its ciphertext, None if the blob or key is unavailable
+    pub fn blob_sizes(&self, id: &str, mbfile: &manifestdb::MBFile) -> Option<(Option<u64>, u64)> {
+        let key = self.file_key(mbfile)?;
+        let f = std::fs::File::open(self.blob_path(id)).ok()?;
+        let reader = enc_reader::DecryptingReader::new(f, key).ok()?;
+        Some((reader.padded_len(), reader.ciphertext_len()))
+    }
+
+    // Domain, relativePath, flags and MBFile of a record
+    pub fn record(&self, id: &str) -> rusqlite::Result<(String, String, i64, manifestdb::MBFile)> {
+        self.con.query_row(
+            "SELECT domain, relativePath, flags, file FROM Files WHERE fileID = ?",
+            [id],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+    }
+
+    // fileID, domain, relativePath, flags and MBFile of every record, in domain
+    // and relativePath order. Records whose MBFile can't be decoded are reported
+    // and left out.
+    pub fn records(&self) -> Vec<(String, String, String, i64, manifestdb::MBFile)> {
+        let mut sta = self
+            .con
+            .prepare(
+                "SELECT fileID, domain, relativePath, flags, file FROM Files ORDER BY domain, relativePath",
+            )
+            .unwrap();
+        let mut rows = sta.query(()).unwrap();
+
+        let mut records = Vec::new();
+        while let Some(row) = rows.next().unwrap() {
+            let id: String = row.get(0).unwrap();
+            let file = match row.get(4) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("warning: invalid_mbfile file_id={} error={}", id, e);
+                    continue;
+                }
+            };
+            records.push((
+                id,
+                row.get(1).unwrap(),
+                row.get(2).unwrap(),
+                row.get(3).unwrap(),
+                file,
+            ));
+        }
+        records
+    }
+
+    // Adds a record or replaces the one with the same fileID
+    pub fn save_record(
+        &self,
+        id: &str,
+        domain: &str,
+        path: &str,
+        flags: i64,
+        mbfile: &manifestdb::MBFile,
+    ) -> rusqlite::Result<()> {
+        self.con.execute(
+            "INSERT OR REPLACE INTO Files (fileID, domain, relativePath, flags, file) VALUES (?, ?, ?, ?, ?)",
+            (id, domain, path, flags, mbfile.to_bytes()),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_record(&self, id: &str) -> rusqlite::Result<()> {
+        self.con
+            .execute("DELETE FROM Files WHERE fileID = ?", [id])?;
+        Ok(())
+    }
+
+    // Protection class new data of a file is encrypted with, always its own so
+    // a change never makes it readable in other device states. None if its
+    // class key is unavailable.
+    fn writable_class(&self, class: u32) -> Option<u32> {
+        self.keys.contains_key(&class).then_some(class)
+    }
+
+    // Encrypts data into the blob of a file with a new key, and records the new
+    // key, size, digest and modification time in its MBFile
+    pub fn write_blob(
+        &self,
+        id: &str,
+        mbfile: &mut manifestdb::MBFile,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        use sha1::Digest;
+
+        let class = self
+            .writable_class(mbfile.protection_class as u32)
+            .ok_or(std::io::ErrorKind::PermissionDenied)?;
+
+        let key = enc_writer::random_key()?;
+        let mut wrapped = [0u8; 40];
+        self.keys[&class].wrap(&key, &mut wrapped).unwrap();
+        let mut encryption_key = class.to_le_bytes().to_vec();
+        encryption_key.extend_from_slice(&wrapped);
+
+        let ciphertext = enc_writer::encrypt(&key, data);
+        let path = self.blob_path(id);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        enc_writer::write_atomic(&path, &ciphertext)?;
+
+        let now = now();
+        mbfile.encryption_key = Some(manifestdb::NSMutableData::new(encryption_key));
+        mbfile.digest = Some(sha1::Sha1::digest(&ciphertext).to_vec().into());
+        mbfile.protection_class = class as u64;
+        mbfile.size = data.len() as u64;
+        mbfile.last_modified = now;
+        mbfile.last_status_change = now;
+        Ok(())
+    }
+
+    // fileID and MBFile of the non-empty file stored next to a database with
+    // suffix appended to its name, such as its -wal, if its blob is present
+    fn companion(&self, id: &str, suffix: &str) -> Option<(String, manifestdb::MBFile)> {
+        let (domain, path, _, _) = self.record(id).ok()?;
+        let companion_id = file_id(&domain, &format!("{}{}", path, suffix));
+        let (_, _, _, mbfile) = self.record(&companion_id).ok()?;
+        if mbfile.size == 0 || !self.blob_path(&companion_id).is_file() {
+            return None;
+        }
+        Some((companion_id, mbfile))
+    }
+
+    // Whether a database has a -wal holding changes not yet in the database file
+    pub fn has_wal(&self, id: &str) -> bool {
+        self.companion(id, "-wal").is_some()
+    }
+
+    // Decrypted -wal of a database, None if it has none
+    pub fn read_wal(&self, id: &str) -> Option<Vec<u8>> {
+        let (wal_id, mbfile) = self.companion(id, "-wal")?;
+        self.read_file(&wal_id, &mbfile)
+    }
+
+    // Opens a database of the backup read only, decrypted as it is read. For
+    // the latest snapshot its -wal, stored as a backup file of its own, is
+    // replayed if present. A -journal left by an interrupted transaction is
+    // not rolled back.
+    pub fn open_database(
+        &self,
+        id: &str,
+        snapshot: sqliteview::Snapshot,
+    ) -> Result<Connection, String> {
+        let (_, _, _, mbfile) = self.record(id).map_err(|e| e.to_string())?;
+        let key = self
+            .file_key(&mbfile)
+            .ok_or_else(|| format!("Can't unwrap key of {}", id))?;
+        let db = vfs::BlobFile {
+            path: self.blob_path(id),
+            key,
+            size: mbfile.size,
+        };
+
+        if self.companion(id, "-journal").is_some() {
+            eprintln!("warning: hot_journal_ignored file_id={}", id);
+        }
+
+        let wal = match snapshot {
+            sqliteview::Snapshot::Latest => self.companion(id, "-wal"),
+            sqliteview::Snapshot::BeforeWal => None,
+        };
+        let wal = match wal {
+            Some((wal_id, wal_mbfile)) => Some(vfs::BlobFile {
+                path: self.blob_path(&wal_id),
+                key: self
+                    .file_key(&wal_mbfile)
+                    .ok_or_else(|| format!("Can't unwrap key of {}", wal_id))?,
+                size: wal_mbfile.size,
+            }),
+            None => None,
+        };
+
+        let con = Connection::open_with_flags_and_vfs(
+            vfs::file_uri(&db, wal.as_ref()),
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                | OpenFlags::SQLITE_OPEN_URI,
+            vfs::files(),
+        )
+        .map_err(|e| e.to_string())?;
+        con.pragma_update(None, "temp_store", "MEMORY")
+            .map_err(|e| e.to_string())?;
+        if wal.is_some() {
+            con.pragma_update(None, "locking_mode", "EXCLUSIVE")
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(con)
+    }
+
+    // Decrypts the first len bytes of a file, None if its blob or key is unavailable
+    pub fn read_head(&self, id: &str, mbfile: &manifestdb::MBFile, len: usize) -> Option<Vec<u8>> {
+        let key = self.file_key(mbfile)?;
+        let f = std::fs::File::open(self.blob_path(id)).ok()?;
+        let reader = enc_reader::DecryptingReader::new(f, key).ok()?;
+        let mut head = vec![0; std::cmp::min(len as u64, mbfile.size) as usize];
+        reader.read_at(&mut head, 0).ok()?;
+        Some(head)
+    }
+
+    // Decrypts a whole file into memory, None if its blob or key is unavailable
+    pub fn read_file(&self, id: &str, mbfile: &manifestdb::MBFile) -> Option<Vec<u8>> {
+        let key = self.file_key(mbfile)?;
+        let f = std::fs::File::open(self.blob_path(id)).ok()?;
+
+        let mut reader = enc_reader::DecryptingReader::new(f, key).ok()?;
+        reader.set_len(mbfile.size);
+
+        let mut buffer = Vec::new();
+        std::io::Read::read_to_end(&mut reader, &mut buffer).ok()?;
+        Some(buffer)
+    }
+}
+
+// fileID of a record, which determines where its blob is stored
+pub(crate) fn file_id(domain: &str, path: &str) -> String {
+    use sha1::Digest;
+    crate::util::hex(&sha1::Sha1::digest(format!("{}-{}", domain, path)))
+}
+
+// Seconds since the epoch, as used by MBFile times
+pub(crate) fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn verify_passphrase(
+    bkb: &manifest::KeyBag,
+    password: &[u8],
+) -> Result<BTreeMap<u32, Kek<Aes256>>, String> {
+    let mut res = BTreeMap::new();
+
+    let mut round1 = [0u8; 32];
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password, &bkb.dpsl, bkb.dpic, &mut round1);
+    pbkdf2::pbkdf2_hmac::<sha1::Sha1>(&round1, &bkb.salt, bkb.iter, &mut key);
+
+    let kek = aes_kw::Kek::from(key);
+    for x in &bkb.others {
+        if x.wrap & 0x2 == 0x2 {
+            let mut ukey = [0u8; 32];
+            if let Err(x) = kek.unwrap(&x.wpky, &mut ukey) {
+                if let aes_kw::Error::IntegrityCheckFailed = x {
+                    return Err("Incorrect Passphrase".to_owned());
+                }
+                return Err(format!("Unknown AES_KW ERROR {}", x));
+            }
+            res.insert(x.clas, aes_kw::Kek::from(ukey));
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Folder removed with everything in it when dropped
+    pub(crate) struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "iphonebackupfs-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Packs files, given as domain, relativePath and content, into a backup
+    // in dir with the passphrase "pw"
+    pub(crate) fn pack_files(dir: &TempDir, files: &[(&str, &str, &[u8])]) -> Backup {
+        let source = dir.0.join("source");
+        for (domain, path, data) in files {
+            let path = source.join(domain).join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+        let output = dir.0.join("backup");
+        let options = crate::pack::Options {
+            base: None,
+            iterations: 1,
+            protection_class: 3,
+        };
+        crate::pack::pack(&source, &output, "pw", options).unwrap();
+        Backup::open(output, "pw", SnapshotPolicy::Refuse).unwrap()
+    }
+
+    #[test]
+    fn writes_need_the_file_class_key() {
+        let dir = TempDir::new("writable-class");
+        let backup = pack_files(&dir, &[("HomeDomain", "a.txt", b"one")]);
+        let id = file_id("HomeDomain", "a.txt");
+        let (_, _, _, mut mbfile) = backup.record(&id).unwrap();
+
+        backup.write_blob(&id, &mut mbfile, b"two").unwrap();
+        assert_eq!(mbfile.protection_class, 3);
+        assert_eq!(backup.read_file(&id, &mbfile).unwrap(), b"two");
+
+        // No other class stands in for a missing one
+        mbfile.protection_class = 12;
+        let e = backup.write_blob(&id, &mut mbfile, b"three").unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(mbfile.protection_class, 12);
+    }
+
+    #[test]
+    fn open_reports_errors() {
+        let dir = TempDir::new("open-errors");
+        let backup = pack_files(&dir, &[("HomeDomain", "a.txt", b"one")]);
+        let path = backup.path.clone();
+        drop(backup);
+
+        let e = Backup::open(path.clone(), "wrong", SnapshotPolicy::Refuse).err();
+        assert!(e.unwrap().starts_with("Incorrect Passphrase"));
+
+        // A Status.plist being written is taken as an unfinished backup
+        std::fs::write(path.join("Status.plist"), b"<?xml").unwrap();
+        assert!(Backup::open(path.clone(), "pw", SnapshotPolicy::Refuse).is_err());
+        assert!(Backup::open(path.clone(), "pw", SnapshotPolicy::Consistent).is_ok());
+
+        std::fs::remove_file(path.join("Manifest.plist")).unwrap();
+        assert!(Backup::open(path, "pw", SnapshotPolicy::Consistent).is_err());
+    }
+
+    #[test]
+    fn short_keys_are_rejected() {
+        let dir = TempDir::new("short-key");
+        let backup = pack_files(&dir, &[("HomeDomain", "a.txt", b"one")]);
+        let (_, _, _, mut mbfile) = backup.record(&file_id("HomeDomain", "a.txt")).unwrap();
+        assert!(backup.file_key(&mbfile).is_some());
+        mbfile.encryption_key.as_mut().unwrap().data = vec![3, 0].into();
+        assert_eq!(backup.file_key(&mbfile), None);
+    }
+
+    #[test]
+    fn labels_of_backups_made_in_the_same_second() {
+        let dir = TempDir::new("labels");
+        let path = pack_files(&dir, &[("HomeDomain", "a.txt", b"one")]).path;
+        let mut backups: Vec<Backup> = ["udid-a", "udid-a", "udid-b", "udid-c"]
+            .iter()
+            .map(|udid| {
+                let mut backup = Backup::open(path.clone(), "pw", SnapshotPolicy::Refuse).unwrap();
+                backup.path = dir.0.join(udid);
+                backup
+            })
+            .collect();
+        backups[3].manifest.date += chrono::Duration::seconds(1);
+
+        let label = backups[0].label();
+        assert_eq!(
+            Backup::labels(&backups),
+            [
+                format!("{}_udid-a", label),
+                format!("{}_udid-a_2", label),
+                format!("{}_udid-b", label),
+                backups[3].label(),
+            ]
+        );
+    }
+
+    // Database in WAL mode whose WAL holds two commits not yet checkpointed,
+    // with the WAL size after the first one
+    fn wal_database(dir: &TempDir) -> (Vec<u8>, Vec<u8>, u64) {
+        let path = dir.0.join("wal.sqlite");
+        let con = Connection::open(&path).unwrap();
+        con.pragma_update(None, "journal_mode", "WAL").unwrap();
+        con.execute_batch(
+            "CREATE TABLE t (x TEXT);
+             INSERT INTO t VALUES ('checkpointed');
+             PRAGMA wal_checkpoint(TRUNCATE);
+             INSERT INTO t VALUES ('first');",
+        )
+        .unwrap();
+        let wal = path.with_extension("sqlite-wal");
+        let first = std::fs::metadata(&wal).unwrap().len();
+        con.execute("INSERT INTO t VALUES ('second')", []).unwrap();
+
+        // Read while the connection is open, as closing it checkpoints
+        let data = (std::fs::read(&path).unwrap(), std::fs::read(&wal).unwrap());
+        drop(con);
+        (data.0, data.1, first)
+    }
+
+    fn rows(backup: &Backup, snapshot: sqliteview::Snapshot) -> Vec<String> {
+        let con = backup
+            .open_database(&file_id("HomeDomain", "wal.sqlite"), snapshot)
+            .unwrap();
+        let mut sta = con.prepare("SELECT x FROM t ORDER BY rowid").unwrap();
+        let rows = sta.query_map([], |row| row.get(0)).unwrap();
+        rows.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn uncheckpointed_rows_are_only_in_latest() {
+        let dir = TempDir::new("wal-snapshots");
+        let (db, wal, _) = wal_database(&dir);
+        // The header marks the database as in WAL mode
+        assert_eq!(db[18..20], [2, 2]);
+        let backup = pack_files(
+            &dir,
+            &[
+                ("HomeDomain", "wal.sqlite", &db),
+                ("HomeDomain", "wal.sqlite-wal", &wal),
+            ],
+        );
+
+        assert_eq!(
+            rows(&backup, sqliteview::Snapshot::Latest),
+            ["checkpointed", "first", "second"]
+        );
+        assert_eq!(
+            rows(&backup, sqliteview::Snapshot::BeforeWal),
+            ["checkpointed"]
+        );
+    }
+
+    #[test]
+    fn wal_is_read_up_to_its_recorded_size() {
+        let dir = TempDir::new("wal-size");
+        let (db, wal, first) = wal_database(&dir);
+        let mut backup = pack_files(
+            &dir,
+            &[
+                ("HomeDomain", "wal.sqlite", &db),
+                ("HomeDomain", "wal.sqlite-wal", &wal),
+            ],
+        );
+        backup.make_writable().unwrap();
+        let id = file_id("HomeDomain", "wal.sqlite-wal");
+        let (domain, path, flags, mut mbfile) = backup.record(&id).unwrap();
+        mbfile.size = first;
+        backup
+            .save_record(&id, &domain, &path, flags, &mbfile)
+            .unwrap();
+
+        assert_eq!(
+            rows(&backup, sqliteview::Snapshot::Latest),
+            ["checkpointed", "first"]
+        );
+    }
+}
@@ -1,88 +1,398 @@
 use std::{
-    collections::BTreeMap,
-    ffi::c_int,
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{c_int, OsStr},
     time::{Duration, SystemTime},
 };
 
 use crate::{
-    enc_reader::{self, CbcCache},
+    backup::{self, Backup},
+    enc_reader::DecryptingReader,
     manifestdb::{self, FileType},
+    plistview, sqliteview,
 };
-use aes::Aes256;
-use aes_kw::Kek;
-use fuser::FileAttr;
-use rusqlite::{CachedStatement, Connection};
+use fuser::{FileAttr, TimeOrNow};
+use rusqlite::CachedStatement;
 use sha1::Digest;
 
+const EPERM: c_int = 1;
 const ENOENT: c_int = 2;
 const EIO: c_int = 5;
 const E2BIG: c_int = 7;
+const EBADF: c_int = 9;
+const EACCES: c_int = 13;
+const EEXIST: c_int = 17;
 const ENOTDIR: c_int = 20;
+const EISDIR: c_int = 21;
+const EINVAL: c_int = 22;
+const EFBIG: c_int = 27;
+const EROFS: c_int = 30;
 const ERANGE: c_int = 34;
 const ENOSYS: c_int = 38;
+const ENOTEMPTY: c_int = 39;
 const ENODATA: c_int = 61;
-pub(crate) struct BackupFS<'db> {
+
+const O_ACCMODE: i32 = 3;
+const RENAME_NOREPLACE: u32 = 1;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o40000;
+
+// Decrypted ahead of each read so sequential reads are served from memory
+const READAHEAD: usize = 256 * 1024;
+
+// Largest file written through the mount, which holds files being written in memory
+const MAX_WRITE_SIZE: u64 = 4 << 30;
+
+// Backup a file was read from when several backups are mounted
+const XATTR_BACKUP: &str = "user.iphonebackupfs.backup";
+
+// Set on files whose blob is absent from the backup, which are served empty
+// and without permissions, and fail to open with ENOENT
+const XATTR_MISSING: &str = "user.iphonebackupfs.missing";
+
+// Statistics of the decrypted page cache, on the root folder
+const XATTR_CACHE: &str = "user.iphonebackupfs.cache";
+
+// Size of the blocks reported by statfs
+const BLOCK_SIZE: u64 = 4096;
+
+pub(crate) struct BackupFS {
     fs: crate::manifestdb::FS,
-    con: &'db rusqlite::Connection,
-    keys: BTreeMap<u32, Kek<Aes256>>,
-    basepath: std::path::PathBuf,
+    backups: Vec<Backup>,
     options: Options,
+    stable_inodes: Option<StableInodes>,
+    // Plaintext bytes and number of files and folders, computed on the first
+    // statfs and kept unless the backup can change
+    totals: Option<(u64, u64)>,
+    // Plaintext length given by the padding of each blob read so far, by
+    // inode, when trusting the padding
+    padded_sizes: RefCell<HashMap<usize, u64>>,
 }
 
-struct Options {
-    verify_digests: bool,
+// Inode numbers derived from the fileID (or the path for folders and views
+// created by the mount) instead of indices into the backing, so they are the same on
+// every mount of a backup
+struct StableInodes {
+    index: std::collections::HashMap<u64, usize>,
+    number: Vec<u64>,
+    // Set when several backups are mounted, where a fileID can be found in each
+    // backup and again in diff folders: the path in the mount is then hashed
+    // along with it
+    with_path: bool,
 }
 
-impl<'con> BackupFS<'con> {
-    pub(crate) fn new(
-        fs: crate::manifestdb::FS,
-        con: &'con Connection,
-        keys: BTreeMap<u32, Kek<Aes256>>,
-        basepath: std::path::PathBuf,
-    ) -> Self {
-        con.prepare_cached("SELECT file FROM Files WHERE fileID = ?")
-            .unwrap();
+impl StableInodes {
+    fn new(fs: &manifestdb::FS, with_path: bool) -> Self {
+        let mut stable = StableInodes {
+            index: std::collections::HashMap::new(),
+            number: Vec::new(),
+            with_path,
+        };
+        stable.add(1, 1);
+
+        let mut stack = vec![(String::new(), 1)];
+        while let Some((path, inode_nr)) = stack.pop() {
+            for (name, &child) in fs.backing[inode_nr].children.iter().flatten() {
+                let path = format!("{}/{}", path, name);
+                stable.add_derived(fs, child, &path);
+                stack.push((path, child));
+            }
+        }
+        stable
+    }
+
+    fn add(&mut self, inode_nr: usize, number: u64) {
+        if self.number.len() <= inode_nr {
+            self.number.resize(inode_nr + 1, 0);
+        }
+        self.number[inode_nr] = number;
+        self.index.insert(number, inode_nr);
+    }
+
+    // Numbers are below 2^63, the index with the top bit set is used instead
+    // in the unlikely case of a collision
+    fn add_derived(&mut self, fs: &manifestdb::FS, inode_nr: usize, path: &str) {
+        let inode = &fs.backing[inode_nr];
+        let hash = match (inode.backup, &inode.view) {
+            (Some(_), None) if !self.with_path => *inode.id.as_bytes(),
+            (Some(_), None) => sha1::Sha1::new()
+                .chain_update(inode.id.as_bytes())
+                .chain_update(path.as_bytes())
+                .finalize()
+                .into(),
+            _ => sha1::Sha1::digest(path.as_bytes()).into(),
+        };
+        let number = (u64::from_be_bytes(hash[..8].try_into().unwrap()) >> 1).max(2);
+        if self.index.contains_key(&number) {
+            self.add(inode_nr, inode_nr as u64 | 1 << 63);
+        } else {
+            self.add(inode_nr, number);
+        }
+    }
+}
+
+pub(crate) struct Options {
+    pub verify_digests: bool,
+    pub size_policy: SizePolicy,
+    // Allow changes, which are encrypted back into the backup. Only supported
+    // with a single backup whose Manifest.db has been made writable.
+    pub read_write: bool,
+    // Report inode numbers derived from fileIDs rather than mount order
+    pub stable_inodes: bool,
+}
+
+// What to do when the plaintext length given by a blob's padding differs from
+// the size recorded in its MBFile, usually because the blob was truncated
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum SizePolicy {
+    // Serve the length given by the padding, or all decryptable data if the padding is invalid
+    TrustPadding,
+    // Serve the MBFile size, zero filling data missing from the blob
+    TrustManifest,
+    // Fail to open the file with EIO
+    Fail,
+}
+
+impl std::str::FromStr for SizePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trust-padding" => Ok(SizePolicy::TrustPadding),
+            "trust-manifest" => Ok(SizePolicy::TrustManifest),
+            "fail" => Ok(SizePolicy::Fail),
+            _ => Err(format!("Unknown size mismatch policy: {}", s)),
+        }
+    }
+}
+
+impl SizePolicy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SizePolicy::TrustPadding => "trust-padding",
+            SizePolicy::TrustManifest => "trust-manifest",
+            SizePolicy::Fail => "fail",
+        }
+    }
+
+    // Returns the size to serve and how much of it to decrypt, None to fail
+    fn resolve(
+        &self,
+        manifest_size: u64,
+        padded: Option<u64>,
+        available: u64,
+    ) -> Option<(u64, u64)> {
+        match self {
+            SizePolicy::TrustPadding => {
+                let size = padded.unwrap_or(available);
+                Some((size, size))
+            }
+            SizePolicy::TrustManifest => Some((
+                manifest_size,
+                std::cmp::min(padded.unwrap_or(available), manifest_size),
+            )),
+            SizePolicy::Fail if padded == Some(manifest_size) => {
+                Some((manifest_size, manifest_size))
+            }
+            SizePolicy::Fail => None,
+        }
+    }
+}
+
+impl BackupFS {
+    pub(crate) fn new(fs: crate::manifestdb::FS, backups: Vec<Backup>, options: Options) -> Self {
+        for backup in &backups {
+            backup
+                .con
+                .prepare_cached("SELECT file FROM Files WHERE fileID = ?")
+                .unwrap();
+        }
         Self {
+            stable_inodes: options
+                .stable_inodes
+                .then(|| StableInodes::new(&fs, backups.len() > 1)),
             fs,
-            con,
-            keys,
-            basepath,
-            options: Options {
-                verify_digests: true,
-            },
+            backups,
+            options,
+            totals: None,
+            padded_sizes: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // Index into the backing of an inode number given by the kernel, None
+    // for numbers that were never handed out
+    fn index(&self, ino: u64) -> Option<usize> {
+        let inode_nr = match &self.stable_inodes {
+            Some(stable) => *stable.index.get(&ino)?,
+            None => ino as usize,
+        };
+        (inode_nr != 0 && inode_nr < self.fs.backing.len()).then_some(inode_nr)
+    }
+
+    // Inode number given to the kernel for an index into the backing
+    fn node(&self, inode_nr: usize) -> u64 {
+        match &self.stable_inodes {
+            Some(stable) => stable.number[inode_nr],
+            None => inode_nr as u64,
         }
     }
 
-    fn get_statement(&self) -> CachedStatement<'_> {
-        self.con
+    // Converted content of a plist or table view, generated for each open
+    fn view_data(&self, ino: usize) -> Result<Vec<u8>, c_int> {
+        let inode = &self.fs.backing[ino];
+        let (view, source) = inode.view.as_ref().unwrap();
+        let mbfile = self.get_mbfile(*source).ok_or(EIO)?;
+        let backup = &self.backups[inode.backup.unwrap()];
+        let id = inode.id.as_stringid();
+        let data = match view {
+            manifestdb::View::Plist(view) => {
+                let data = backup.read_file(id.as_str(), &mbfile).ok_or(EIO)?;
+                plistview::convert(&data, *view).map_err(|e| {
+                    eprintln!("Can't convert plist: {}", e);
+                    EIO
+                })?
+            }
+            manifestdb::View::Table(table, view, snapshot) => backup
+                .open_database(id.as_str(), *snapshot)
+                .and_then(|con| sqliteview::export(&con, table, *view).map_err(|e| e.to_string()))
+                .map_err(|e| {
+                    eprintln!("Can't export table {}: {}", table, e);
+                    EIO
+                })?,
+            manifestdb::View::Tables => return Err(EISDIR),
+        };
+        Ok(data)
+    }
+
+    // Plaintext bytes of the files (as recorded in Manifest.db) and number of
+    // files and folders in the tree
+    fn compute_totals(&self) -> (u64, u64) {
+        let (mut bytes, mut count) = (0, 0);
+        let mut stack = vec![1];
+        while let Some(inode_nr) = stack.pop() {
+            count += 1;
+            let inode = &self.fs.backing[inode_nr];
+            match inode.ftype {
+                FileType::Folder => stack.extend(inode.children.as_ref().unwrap().values()),
+                FileType::File if inode.missing => (),
+                FileType::File => bytes += self.get_mbfile(inode_nr).map_or(0, |m| m.size),
+            }
+        }
+        (bytes, count)
+    }
+
+    fn get_statement(&self, backup: usize) -> CachedStatement<'_> {
+        self.backups[backup]
+            .con
             .prepare_cached("SELECT file FROM Files WHERE fileID = ?")
             .unwrap()
     }
 
-    fn get_mbfile(&self, inode_id: &manifestdb::RawId) -> manifestdb::MBFile {
-        self.get_statement()
-            .query_row([inode_id.as_stringid().as_str()], |r| {
-                Ok(r.get::<_, manifestdb::MBFile>(0).unwrap())
-            })
-            .unwrap()
+    // Folders created by the mount itself have no MBFile
+    fn get_mbfile(&self, ino: usize) -> Option<manifestdb::MBFile> {
+        let inode = &self.fs.backing[ino];
+        let backup = inode.backup?;
+        Some(
+            self.get_statement(backup)
+                .query_row([inode.id.as_stringid().as_str()], |r| {
+                    Ok(r.get::<_, manifestdb::MBFile>(0).unwrap())
+                })
+                .unwrap(),
+        )
+    }
+
+    // Extended attributes stored in the backup followed by the ones added by the mount
+    fn xattrs(&self, ino: usize) -> Result<Vec<(String, Vec<u8>)>, c_int> {
+        let mut xattrs = Vec::new();
+
+        if ino == 1 {
+            xattrs.push((
+                XATTR_CACHE.to_owned(),
+                crate::cache::global().summary().into_bytes(),
+            ));
+        }
+
+        let Some(mbfile) = self.get_mbfile(ino) else {
+            return Ok(xattrs);
+        };
+
+        if let Some(plist) = mbfile.extended_attributes {
+            let Some(dict) = plist.0.as_dictionary() else {
+                return Err(EIO);
+            };
+            for (key, value) in dict {
+                let Some(data) = value.as_data() else {
+                    return Err(EIO);
+                };
+                xattrs.push((key.clone(), data.to_vec()));
+            }
+        }
+
+        if self.fs.backing[ino].missing {
+            xattrs.push((XATTR_MISSING.to_owned(), b"1".to_vec()));
+        }
+
+        if self.backups.len() > 1 {
+            let backup = &self.backups[self.fs.backing[ino].backup.unwrap()];
+            xattrs.push((
+                XATTR_BACKUP.to_owned(),
+                backup.path.to_string_lossy().into_owned().into_bytes(),
+            ));
+        }
+
+        Ok(xattrs)
+    }
+
+    // Size reported for a file, only read from its blob (once) when trusting the padding
+    fn served_size(&self, ino: usize, mbfile: &manifestdb::MBFile) -> u64 {
+        let SizePolicy::TrustPadding = self.options.size_policy else {
+            return mbfile.size;
+        };
+        if let Some(size) = self.padded_sizes.borrow().get(&ino) {
+            return *size;
+        }
+        let inode = &self.fs.backing[ino];
+        let backup = &self.backups[inode.backup.unwrap()];
+        match backup.blob_sizes(inode.id.as_stringid().as_str(), mbfile) {
+            Some((padded, available)) => {
+                let size = padded.unwrap_or(available);
+                self.padded_sizes.borrow_mut().insert(ino, size);
+                size
+            }
+            None => mbfile.size,
+        }
     }
 
     fn file_attr(&self, ino: usize) -> FileAttr {
         let inode = &self.fs.backing[ino];
-        let m = if ino > 1 {
-            let m = self.get_mbfile(&inode.id);
-            Some(m)
-        } else {
-            None
-        };
+        let m = self.get_mbfile(ino);
 
         let size: u64 = match inode.ftype {
-            FileType::File => m.as_ref().map(|z| z.size).unwrap_or(0),
+            // Views are only converted when opened, and read past their
+            // size with direct I/O
+            FileType::File if inode.missing || inode.view.is_some() => 0,
+            FileType::File => m.as_ref().map(|z| self.served_size(ino, z)).unwrap_or(0),
             FileType::Folder => inode.children.as_ref().unwrap().len() as u64,
         };
 
         let kind = inode.ftype.into();
 
+        // Folders are linked from their parent, from their own "." and from the
+        // ".." of each subfolder
+        let nlink = match inode.ftype {
+            FileType::File => 1,
+            FileType::Folder => {
+                let subfolders = inode
+                    .children
+                    .as_ref()
+                    .unwrap()
+                    .values()
+                    .filter(|&&x| matches!(self.fs.backing[x].ftype, FileType::Folder))
+                    .count();
+                2 + subfolders as u32
+            }
+        };
+
         let crtime = m
             .as_ref()
             .map(|z| SystemTime::UNIX_EPOCH + Duration::from_secs(z.birth))
@@ -102,17 +412,24 @@ impl<'con> BackupFS<'con> {
         let gid = m.as_ref().map(|z| z.group_i_d).unwrap_or(0) as u32;
 
         FileAttr {
-            ino: ino as u64,
+            ino: self.node(ino),
             blksize: 4096,
             size,
-            blocks: 0,
+            blocks: size.div_ceil(512),
             atime,
             mtime,
             ctime,
             crtime,
             kind,
-            perm: 0x1ff,
-            nlink: 0,
+            perm: match inode {
+                _ if inode.missing => 0,
+                _ if inode.view.is_some() => match inode.ftype {
+                    FileType::File => 0o444,
+                    FileType::Folder => 0o555,
+                },
+                _ => 0x1ff,
+            },
+            nlink,
             uid,
             gid,
             rdev: 0,
@@ -121,14 +438,397 @@ impl<'con> BackupFS<'con> {
     }
 }
 
-struct OpenFile {
-    encrypted_size: u64,
-    zero_size: u64,
-    f: std::fs::File,
-    cbc_cache: CbcCache,
+enum OpenFile {
+    // Plist and table views, converted in memory and dropped on release
+    View(Vec<u8>),
+    Read {
+        zero_size: u64,
+        reader: DecryptingReader<std::fs::File>,
+        // Reused between reads
+        buffer: Vec<u8>,
+    },
+    // Files opened for writing are held in memory, and encrypted into a new
+    // blob when flushed after a change
+    Write {
+        ino: usize,
+        data: Vec<u8>,
+        dirty: bool,
+    },
+}
+
+fn write_error(e: impl std::fmt::Display) -> c_int {
+    eprintln!("Can't update backup: {}", e);
+    EIO
+}
+
+impl BackupFS {
+    fn child(&self, parent: usize, name: &OsStr) -> Option<usize> {
+        self.fs.backing[parent]
+            .children
+            .as_ref()?
+            .get(name.to_str()?)
+            .copied()
+    }
+
+    // Domain, relativePath, flags and MBFile of the record behind an inode
+    fn record(&self, ino: usize) -> Result<(String, String, i64, manifestdb::MBFile), c_int> {
+        let inode = &self.fs.backing[ino];
+        if inode.view.is_some() {
+            return Err(EPERM);
+        }
+        let backup = inode.backup.ok_or(EPERM)?;
+        self.backups[backup]
+            .record(inode.id.as_stringid().as_str())
+            .map_err(write_error)
+    }
+
+    // Domain and relativePath of name in parent. Folders created by the mount
+    // have no record, so nothing can be added to them.
+    fn child_path(&self, parent: usize, name: &OsStr) -> Result<(String, String), c_int> {
+        let name = name.to_str().ok_or(EINVAL)?;
+        let (domain, path, _, _) = self.record(parent)?;
+        if path.is_empty() {
+            Ok((domain, name.to_owned()))
+        } else {
+            Ok((domain, format!("{}/{}", path, name)))
+        }
+    }
+
+    // Encrypts data as the new content of a file
+    fn commit(&self, ino: usize, data: &[u8]) -> Result<(), c_int> {
+        let (domain, path, flags, mut mbfile) = self.record(ino)?;
+        let inode = &self.fs.backing[ino];
+        let backup = &self.backups[inode.backup.unwrap()];
+        let id = inode.id.as_stringid();
+        backup
+            .write_blob(id.as_str(), &mut mbfile, data)
+            .map_err(write_error)?;
+        self.padded_sizes.borrow_mut().remove(&ino);
+        backup
+            .save_record(id.as_str(), &domain, &path, flags, &mbfile)
+            .map_err(write_error)
+    }
+
+    fn flush_handle(&self, fh: &mut OpenFile) -> Result<(), c_int> {
+        if let OpenFile::Write { ino, data, dirty } = fh {
+            if *dirty {
+                self.commit(*ino, data)?;
+                *dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_attributes(
+        &mut self,
+        ino: usize,
+        mode: Option<u32>,
+        size: Option<u64>,
+        mtime: Option<TimeOrNow>,
+        fh: Option<u64>,
+    ) -> Result<(), c_int> {
+        if let Some(size) = size {
+            if size > MAX_WRITE_SIZE {
+                return Err(EFBIG);
+            }
+            match fh.map(|fh| unsafe { &mut *(fh as *mut OpenFile) }) {
+                Some(OpenFile::Write { data, dirty, .. }) => {
+                    data.resize(size as usize, 0);
+                    *dirty = true;
+                }
+                _ => {
+                    let mbfile = self.record(ino)?.3;
+                    let inode = &self.fs.backing[ino];
+                    let mut data = match mbfile.encryption_key {
+                        None if mbfile.size == 0 => Vec::new(),
+                        _ => self.backups[inode.backup.unwrap()]
+                            .read_file(inode.id.as_stringid().as_str(), &mbfile)
+                            .ok_or(EIO)?,
+                    };
+                    data.resize(size as usize, 0);
+                    self.commit(ino, &data)?;
+                }
+            }
+        }
+
+        if mode.is_none() && mtime.is_none() {
+            return Ok(());
+        }
+
+        let (domain, path, flags, mut mbfile) = self.record(ino)?;
+        if let Some(mode) = mode {
+            mbfile.mode = (mbfile.mode & !0o7777) | (mode & 0o7777) as u64;
+        }
+        if let Some(mtime) = mtime {
+            let secs = match mtime {
+                TimeOrNow::SpecificTime(t) => t
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|x| x.as_secs())
+                    .unwrap_or(0),
+                TimeOrNow::Now => backup::now(),
+            };
+            mbfile.last_modified = secs;
+        }
+        mbfile.last_status_change = backup::now();
+
+        let inode = &self.fs.backing[ino];
+        self.backups[inode.backup.unwrap()]
+            .save_record(
+                inode.id.as_stringid().as_str(),
+                &domain,
+                &path,
+                flags,
+                &mbfile,
+            )
+            .map_err(write_error)
+    }
+
+    // Adds a record for a new empty file or folder
+    fn create_entry(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        ftype: FileType,
+    ) -> Result<usize, c_int> {
+        let parent = self.index(parent).ok_or(ENOENT)?;
+        if self.child(parent, name).is_some() {
+            return Err(EEXIST);
+        }
+        let (domain, path) = self.child_path(parent, name)?;
+        let (_, _, _, parent_mbfile) = self.record(parent)?;
+
+        let backup_nr = self.fs.backing[parent].backup.unwrap();
+        let backup = &self.backups[backup_nr];
+        let id = backup::file_id(&domain, &path);
+
+        let (flags, mode) = match ftype {
+            FileType::File => (1, S_IFREG | (mode & 0o7777)),
+            FileType::Folder => (2, S_IFDIR | (mode & 0o7777)),
+        };
+        let mut mbfile = parent_mbfile.child(&path, mode as u64, backup::now());
+        if let FileType::File = ftype {
+            backup
+                .write_blob(&id, &mut mbfile, &[])
+                .map_err(write_error)?;
+        }
+        backup
+            .save_record(&id, &domain, &path, flags, &mbfile)
+            .map_err(write_error)?;
+
+        let inode_nr = self
+            .fs
+            .insert_file(1, backup_nr, &domain, &path, &id, ftype)
+            .ok_or(EEXIST)?;
+        if let Some(stable) = &mut self.stable_inodes {
+            if stable.number.get(inode_nr).is_none_or(|&x| x == 0) {
+                stable.add_derived(&self.fs, inode_nr, "");
+            }
+        }
+        Ok(inode_nr)
+    }
+
+    // Removes the record and blob of a file, or the record of an empty folder
+    fn remove_entry(&mut self, parent: u64, name: &OsStr, ftype: FileType) -> Result<(), c_int> {
+        let parent = self.index(parent).ok_or(ENOENT)?;
+        let ino = self.child(parent, name).ok_or(ENOENT)?;
+        self.check_removable(ino, ftype)?;
+        let inode = &self.fs.backing[ino];
+        let backup = &self.backups[inode.backup.ok_or(EPERM)?];
+        let id = inode.id.as_stringid();
+        backup.delete_record(id.as_str()).map_err(write_error)?;
+
+        if let FileType::File = inode.ftype {
+            let blob = backup.blob_path(id.as_str());
+            match std::fs::remove_file(&blob) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(write_error(e)),
+                _ => crate::cache::global().invalidate(&blob),
+            }
+        }
+
+        self.forget_entry(parent, name, ino);
+        Ok(())
+    }
+
+    // Whether an entry can be removed, or replaced by one of type ftype
+    fn check_removable(&self, ino: usize, ftype: FileType) -> Result<(), c_int> {
+        let inode = &self.fs.backing[ino];
+        if inode.view.is_some() {
+            return Err(EPERM);
+        }
+        match (inode.ftype, ftype) {
+            (FileType::Folder, FileType::File) => Err(EISDIR),
+            (FileType::File, FileType::Folder) => Err(ENOTDIR),
+            (FileType::Folder, _) if !inode.children.as_ref().unwrap().is_empty() => Err(ENOTEMPTY),
+            _ => Ok(()),
+        }
+    }
+
+    // Drops a removed entry from its parent. Views of a removed plist or
+    // database go with it.
+    fn forget_entry(&mut self, parent: usize, name: &OsStr, ino: usize) {
+        self.padded_sizes.borrow_mut().remove(&ino);
+        let mut children = self.fs.backing[parent].children.take().unwrap();
+        children.remove(name.to_str().unwrap());
+        children.retain(|_, x| {
+            self.fs.backing[*x]
+                .view
+                .as_ref()
+                .is_none_or(|(_, source)| *source != ino)
+        });
+        self.fs.backing[parent].children = Some(children);
+    }
+
+    // Moves a file or folder with everything below it. As fileIDs are derived
+    // from the path, every record below it gets a new fileID and blob location.
+    fn rename_entry(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        if flags & !RENAME_NOREPLACE != 0 {
+            return Err(EINVAL);
+        }
+        let parent = self.index(parent).ok_or(ENOENT)?;
+        let newparent = self.index(newparent).ok_or(ENOENT)?;
+        let ino = self.child(parent, name).ok_or(ENOENT)?;
+        if self.fs.backing[ino].view.is_some() {
+            return Err(EPERM);
+        }
+        let backup_nr = self.fs.backing[ino].backup.ok_or(EPERM)?;
+        let (domain, path) = self.child_path(newparent, newname)?;
+
+        // An entry in the way is replaced, its record deleted in the same
+        // transaction and its blob overwritten by the moved one
+        let existing = self.child(newparent, newname);
+        if let Some(existing) = existing {
+            if existing == ino {
+                return Ok(());
+            }
+            if flags & RENAME_NOREPLACE != 0 {
+                return Err(EEXIST);
+            }
+            self.check_removable(existing, self.fs.backing[ino].ftype)?;
+            if self.fs.backing[existing].backup != Some(backup_nr) {
+                return Err(EPERM);
+            }
+        }
+
+        let mut moves = Vec::new();
+        let mut views = Vec::new();
+        let mut stack = vec![(ino, path)];
+        while let Some((n, path)) = stack.pop() {
+            let inode = &self.fs.backing[n];
+            for (name, child) in inode.children.iter().flatten() {
+                stack.push((*child, format!("{}/{}", path, name)));
+            }
+            if let Some((_, source)) = &inode.view {
+                views.push((n, *source));
+            } else if inode.backup.is_some() {
+                let new_id = backup::file_id(&domain, &path);
+                moves.push((n, inode.id.as_stringid(), new_id, path));
+            }
+        }
+
+        let backup = &self.backups[backup_nr];
+        let tx = backup.con.unchecked_transaction().map_err(write_error)?;
+        if let Some(existing) = existing {
+            let id = self.fs.backing[existing].id.as_stringid();
+            backup.delete_record(id.as_str()).map_err(write_error)?;
+        }
+        for (_, old_id, new_id, path) in &moves {
+            let (_, _, flags, mut mbfile) = backup.record(old_id.as_str()).map_err(write_error)?;
+            mbfile.relative_path = path.clone();
+            backup.delete_record(old_id.as_str()).map_err(write_error)?;
+            backup
+                .save_record(new_id, &domain, path, flags, &mbfile)
+                .map_err(write_error)?;
+        }
+
+        // Blobs are moved before committing, so the records never point to a
+        // location without a blob. The blob of a replaced file is kept aside
+        // until then.
+        let replaced = match existing {
+            Some(existing) if matches!(self.fs.backing[existing].ftype, FileType::File) => {
+                let blob = backup.blob_path(self.fs.backing[existing].id.as_stringid().as_str());
+                let aside = blob.with_extension("replaced");
+                match std::fs::rename(&blob, &aside) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(write_error(e))
+                    }
+                    _ => Some((blob, aside)),
+                }
+            }
+            _ => None,
+        };
+        let rollback = |moved: Vec<(std::path::PathBuf, std::path::PathBuf)>| {
+            for (from, to) in moved {
+                let _ = std::fs::rename(to, from);
+            }
+            if let Some((blob, aside)) = &replaced {
+                let _ = std::fs::rename(aside, blob);
+            }
+        };
+        let mut moved = Vec::new();
+        for (n, old_id, new_id, _) in &moves {
+            if let FileType::Folder = self.fs.backing[*n].ftype {
+                continue;
+            }
+            let (from, to) = (backup.blob_path(old_id.as_str()), backup.blob_path(new_id));
+            let res = std::fs::create_dir_all(to.parent().unwrap())
+                .and_then(|_| std::fs::rename(&from, &to));
+            match res {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    rollback(moved);
+                    return Err(write_error(e));
+                }
+                _ => moved.push((from, to)),
+            }
+        }
+        if let Err(e) = tx.commit() {
+            rollback(moved);
+            return Err(write_error(e));
+        }
+
+        if let Some((blob, aside)) = &replaced {
+            let _ = std::fs::remove_file(aside);
+            crate::cache::global().invalidate(blob);
+        }
+        let mut cache = crate::cache::global();
+        for (from, to) in &moved {
+            cache.invalidate(from);
+            cache.invalidate(to);
+        }
+        drop(cache);
+
+        for (n, _, new_id, _) in &moves {
+            self.fs.backing[*n].id = manifestdb::RawId::parse(new_id);
+        }
+        for (n, source) in views {
+            let id = self.fs.backing[source].id.as_stringid();
+            self.fs.backing[n].id = manifestdb::RawId::parse(id.as_str());
+        }
+        if let Some(existing) = existing {
+            self.forget_entry(newparent, newname, existing);
+        }
+        self.fs.backing[parent]
+            .children
+            .as_mut()
+            .unwrap()
+            .remove(name.to_str().unwrap());
+        self.fs.backing[newparent]
+            .children
+            .as_mut()
+            .unwrap()
+            .insert(newname.to_str().unwrap().to_owned(), ino);
+        Ok(())
+    }
 }
 
-impl fuser::Filesystem for BackupFS<'_> {
+impl fuser::Filesystem for BackupFS {
     /*     fn init(
             &mut self,
             _req: &fuser::Request,
@@ -137,7 +837,9 @@ impl fuser::Filesystem for BackupFS<'_> {
             Ok(())
         }
     */
-    fn destroy(&mut self) {}
+    fn destroy(&mut self) {
+        println!("cache {}", crate::cache::global().summary());
+    }
 
     fn lookup(
         &mut self,
@@ -147,17 +849,11 @@ impl fuser::Filesystem for BackupFS<'_> {
         reply: fuser::ReplyEntry,
     ) {
         println!("lookup {} {:#?}", parent, name);
-        let result = self
-            .fs
-            .backing
-            .get(parent as usize)
-            .unwrap()
-            .children
-            .as_ref()
-            .unwrap()
-            .get(name.to_str().unwrap());
-        match result {
-            Some(x) => reply.entry(&Duration::from_secs(300), &self.file_attr(*x), 0),
+        let Some(parent) = self.index(parent) else {
+            return reply.error(ENOENT);
+        };
+        match self.child(parent, name) {
+            Some(x) => reply.entry(&Duration::from_secs(300), &self.file_attr(x), 0),
             None => reply.error(ENOENT),
         }
     }
@@ -171,7 +867,10 @@ impl fuser::Filesystem for BackupFS<'_> {
         _fh: Option<u64>,
         reply: fuser::ReplyAttr,
     ) {
-        reply.attr(&Duration::from_secs(300), &self.file_attr(_ino as usize));
+        match self.index(_ino) {
+            Some(inode_nr) => reply.attr(&Duration::from_secs(300), &self.file_attr(inode_nr)),
+            None => reply.error(ENOENT),
+        }
     }
 
     fn readlink(&mut self, _req: &fuser::Request, _ino: u64, reply: fuser::ReplyData) {
@@ -179,34 +878,66 @@ impl fuser::Filesystem for BackupFS<'_> {
         reply.error(ENOSYS);
     }
 
-    fn open(&mut self, _req: &fuser::Request, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+    fn open(&mut self, _req: &fuser::Request, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
         println!("open {}", ino);
 
-        let id = &self.fs.backing[ino as usize].id;
+        let writable = flags & O_ACCMODE != 0;
+        if writable && !self.options.read_write {
+            return reply.error(EROFS);
+        }
 
-        let mbfile = self.get_mbfile(id);
+        let Some(inode_nr) = self.index(ino) else {
+            return reply.error(ENOENT);
+        };
+        let inode = &self.fs.backing[inode_nr];
 
-        let id = self.fs.backing[ino as usize].id.as_stringid();
-        let id = id.as_str();
-        let mut folder = self.basepath.join(&id[0..2]);
-        folder.push(id);
+        if inode.view.is_some() {
+            if writable {
+                return reply.error(EACCES);
+            }
+            return match self.view_data(inode_nr) {
+                Ok(data) => {
+                    let handle = Box::into_raw(Box::new(OpenFile::View(data)));
+                    reply.opened(handle as u64, fuser::consts::FOPEN_DIRECT_IO)
+                }
+                Err(e) => reply.error(e),
+            };
+        }
 
-        let encdata = mbfile.encryption_key.unwrap().0.data;
+        let backup = &self.backups[inode.backup.unwrap()];
 
-        let mut key = [0; 32];
+        let mbfile = self.get_mbfile(inode_nr).unwrap();
 
-        self.keys[&u32::from_le_bytes(encdata.as_ref()[0..4].try_into().unwrap())]
-            .unwrap(&encdata.as_ref()[4..], &mut key)
-            .unwrap();
+        // Empty files may have no key
+        if writable && mbfile.encryption_key.is_none() && mbfile.size == 0 {
+            let handle = Box::into_raw(Box::new(OpenFile::Write {
+                ino: inode_nr,
+                data: Vec::new(),
+                dirty: false,
+            }));
+            return reply.opened(handle as u64, 0);
+        }
 
-        let mut cbc_cache = CbcCache::new(key, &[0; 16], 0);
+        let folder = backup.blob_path(inode.id.as_stringid().as_str());
+
+        let Some(key) = backup.file_key(&mbfile) else {
+            eprintln!("Can't unwrap key: {}", folder.to_str().unwrap());
+            return reply.error(EIO);
+        };
 
         let size = dbg!(mbfile.size);
         println!("open {} {} {:?}", ino, size, &folder);
 
-        let Ok(mut f) = std::fs::File::open(&folder) else {
-            eprintln!("Can't open file: {}", folder.to_str().unwrap());
-            return reply.error(EIO);
+        let mut f = match std::fs::File::open(&folder) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("Can't open file: {}", folder.to_str().unwrap());
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    self.fs.backing[inode_nr].missing = true;
+                    return reply.error(ENOENT);
+                }
+                return reply.error(EIO);
+            }
         };
 
         'verify_digest: {
@@ -235,27 +966,54 @@ impl fuser::Filesystem for BackupFS<'_> {
             std::io::Seek::seek(&mut f, std::io::SeekFrom::Start(0)).unwrap();
         }
 
-        let filesize = dbg!(f.metadata().unwrap().len());
-        assert!(filesize % 16 == 0);
-
-        let size = if filesize < size {
-            dbg!("!!!!MISMATCH!!!!");
-            // TODO?: zero extend file in reader?
-            filesize - 16
-        } else {
-            size
+        let Ok(reader) = DecryptingReader::new(f, key) else {
+            return reply.error(EIO);
         };
+        let mut reader = reader.readahead(READAHEAD).with_cache(&folder);
+
+        let padded = reader.padded_len();
+        let available = reader.ciphertext_len();
+
+        if padded != Some(size) {
+            eprintln!(
+                "warning: size_mismatch file_id={} blob={} manifest_size={} padded_size={} ciphertext_size={} policy={}",
+                inode.id.as_stringid().as_str(),
+                folder.display(),
+                size,
+                padded.map(|x| x.to_string()).unwrap_or_else(|| "invalid".to_owned()),
+                available,
+                self.options.size_policy.as_str(),
+            );
+        }
 
-        if !enc_reader::has_correct_pkcs5_padding(&f, &mut cbc_cache, filesize - 16) {
+        let Some((size, decrypt_size)) =
+            self.options
+                .size_policy
+                .resolve(mbfile.size, padded, available)
+        else {
             return reply.error(EIO);
-        }
+        };
+        reader.set_len(decrypt_size);
 
-        let handle = Box::into_raw(Box::new(OpenFile {
-            encrypted_size: size,
-            zero_size: mbfile.size,
-            f,
-            cbc_cache,
-        }));
+        let handle = if writable {
+            let mut data = vec![0; size as usize];
+            if let Err(e) = reader.read_at(&mut data, 0) {
+                eprintln!("Can't read file: {}", e);
+                return reply.error(EIO);
+            }
+            OpenFile::Write {
+                ino: inode_nr,
+                data,
+                dirty: false,
+            }
+        } else {
+            OpenFile::Read {
+                zero_size: size,
+                reader,
+                buffer: Vec::new(),
+            }
+        };
+        let handle = Box::into_raw(Box::new(handle));
 
         reply.opened(handle as u64, 0);
     }
@@ -273,38 +1031,106 @@ impl fuser::Filesystem for BackupFS<'_> {
     ) {
         println!("read {} {} {}", _ino, offset, size);
 
-        let fh = unsafe { &mut *(fh as *mut OpenFile) };
+        let (zero_size, reader, buffer) = match unsafe { &mut *(fh as *mut OpenFile) } {
+            OpenFile::Read {
+                zero_size,
+                reader,
+                buffer,
+            } => (*zero_size, reader, buffer),
+            OpenFile::Write { data, .. } => {
+                let start = std::cmp::min(offset as usize, data.len());
+                let end = std::cmp::min(start + size as usize, data.len());
+                return reply.data(&data[start..end]);
+            }
+            OpenFile::View(data) => {
+                let start = std::cmp::min(offset as usize, data.len());
+                let end = std::cmp::min(start + size as usize, data.len());
+                return reply.data(&data[start..end]);
+            }
+        };
 
-        if offset as u64 == fh.zero_size {
+        if offset as u64 == zero_size {
             return reply.data(&[]);
         }
 
-        if offset as u64 > fh.zero_size {
+        if offset as u64 > zero_size {
             return reply.error(ENOENT);
         }
 
-        let mut decrypt_size = size as u64;
-        if offset as u64 + size as u64 > fh.encrypted_size {
-            decrypt_size = fh.encrypted_size.saturating_sub(offset as u64);
+        let mut read_size = size as u64;
+        if offset as u64 + size as u64 > zero_size {
+            read_size = zero_size - offset as u64;
         }
-        let mut zero_size = size as u64;
-        if offset as u64 + size as u64 > fh.zero_size {
-            zero_size = fh.zero_size - offset as u64;
+
+        buffer.clear();
+        buffer.resize(read_size as usize, 0);
+
+        // Past the end of the decrypted data the buffer stays zero filled
+        if let Err(e) = reader.read_at(buffer, offset as u64) {
+            eprintln!("Can't read file: {}", e);
+            return reply.error(EIO);
         }
 
-        let mut buffer = vec![0; zero_size as usize];
+        reply.data(buffer)
+    }
 
-        if decrypt_size > 0 {
-            enc_reader::read_encrypted(
-                &fh.f,
-                &mut fh.cbc_cache,
-                buffer.as_mut_ptr(),
-                decrypt_size,
-                offset as u64,
-            );
+    fn write(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        println!("write {} {} {}", ino, offset, data.len());
+
+        let OpenFile::Write {
+            data: content,
+            dirty,
+            ..
+        } = (unsafe { &mut *(fh as *mut OpenFile) })
+        else {
+            return reply.error(EBADF);
+        };
+
+        if let Err(e) = write_at(content, offset, data) {
+            return reply.error(e);
         }
+        *dirty = true;
 
-        reply.data(&buffer)
+        reply.written(data.len() as u32)
+    }
+
+    fn flush(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        match self.flush_handle(unsafe { &mut *(fh as *mut OpenFile) }) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        match self.flush_handle(unsafe { &mut *(fh as *mut OpenFile) }) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn release(
@@ -318,20 +1144,16 @@ impl fuser::Filesystem for BackupFS<'_> {
         reply: fuser::ReplyEmpty,
     ) {
         println!("release {} {} {}", _ino, _fh, _flags);
-        unsafe {
-            let _ = Box::from_raw(_fh as *mut OpenFile);
+        let mut fh = unsafe { Box::from_raw(_fh as *mut OpenFile) };
+        match self.flush_handle(&mut fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
-        reply.ok();
     }
 
     fn opendir(&mut self, _req: &fuser::Request, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
         println!("opendir {} {}", ino, _flags);
-        match self
-            .fs
-            .backing
-            .get(ino as usize)
-            .map(|x: &crate::manifestdb::Inode| x.ftype)
-        {
+        match self.index(ino).map(|x| self.fs.backing[x].ftype) {
             // FOPEN_CACHE_DIR | FOPEN_KEEP_CACHE
             Some(FileType::Folder) => {
                 println!("opendir opened");
@@ -357,7 +1179,10 @@ impl fuser::Filesystem for BackupFS<'_> {
         mut reply: fuser::ReplyDirectory,
     ) {
         println!("readdir {} {}", ino, offset);
-        let inode = self.fs.backing.get(ino as usize).unwrap();
+        let Some(inode_nr) = self.index(ino) else {
+            return reply.error(ENOENT);
+        };
+        let inode = &self.fs.backing[inode_nr];
         for x in inode
             .children
             .as_ref()
@@ -368,7 +1193,7 @@ impl fuser::Filesystem for BackupFS<'_> {
         {
             println!("readdir {} {} {} {}", ino, x.0, x.1 .0, x.1 .1);
             if reply.add(
-                *x.1 .1 as u64,
+                self.node(*x.1 .1),
                 x.0 as i64 + 1,
                 self.fs.backing.get(*x.1 .1).unwrap().ftype.into(),
                 x.1 .0,
@@ -393,7 +1218,26 @@ impl fuser::Filesystem for BackupFS<'_> {
     }
 
     fn statfs(&mut self, _req: &fuser::Request, _ino: u64, reply: fuser::ReplyStatfs) {
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+        let (bytes, files) = match self.totals {
+            Some(totals) => totals,
+            None => {
+                let totals = self.compute_totals();
+                if !self.options.read_write {
+                    self.totals = Some(totals);
+                }
+                totals
+            }
+        };
+        reply.statfs(
+            bytes.div_ceil(BLOCK_SIZE),
+            0,
+            0,
+            files,
+            0,
+            BLOCK_SIZE as u32,
+            255,
+            BLOCK_SIZE as u32,
+        );
     }
 
     fn getxattr(
@@ -405,26 +1249,19 @@ impl fuser::Filesystem for BackupFS<'_> {
         reply: fuser::ReplyXattr,
     ) {
         println!("getxattr {} {}", ino, name.to_str().unwrap());
-        let id = &self.fs.backing[ino as usize].id;
-
-        let mbfile = self.get_mbfile(id);
 
-        let Some(plist) = mbfile.extended_attributes else {
-            return reply.size(0);
+        let Some(inode_nr) = self.index(ino) else {
+            return reply.error(ENOENT);
         };
-
-        let Some(dict) = plist.0.as_dictionary() else {
-            return reply.error(EIO);
+        let xattrs = match self.xattrs(inode_nr) {
+            Ok(x) => x,
+            Err(e) => return reply.error(e),
         };
 
-        let Some(value) = dict.get(name.to_str().unwrap()) else {
+        let Some((_, data)) = xattrs.iter().find(|x| x.0 == name.to_str().unwrap()) else {
             return reply.error(ENODATA);
         };
 
-        let Some(data) = value.as_data() else {
-            return reply.error(EIO);
-        };
-
         if size == 0 {
             return reply.size(data.len() as u32);
         }
@@ -443,21 +1280,17 @@ impl fuser::Filesystem for BackupFS<'_> {
             return reply.size(0);
         }
 
-        let id = &self.fs.backing[ino as usize].id;
-
-        let mbfile = self.get_mbfile(id);
-
-        let Some(plist) = mbfile.extended_attributes else {
-            return reply.size(0);
+        let Some(inode_nr) = self.index(ino) else {
+            return reply.error(ENOENT);
         };
-
-        let Some(dict) = plist.0.as_dictionary() else {
-            return reply.error(EIO);
+        let xattrs = match self.xattrs(inode_nr) {
+            Ok(x) => x,
+            Err(e) => return reply.error(e),
         };
 
-        let reply_size = dict
-            .keys()
-            .fold(0u32, |acc, key| acc.saturating_add(key.len() as u32 + 1));
+        let reply_size = xattrs.iter().fold(0u32, |acc, (key, _)| {
+            acc.saturating_add(key.len() as u32 + 1)
+        });
 
         if reply_size == u32::MAX {
             return reply.error(E2BIG);
@@ -473,7 +1306,7 @@ impl fuser::Filesystem for BackupFS<'_> {
 
         let mut replydata = Vec::with_capacity(reply_size as usize);
 
-        for key in dict.keys() {
+        for (key, _) in &xattrs {
             replydata.extend_from_slice(key.as_bytes());
             replydata.push(0);
         }
@@ -485,15 +1318,405 @@ impl fuser::Filesystem for BackupFS<'_> {
         reply.error(ENOSYS);
     }
 
-    /*    fn create(
+    fn setattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        println!("setattr {} {:?} {:?} {:?}", ino, mode, size, mtime);
+        let Some(inode_nr) = self.index(ino) else {
+            return reply.error(ENOENT);
+        };
+
+        if mode.is_some() || size.is_some() || mtime.is_some() {
+            if !self.options.read_write {
+                return reply.error(EROFS);
+            }
+            if let Err(e) = self.set_attributes(inode_nr, mode, size, mtime, fh) {
+                return reply.error(e);
+            }
+        }
+
+        reply.attr(&Duration::from_secs(300), &self.file_attr(inode_nr));
+    }
+
+    fn create(
         &mut self,
         _req: &fuser::Request,
-        _parent: u64,
-        _name: &std::ffi::OsStr,
-        _mode: u32,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        mode: u32,
+        umask: u32,
         _flags: i32,
         reply: fuser::ReplyCreate,
     ) {
-        reply.error(ENOSYS);
-    }*/
+        println!("create {} {:?}", parent, name);
+
+        if !self.options.read_write {
+            return reply.error(EROFS);
+        }
+
+        match self.create_entry(parent, name, mode & !umask, FileType::File) {
+            Ok(ino) => {
+                let handle = Box::into_raw(Box::new(OpenFile::Write {
+                    ino,
+                    data: Vec::new(),
+                    dirty: false,
+                }));
+                reply.created(
+                    &Duration::from_secs(300),
+                    &self.file_attr(ino),
+                    0,
+                    handle as u64,
+                    0,
+                )
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        println!("mkdir {} {:?}", parent, name);
+
+        if !self.options.read_write {
+            return reply.error(EROFS);
+        }
+
+        match self.create_entry(parent, name, mode & !umask, FileType::Folder) {
+            Ok(ino) => reply.entry(&Duration::from_secs(300), &self.file_attr(ino), 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn unlink(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        println!("unlink {} {:?}", parent, name);
+
+        if !self.options.read_write {
+            return reply.error(EROFS);
+        }
+
+        match self.remove_entry(parent, name, FileType::File) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        println!("rmdir {} {:?}", parent, name);
+
+        if !self.options.read_write {
+            return reply.error(EROFS);
+        }
+
+        match self.remove_entry(parent, name, FileType::Folder) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        println!("rename {} {:?} {} {:?}", parent, name, newparent, newname);
+
+        if !self.options.read_write {
+            return reply.error(EROFS);
+        }
+
+        match self.rename_entry(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+// Writes data at offset into the content of a file open for writing, growing it
+fn write_at(content: &mut Vec<u8>, offset: i64, data: &[u8]) -> Result<(), c_int> {
+    let offset = u64::try_from(offset).map_err(|_| EINVAL)?;
+    let end = offset
+        .checked_add(data.len() as u64)
+        .filter(|&end| end <= MAX_WRITE_SIZE)
+        .ok_or(EFBIG)? as usize;
+    if end > content.len() {
+        content.resize(end, 0);
+    }
+    content[offset as usize..end].copy_from_slice(data);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::tests::{pack_files, TempDir};
+
+    fn mount(backup: Backup) -> BackupFS {
+        let mut fs = manifestdb::FS::new();
+        fs.insert_backup(1, 0, &backup.con, false);
+        let options = Options {
+            verify_digests: false,
+            size_policy: SizePolicy::TrustManifest,
+            read_write: true,
+            stable_inodes: false,
+        };
+        BackupFS::new(fs, vec![backup], options)
+    }
+
+    #[test]
+    fn rename_replaces_existing_file() {
+        let dir = TempDir::new("rename");
+        let mut backup = pack_files(
+            &dir,
+            &[
+                ("HomeDomain", "a.txt", b"one"),
+                ("HomeDomain", "b.txt", b"two"),
+            ],
+        );
+        backup.make_writable().unwrap();
+        let mut fs = mount(backup);
+
+        let domain = fs.child(1, OsStr::new("HomeDomain")).unwrap();
+        let a = fs.child(domain, OsStr::new("a.txt")).unwrap();
+        let ino = domain as u64;
+        fs.rename_entry(ino, OsStr::new("a.txt"), ino, OsStr::new("b.txt"), 0)
+            .unwrap();
+        assert_eq!(fs.child(domain, OsStr::new("a.txt")), None);
+        assert_eq!(fs.child(domain, OsStr::new("b.txt")), Some(a));
+
+        let backup = &fs.backups[0];
+        let id = backup::file_id("HomeDomain", "b.txt");
+        assert!(backup
+            .record(&backup::file_id("HomeDomain", "a.txt"))
+            .is_err());
+        let (_, path, _, mbfile) = backup.record(&id).unwrap();
+        assert_eq!(path, "b.txt");
+        assert_eq!(backup.read_file(&id, &mbfile).unwrap(), b"one");
+        assert!(!backup.blob_path(&id).with_extension("replaced").exists());
+    }
+
+    #[test]
+    fn stable_inodes_of_shared_file_ids_survive_remounts() {
+        let dirs = [TempDir::new("stable-one"), TempDir::new("stable-two")];
+        for dir in &dirs {
+            pack_files(dir, &[("HomeDomain", "a.txt", b"one")]);
+        }
+        // Both backups side by side, and the first one again in a diff folder
+        let numbers = || {
+            let backups: Vec<_> = dirs
+                .iter()
+                .map(|dir| Backup::open(dir.0.join("backup"), "pw", backup::SnapshotPolicy::Refuse))
+                .collect::<Result<_, _>>()
+                .unwrap();
+            let mut fs = manifestdb::FS::new();
+            for (name, backup) in [("one", 0), ("two", 1), ("diff", 0)] {
+                let root = fs.mkdir(1, name);
+                fs.insert_backup(root, backup, &backups[backup].con, false);
+            }
+            let options = Options {
+                verify_digests: false,
+                size_policy: SizePolicy::TrustManifest,
+                read_write: false,
+                stable_inodes: true,
+            };
+            let fs = BackupFS::new(fs, backups, options);
+            ["one", "two", "diff"].map(|name| {
+                let root = fs.child(1, OsStr::new(name)).unwrap();
+                let domain = fs.child(root, OsStr::new("HomeDomain")).unwrap();
+                fs.node(fs.child(domain, OsStr::new("a.txt")).unwrap())
+            })
+        };
+
+        let first = numbers();
+        assert!(first.iter().all(|&x| x < 1 << 63), "{:?}", first);
+        assert_ne!(first[0], first[1]);
+        assert_ne!(first[0], first[2]);
+        assert_eq!(numbers(), first);
+    }
+
+    #[test]
+    fn writes_past_the_size_limit_are_refused() {
+        let mut content = b"abc".to_vec();
+        write_at(&mut content, 5, b"de").unwrap();
+        assert_eq!(content, b"abc\0\0de");
+        write_at(&mut content, 1, b"x").unwrap();
+        assert_eq!(content, b"axc\0\0de");
+
+        assert_eq!(write_at(&mut content, -1, b"x"), Err(EINVAL));
+        assert_eq!(write_at(&mut content, i64::MAX, b"x"), Err(EFBIG));
+        assert_eq!(
+            write_at(&mut content, MAX_WRITE_SIZE as i64, b"x"),
+            Err(EFBIG)
+        );
+        assert_eq!(content.len(), 7);
+    }
+
+    #[test]
+    fn size_mismatches_are_resolved_by_policy() {
+        // MBFile size of 100 against a shorter, a longer, an invalid and a matching padding
+        let blobs = [
+            (Some(60), 64),
+            (Some(150), 160),
+            (None, 64),
+            (Some(100), 112),
+        ];
+        let resolve = |policy: SizePolicy| {
+            blobs
+                .iter()
+                .map(|&(padded, available)| policy.resolve(100, padded, available))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            resolve(SizePolicy::TrustPadding),
+            [
+                Some((60, 60)),
+                Some((150, 150)),
+                Some((64, 64)),
+                Some((100, 100))
+            ]
+        );
+        assert_eq!(
+            resolve(SizePolicy::TrustManifest),
+            [
+                Some((100, 60)),
+                Some((100, 100)),
+                Some((100, 64)),
+                Some((100, 100))
+            ]
+        );
+        assert_eq!(
+            resolve(SizePolicy::Fail),
+            [None, None, None, Some((100, 100))]
+        );
+    }
+
+    // Reads a file through the page cache, the way open does
+    fn read_cached(backup: &Backup, path: &str) -> Vec<u8> {
+        let id = backup::file_id("HomeDomain", path);
+        let (_, _, _, mbfile) = backup.record(&id).unwrap();
+        let blob = backup.blob_path(&id);
+        let f = std::fs::File::open(&blob).unwrap();
+        let mut reader = DecryptingReader::new(f, backup.file_key(&mbfile).unwrap())
+            .unwrap()
+            .with_cache(&blob);
+        reader.set_len(mbfile.size);
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut reader, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn cached_pages_are_dropped_when_blobs_change() {
+        let dir = TempDir::new("cache-invalidation");
+        let mut backup = pack_files(
+            &dir,
+            &[
+                ("HomeDomain", "a.txt", b"one"),
+                ("HomeDomain", "b.txt", b"two"),
+            ],
+        );
+        backup.make_writable().unwrap();
+        let mut fs = mount(backup);
+        crate::cache::global().set_budget(1024 * 1024);
+
+        // Write
+        assert_eq!(read_cached(&fs.backups[0], "a.txt"), b"one");
+        let backup = &fs.backups[0];
+        let id = backup::file_id("HomeDomain", "a.txt");
+        let (_, _, flags, mut mbfile) = backup.record(&id).unwrap();
+        backup.write_blob(&id, &mut mbfile, b"three").unwrap();
+        backup
+            .save_record(&id, "HomeDomain", "a.txt", flags, &mbfile)
+            .unwrap();
+        assert_eq!(read_cached(&fs.backups[0], "a.txt"), b"three");
+
+        // Rename over a cached file
+        assert_eq!(read_cached(&fs.backups[0], "b.txt"), b"two");
+        let domain = fs.child(1, OsStr::new("HomeDomain")).unwrap() as u64;
+        fs.rename_entry(domain, OsStr::new("a.txt"), domain, OsStr::new("b.txt"), 0)
+            .unwrap();
+        assert_eq!(read_cached(&fs.backups[0], "b.txt"), b"three");
+
+        // Remove, then put back a blob with the same fileID and key
+        let id = backup::file_id("HomeDomain", "b.txt");
+        let (_, _, flags, mut mbfile) = fs.backups[0].record(&id).unwrap();
+        fs.remove_entry(domain, OsStr::new("b.txt"), FileType::File)
+            .unwrap();
+        let backup = &fs.backups[0];
+        let key = backup.file_key(&mbfile).unwrap();
+        std::fs::write(
+            backup.blob_path(&id),
+            crate::enc_writer::encrypt(&key, b"four"),
+        )
+        .unwrap();
+        mbfile.size = 4;
+        backup
+            .save_record(&id, "HomeDomain", "b.txt", flags, &mbfile)
+            .unwrap();
+        assert_eq!(read_cached(&fs.backups[0], "b.txt"), b"four");
+    }
+
+    #[test]
+    fn unknown_inodes_are_not_found() {
+        let dir = TempDir::new("unknown-inodes");
+        let mut backup = pack_files(&dir, &[("HomeDomain", "a.txt", b"one")]);
+        backup.make_writable().unwrap();
+        let mut fs = mount(backup);
+
+        let end = fs.fs.backing.len() as u64;
+        assert_eq!(fs.index(1), Some(1));
+        assert_eq!(fs.index(0), None);
+        assert_eq!(fs.index(end), None);
+        assert_eq!(
+            fs.rename_entry(end, OsStr::new("a.txt"), 1, OsStr::new("b.txt"), 0),
+            Err(ENOENT)
+        );
+        assert_eq!(
+            fs.create_entry(end, OsStr::new("c.txt"), 0o644, FileType::File),
+            Err(ENOENT)
+        );
+        assert_eq!(
+            fs.remove_entry(end, OsStr::new("a.txt"), FileType::File),
+            Err(ENOENT)
+        );
+    }
 }
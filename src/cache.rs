@@ -0,0 +1,229 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+// Size of the decrypted pages kept in the cache, a multiple of the AES block size
+pub const PAGE_SIZE: u64 = 64 * 1024;
+
+// (file, page index)
+type PageKey = (u64, u64);
+
+// Backup folder and fileID of a blob
+type FileKey = (PathBuf, String);
+
+// Decrypted pages shared by every reader of the mount and the sqlite vfs, keyed by
+// the fileID they belong to and their index within it. Least recently used pages
+// are evicted once the total size exceeds the budget.
+pub struct PageCache {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    files: BTreeMap<FileKey, u64>,
+    next_file: u64,
+    pages: BTreeMap<PageKey, (Arc<[u8]>, u64)>,
+    lru: BTreeMap<u64, PageKey>,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+// Blobs are stored as <backup>/<first two characters of the fileID>/<fileID>,
+// other files (such as Manifest.db) directly in the backup folder
+fn file_key(path: &Path) -> FileKey {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let parent = path.parent().unwrap_or(Path::new(""));
+    let folder = parent.file_name().unwrap_or_default().to_string_lossy();
+    let backup = match name.len() > 2 && name.starts_with(&*folder) && folder.len() == 2 {
+        true => parent.parent().unwrap_or(Path::new("")),
+        false => parent,
+    };
+    (backup.to_owned(), name.into_owned())
+}
+
+static CACHE: Mutex<PageCache> = Mutex::new(PageCache::new(0));
+
+pub fn global() -> MutexGuard<'static, PageCache> {
+    CACHE.lock().unwrap()
+}
+
+impl PageCache {
+    const fn new(budget: usize) -> Self {
+        PageCache {
+            budget,
+            used: 0,
+            tick: 0,
+            files: BTreeMap::new(),
+            next_file: 0,
+            pages: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    // Number identifying the blob at path in cache keys
+    pub fn file_id(&mut self, path: &Path) -> u64 {
+        let next = self.next_file;
+        let id = *self.files.entry(file_key(path)).or_insert(next);
+        if id == next {
+            self.next_file += 1;
+        }
+        id
+    }
+
+    // Drops the pages of a blob that has been rewritten. Readers opened before
+    // keep the id they were given, new readers get a new one.
+    pub fn invalidate(&mut self, path: &Path) {
+        let Some(file) = self.files.remove(&file_key(path)) else {
+            return;
+        };
+        let pages: Vec<_> = self
+            .pages
+            .range((file, 0)..=(file, u64::MAX))
+            .map(|(key, (data, last_used))| (*key, data.len(), *last_used))
+            .collect();
+        for (key, len, last_used) in pages {
+            self.pages.remove(&key);
+            self.lru.remove(&last_used);
+            self.used -= len;
+        }
+    }
+
+    pub fn get(&mut self, file: u64, page: u64) -> Option<Arc<[u8]>> {
+        let Some((data, last_used)) = self.pages.get_mut(&(file, page)) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        self.lru.remove(last_used);
+        self.tick += 1;
+        *last_used = self.tick;
+        self.lru.insert(self.tick, (file, page));
+        Some(data.clone())
+    }
+
+    pub fn insert(&mut self, file: u64, page: u64, data: Arc<[u8]>) {
+        if !self.enabled() {
+            return;
+        }
+        self.tick += 1;
+        self.used += data.len();
+        if let Some((old, last_used)) = self.pages.insert((file, page), (data, self.tick)) {
+            self.used -= old.len();
+            self.lru.remove(&last_used);
+        }
+        self.lru.insert(self.tick, (file, page));
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.used > self.budget {
+            let Some((_, key)) = self.lru.pop_first() else {
+                break;
+            };
+            let (data, _) = self.pages.remove(&key).unwrap();
+            self.used -= data.len();
+            self.evictions += 1;
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "budget={} used={} pages={} hits={} misses={} evictions={}",
+            self.budget,
+            self.used,
+            self.pages.len(),
+            self.hits,
+            self.misses,
+            self.evictions
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(len: usize) -> Arc<[u8]> {
+        vec![0; len].into()
+    }
+
+    #[test]
+    fn least_recently_used_pages_are_evicted() {
+        let mut cache = PageCache::new(30);
+        cache.insert(0, 0, page(10));
+        cache.insert(0, 1, page(10));
+        cache.insert(1, 0, page(10));
+        assert!(cache.get(0, 0).is_some());
+
+        cache.insert(1, 1, page(10));
+        assert!(cache.get(0, 1).is_none());
+        assert!(cache.get(0, 0).is_some());
+        assert!(cache.get(1, 0).is_some());
+        assert!(cache.get(1, 1).is_some());
+        assert_eq!(cache.used, 30);
+        assert_eq!(cache.evictions, 1);
+    }
+
+    #[test]
+    fn pages_are_kept_within_the_budget() {
+        let mut cache = PageCache::new(25);
+        cache.insert(0, 0, page(10));
+        cache.insert(0, 0, page(20));
+        assert_eq!(cache.used, 20);
+
+        cache.insert(0, 1, page(10));
+        assert_eq!(cache.used, 10);
+        assert!(cache.get(0, 0).is_none());
+
+        cache.set_budget(5);
+        assert_eq!((cache.used, cache.pages.len()), (0, 0));
+    }
+
+    #[test]
+    fn zero_budget_disables_the_cache() {
+        let mut cache = PageCache::new(0);
+        assert!(!cache.enabled());
+        cache.insert(0, 0, page(10));
+        assert!(cache.get(0, 0).is_none());
+        assert_eq!((cache.used, cache.misses), (0, 1));
+    }
+
+    #[test]
+    fn blobs_are_keyed_by_backup_and_file_id() {
+        let id = "3d0d7e5fb2ce288813306e4d4636395e047a3d28";
+        let blob = Path::new("/backups/a").join(&id[..2]).join(id);
+        assert_eq!(
+            file_key(&blob),
+            (PathBuf::from("/backups/a"), id.to_owned())
+        );
+        assert_eq!(
+            file_key(Path::new("/backups/a/Manifest.db")),
+            (PathBuf::from("/backups/a"), "Manifest.db".to_owned())
+        );
+
+        let mut cache = PageCache::new(100);
+        let other = Path::new("/backups/b").join(&id[..2]).join(id);
+        let file = cache.file_id(&blob);
+        assert_eq!(cache.file_id(&blob), file);
+        assert_ne!(cache.file_id(&other), file);
+
+        cache.insert(file, 0, page(10));
+        cache.insert(file, 1, page(10));
+        cache.invalidate(&blob);
+        assert_eq!((cache.used, cache.pages.len()), (0, 0));
+        assert_ne!(cache.file_id(&blob), file);
+    }
+}
@@ -0,0 +1,438 @@
+use std::{io::Write, path::Path};
+
+use rusqlite::{types::ValueRef, Connection};
+
+use crate::{
+    backup::{file_id, Backup},
+    sqliteview::{has_column, Snapshot},
+    util::{apple_time, csv_line},
+};
+
+const DOMAIN: &str = "HomeDomain";
+const CALLS_PATH: &str = "Library/CallHistoryDB/CallHistory.storedata";
+const VOICEMAIL_PATH: &str = "Library/Voicemail/voicemail.db";
+
+// ZCALLTYPE of FaceTime calls
+const FACETIME_VIDEO: i64 = 8;
+const FACETIME_AUDIO: i64 = 16;
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Format {
+    Csv,
+    JsonLines,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::JsonLines => "jsonl",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::JsonLines),
+            _ => Err(format!("Unknown calls format: {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Direction {
+    Incoming,
+    Outgoing,
+    // Incoming and not answered
+    Missed,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Incoming => "incoming",
+            Direction::Outgoing => "outgoing",
+            Direction::Missed => "missed",
+        }
+    }
+}
+
+// A ZCALLRECORD of the call history
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Call {
+    pub id: i64,
+    // RFC 3339
+    pub time: Option<String>,
+    pub direction: Direction,
+    // Seconds
+    pub duration: f64,
+    pub number: Option<String>,
+    // Name shown by the app that made the call, from iOS 13 on
+    pub name: Option<String>,
+    // phone, facetime-audio, facetime-video, or the bundle ID of the app that
+    // made the call through CallKit
+    pub service: String,
+    pub country: Option<String>,
+    pub location: Option<String>,
+}
+
+const CALL_COLUMNS: [&str; 9] = [
+    "id",
+    "time",
+    "direction",
+    "duration",
+    "number",
+    "name",
+    "service",
+    "country",
+    "location",
+];
+
+impl Call {
+    fn columns(&self) -> [String; 9] {
+        [
+            self.id.to_string(),
+            self.time.clone().unwrap_or_default(),
+            self.direction.as_str().to_owned(),
+            self.duration.to_string(),
+            self.number.clone().unwrap_or_default(),
+            self.name.clone().unwrap_or_default(),
+            self.service.clone(),
+            self.country.clone().unwrap_or_default(),
+            self.location.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+// A message of voicemail.db, whose audio is stored next to it as <id>.amr
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Voicemail {
+    pub id: i64,
+    // RFC 3339, None when the record has no date
+    pub time: Option<String>,
+    pub sender: Option<String>,
+    pub callback: Option<String>,
+    // Seconds
+    pub duration: i64,
+    // When it was deleted on the device, None if it wasn't
+    pub trashed: Option<String>,
+    #[serde(rename = "fileID")]
+    pub audio_id: String,
+    // Copy of the audio, relative to the export, None if it isn't in the backup
+    pub audio: Option<String>,
+}
+
+const VOICEMAIL_COLUMNS: [&str; 8] = [
+    "id", "time", "sender", "callback", "duration", "trashed", "fileID", "audio",
+];
+
+impl Voicemail {
+    fn columns(&self) -> [String; 8] {
+        [
+            self.id.to_string(),
+            self.time.clone().unwrap_or_default(),
+            self.sender.clone().unwrap_or_default(),
+            self.callback.clone().unwrap_or_default(),
+            self.duration.to_string(),
+            self.trashed.clone().unwrap_or_default(),
+            self.audio_id.clone(),
+            self.audio.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+// Every call of the call history, most recent first
+pub(crate) fn calls(backup: &Backup) -> Result<Vec<Call>, String> {
+    let con = backup.open_database(&file_id(DOMAIN, CALLS_PATH), Snapshot::Latest)?;
+    read_calls(&con).map_err(|e| e.to_string())
+}
+
+fn read_calls(con: &Connection) -> rusqlite::Result<Vec<Call>> {
+    // Calls were all made by the phone app before CallKit, and unnamed
+    // before iOS 13
+    let optional = |column: &'static str| {
+        has_column(con, "ZCALLRECORD", column).map(|x| if x { column } else { "NULL" })
+    };
+    let mut sta = con.prepare(&format!(
+        "SELECT Z_PK, ZDATE, ZORIGINATED, ZANSWERED, ZDURATION, ZADDRESS, {}, ZCALLTYPE, {}, \
+         ZISO_COUNTRY_CODE, ZLOCATION FROM ZCALLRECORD ORDER BY ZDATE DESC",
+        optional("ZNAME")?,
+        optional("ZSERVICE_PROVIDER")?,
+    ))?;
+    let calls = sta
+        .query_map((), |r| {
+            let originated: Option<bool> = r.get(2)?;
+            let answered: Option<bool> = r.get(3)?;
+            let provider: Option<String> = r.get(8)?;
+            Ok(Call {
+                id: r.get(0)?,
+                time: r
+                    .get::<_, Option<f64>>(1)?
+                    .and_then(|x| apple_time(x as i64)),
+                direction: match (originated, answered) {
+                    (Some(true), _) => Direction::Outgoing,
+                    (_, Some(true)) => Direction::Incoming,
+                    _ => Direction::Missed,
+                },
+                duration: r.get::<_, Option<f64>>(4)?.unwrap_or_default(),
+                // Stored as text or, on older versions, as a blob of UTF-8
+                number: match r.get_ref(5)? {
+                    ValueRef::Text(x) | ValueRef::Blob(x) => {
+                        Some(String::from_utf8_lossy(x).into_owned())
+                    }
+                    _ => None,
+                },
+                name: r.get(6)?,
+                service: service(r.get(7)?, provider),
+                country: r.get(9)?,
+                location: r.get(10)?,
+            })
+        })?
+        .collect();
+    calls
+}
+
+fn service(call_type: Option<i64>, provider: Option<String>) -> String {
+    match (call_type, provider.as_deref()) {
+        (Some(FACETIME_AUDIO), _) => "facetime-audio".to_owned(),
+        (Some(FACETIME_VIDEO), _) => "facetime-video".to_owned(),
+        (_, None | Some("com.apple.Telephony")) => "phone".to_owned(),
+        (_, Some("com.apple.FaceTime")) => "facetime-audio".to_owned(),
+        (_, Some(provider)) => provider.to_owned(),
+    }
+}
+
+// Every voicemail, most recent first
+pub(crate) fn voicemails(backup: &Backup) -> Result<Vec<Voicemail>, String> {
+    let con = backup.open_database(&file_id(DOMAIN, VOICEMAIL_PATH), Snapshot::Latest)?;
+    read_voicemails(&con).map_err(|e| e.to_string())
+}
+
+fn read_voicemails(con: &Connection) -> rusqlite::Result<Vec<Voicemail>> {
+    let mut sta = con.prepare(
+        "SELECT ROWID, date, sender, callback_num, duration, trashed_date FROM voicemail \
+         ORDER BY date DESC",
+    )?;
+    let voicemails = sta
+        .query_map((), |r| {
+            let id: i64 = r.get(0)?;
+            Ok(Voicemail {
+                id,
+                // Seconds since 1970
+                time: r
+                    .get::<_, Option<i64>>(1)?
+                    .and_then(|x| chrono::DateTime::from_timestamp(x, 0))
+                    .map(|t| t.to_rfc3339()),
+                sender: r.get(2)?,
+                callback: r.get(3)?,
+                duration: r.get::<_, Option<i64>>(4)?.unwrap_or_default(),
+                // Seconds since 2001, 0 when not deleted
+                trashed: r
+                    .get::<_, Option<f64>>(5)?
+                    .and_then(|x| apple_time(x as i64)),
+                audio_id: file_id(DOMAIN, &audio_path(id)),
+                audio: None,
+            })
+        })?
+        .collect();
+    voicemails
+}
+
+fn audio_path(id: i64) -> String {
+    format!("Library/Voicemail/{}.amr", id)
+}
+
+// Writes calls.<ext> and voicemail.<ext> into the output folder, for those
+// that could be read, with the audio of the voicemails in its voicemail folder
+pub(crate) fn export(
+    backup: &Backup,
+    calls: Option<&[Call]>,
+    voicemails: Option<&mut [Voicemail]>,
+    format: Format,
+    output: &Path,
+) -> std::io::Result<()> {
+    std::fs::create_dir_all(output)?;
+
+    if let Some(calls) = calls {
+        let path = output.join(format!("calls.{}", format.extension()));
+        let rows = calls.iter().map(|x| (x.columns(), x));
+        write(&path, format, &CALL_COLUMNS, rows)?;
+    }
+
+    if let Some(voicemails) = voicemails {
+        let folder = output.join("voicemail");
+        std::fs::create_dir_all(&folder)?;
+        for voicemail in voicemails.iter_mut() {
+            let data = backup
+                .record(&voicemail.audio_id)
+                .ok()
+                .and_then(|(_, _, _, mbfile)| backup.read_file(&voicemail.audio_id, &mbfile));
+            let Some(data) = data else {
+                eprintln!(
+                    "warning: missing_voicemail_audio file_id={} path={}",
+                    voicemail.audio_id,
+                    audio_path(voicemail.id)
+                );
+                continue;
+            };
+            std::fs::write(folder.join(format!("{}.amr", voicemail.id)), data)?;
+            voicemail.audio = Some(format!("voicemail/{}.amr", voicemail.id));
+        }
+
+        let path = output.join(format!("voicemail.{}", format.extension()));
+        let rows = voicemails.iter().map(|x| (x.columns(), x));
+        write(&path, format, &VOICEMAIL_COLUMNS, rows)?;
+    }
+    Ok(())
+}
+
+fn write<'a, T: serde::Serialize + 'a, const N: usize>(
+    path: &Path,
+    format: Format,
+    columns: &[&str; N],
+    rows: impl Iterator<Item = ([String; N], &'a T)>,
+) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    if let Format::Csv = format {
+        writeln!(out, "{}", csv_line(columns))?;
+    }
+    for (fields, row) in rows {
+        match format {
+            Format::Csv => writeln!(out, "{}", csv_line(&fields))?,
+            Format::JsonLines => {
+                serde_json::to_writer(&mut out, row)?;
+                writeln!(out)?;
+            }
+        }
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ZCALLRECORD as of iOS 13, or before it without ZNAME and ZSERVICE_PROVIDER
+    fn call_history(recent: bool) -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        let extra = match recent {
+            true => ", ZNAME TEXT, ZSERVICE_PROVIDER TEXT",
+            false => "",
+        };
+        con.execute_batch(&format!(
+            "CREATE TABLE ZCALLRECORD (Z_PK INTEGER PRIMARY KEY, ZDATE TIMESTAMP, \
+             ZORIGINATED INTEGER, ZANSWERED INTEGER, ZDURATION FLOAT, ZADDRESS VARCHAR, \
+             ZCALLTYPE INTEGER, ZISO_COUNTRY_CODE VARCHAR, ZLOCATION VARCHAR{})",
+            extra
+        ))
+        .unwrap();
+        con.execute_batch(
+            "INSERT INTO ZCALLRECORD (Z_PK, ZDATE, ZORIGINATED, ZANSWERED, ZDURATION, ZADDRESS, \
+             ZCALLTYPE, ZISO_COUNTRY_CODE, ZLOCATION) VALUES
+                 (1, 700000000.5, 1, 0, 60.5, '+15555550100', 1, 'us', 'California'),
+                 (2, 700000100, 0, 1, 12, X'2b3135353535353530313031', 8, NULL, NULL),
+                 (3, 700000200, 0, 0, 0, 'ann@example.com', 16, NULL, NULL),
+                 (4, 700000300, NULL, NULL, NULL, NULL, 1, NULL, NULL),
+                 (5, 700000400, 0, 1, 5, '+15555550102', 1, NULL, NULL);",
+        )
+        .unwrap();
+        con
+    }
+
+    #[test]
+    fn calls_of_recent_versions() {
+        let con = call_history(true);
+        con.execute_batch(
+            "UPDATE ZCALLRECORD SET ZNAME = 'Ann', ZSERVICE_PROVIDER = 'com.apple.Telephony' \
+                 WHERE Z_PK = 1;
+             UPDATE ZCALLRECORD SET ZSERVICE_PROVIDER = 'com.apple.FaceTime' WHERE Z_PK IN (2, 5);
+             UPDATE ZCALLRECORD SET ZNAME = 'Bob', ZSERVICE_PROVIDER = 'net.whatsapp.WhatsApp' \
+                 WHERE Z_PK = 4;",
+        )
+        .unwrap();
+
+        let calls = read_calls(&con).unwrap();
+        let summary = calls
+            .iter()
+            .map(|x| {
+                (
+                    x.id,
+                    x.direction.as_str(),
+                    x.service.as_str(),
+                    x.name.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (5, "incoming", "facetime-audio", None),
+                (4, "missed", "net.whatsapp.WhatsApp", Some("Bob")),
+                (3, "missed", "facetime-audio", None),
+                (2, "incoming", "facetime-video", None),
+                (1, "outgoing", "phone", Some("Ann")),
+            ]
+        );
+
+        let first = &calls[4];
+        assert_eq!(first.time.as_deref(), Some("2023-03-08T20:26:40+00:00"));
+        assert_eq!(first.duration, 60.5);
+        assert_eq!(first.number.as_deref(), Some("+15555550100"));
+        assert_eq!(
+            (first.country.as_deref(), first.location.as_deref()),
+            (Some("us"), Some("California"))
+        );
+        // Older versions store the number as a blob
+        assert_eq!(calls[3].number.as_deref(), Some("+15555550101"));
+        assert_eq!((calls[1].number.as_deref(), calls[1].duration), (None, 0.0));
+    }
+
+    #[test]
+    fn calls_without_name_and_provider() {
+        let calls = read_calls(&call_history(false)).unwrap();
+        let summary = calls
+            .iter()
+            .map(|x| (x.id, x.direction.as_str(), x.service.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (5, "incoming", "phone"),
+                (4, "missed", "phone"),
+                (3, "missed", "facetime-audio"),
+                (2, "incoming", "facetime-video"),
+                (1, "outgoing", "phone"),
+            ]
+        );
+        assert!(calls.iter().all(|x| x.name.is_none()));
+    }
+
+    #[test]
+    fn voicemail_times() {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute_batch(
+            "CREATE TABLE voicemail (ROWID INTEGER PRIMARY KEY, date INTEGER, sender TEXT, \
+             callback_num TEXT, duration INTEGER, trashed_date REAL);
+             INSERT INTO voicemail (ROWID, date) VALUES (1, 86400), (2, NULL), (3, -86400);",
+        )
+        .unwrap();
+        let times: Vec<_> = read_voicemails(&con)
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.id, x.time))
+            .collect();
+        assert_eq!(
+            times,
+            [
+                (1, Some("1970-01-02T00:00:00+00:00".to_owned())),
+                (3, Some("1969-12-31T00:00:00+00:00".to_owned())),
+                (2, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1056 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    ops::Range,
+};
+
+use rusqlite::Connection;
+
+use crate::{
+    backup::Backup,
+    sqliteview::{self, Snapshot},
+    util::{csv_line, hex},
+};
+
+const WAL_HEADER: usize = 32;
+const FRAME_HEADER: usize = 24;
+
+const INTERIOR_INDEX: u8 = 0x02;
+const INTERIOR_TABLE: u8 = 0x05;
+const LEAF_INDEX: u8 = 0x0a;
+const LEAF_TABLE: u8 = 0x0d;
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Format {
+    Csv,
+    JsonLines,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::JsonLines),
+            _ => Err(format!("Unknown carve format: {}", s)),
+        }
+    }
+}
+
+// Version of the page a row was found in
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Source {
+    // Page in use in the latest state of the database
+    Live,
+    // Page on the freelist
+    Freelist,
+    // WAL frame superseded by a later one, not committed or left from before
+    // the last checkpoint
+    Wal,
+    // Page of the database file replaced by the WAL
+    Prewal,
+}
+
+impl Source {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Source::Live => "live",
+            Source::Freelist => "freelist",
+            Source::Wal => "wal",
+            Source::Prewal => "prewal",
+        }
+    }
+}
+
+// Part of the page a row was found in
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Area {
+    // Cell of a table leaf page that isn't in the latest state
+    Cell,
+    // Freed cell, whose first bytes are overwritten
+    Freeblock,
+    // Space between the cell pointers and the cells
+    Unallocated,
+    // Page that isn't a table leaf, scanned as a whole
+    Page,
+}
+
+impl Area {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Area::Cell => "cell",
+            Area::Freeblock => "freeblock",
+            Area::Unallocated => "unallocated",
+            Area::Page => "page",
+        }
+    }
+}
+
+// How the table of a row was found
+#[derive(Copy, Clone, Debug)]
+enum Attribution {
+    // The page belongs to the table in the latest state
+    Page,
+    // Only table with that many columns
+    Unique,
+    // First of several tables with that many columns
+    Ambiguous,
+    None,
+}
+
+// A row found in a database that isn't in its latest state
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Row {
+    pub source: Source,
+    pub area: Area,
+    pub page: u32,
+    // WAL frame, counting from 1
+    pub frame: Option<u32>,
+    // Offset of the record in the page
+    pub offset: usize,
+    pub table: Option<String>,
+    pub rowid: Option<i64>,
+    // From 0.1 for records carved from free space with a guessed table up to
+    // 0.9 for cells of a page of the table
+    pub confidence: f64,
+    // Blobs in hex
+    pub values: Vec<serde_json::Value>,
+}
+
+const COLUMNS: [&str; 9] = [
+    "source",
+    "area",
+    "page",
+    "frame",
+    "offset",
+    "table",
+    "rowid",
+    "confidence",
+    "values",
+];
+
+// Table with a rowid, WITHOUT ROWID tables being stored as indexes
+struct Table {
+    name: String,
+    root: u32,
+    columns: usize,
+    // INTEGER PRIMARY KEY column, stored as NULL in records
+    rowid_alias: Option<usize>,
+}
+
+// Recovers the rows of a database of the backup that are in its free pages,
+// in the free space of its pages and in older versions of its pages kept by
+// the WAL, but not in its latest state
+pub(crate) fn carve(backup: &Backup, id: &str) -> Result<Vec<Row>, String> {
+    let (_, _, _, mbfile) = backup
+        .record(id)
+        .map_err(|e| format!("Can't find {}: {}", id, e))?;
+    let db = backup
+        .read_file(id, &mbfile)
+        .ok_or_else(|| format!("Can't read {}", id))?;
+    let wal = backup.read_wal(id).unwrap_or_default();
+    let con = backup.open_database(id, Snapshot::Latest)?;
+    let (tables, others) = schema(&con).map_err(|e| e.to_string())?;
+
+    Ok(Carver::new(&db, &wal, tables, &others)?.carve())
+}
+
+// Tables with a rowid, with sqlite_master first, and the root pages of the
+// other b-trees
+fn schema(con: &Connection) -> rusqlite::Result<(Vec<Table>, Vec<u32>)> {
+    let mut tables = vec![Table {
+        name: "sqlite_master".to_owned(),
+        root: 1,
+        columns: 5,
+        rowid_alias: None,
+    }];
+    let mut others = Vec::new();
+
+    let mut sta = con.prepare(
+        "SELECT type, name, rootpage, coalesce(sql, '') FROM sqlite_master WHERE rootpage > 0",
+    )?;
+    let entries: Vec<(String, String, u32, String)> = sta
+        .query_map((), |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    for (kind, name, root, sql) in entries {
+        if kind != "table" || sql.to_uppercase().contains("WITHOUT ROWID") {
+            others.push(root);
+            continue;
+        }
+        let mut sta = con.prepare("SELECT type, pk FROM pragma_table_info(?)")?;
+        let columns: Vec<(String, i64)> = sta
+            .query_map([&name], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        let keys: Vec<usize> = (0..columns.len()).filter(|&i| columns[i].1 > 0).collect();
+        let rowid_alias = match keys[..] {
+            [i] if columns[i].0.eq_ignore_ascii_case("INTEGER") => Some(i),
+            _ => None,
+        };
+        tables.push(Table {
+            name,
+            root,
+            columns: columns.len(),
+            rowid_alias,
+        });
+    }
+    Ok((tables, others))
+}
+
+// Database as seen with the committed frames of its WAL applied
+struct Image<'a> {
+    db: &'a [u8],
+    page_size: usize,
+    usable: usize,
+    // Text encoding from the database header: 1 for UTF-8, 2 and 3 for
+    // UTF-16 little and big endian
+    encoding: u32,
+    // Latest version of the pages in the WAL
+    pages: HashMap<u32, &'a [u8]>,
+    page_count: u32,
+}
+
+impl<'a> Image<'a> {
+    fn page(&self, n: u32) -> Option<&'a [u8]> {
+        if n == 0 || n > self.page_count {
+            return None;
+        }
+        if let Some(page) = self.pages.get(&n) {
+            return Some(page);
+        }
+        let start = (n as usize - 1) * self.page_size;
+        self.db.get(start..start + self.page_size)
+    }
+}
+
+// Record found in a page, with the table it was attributed to
+struct Found {
+    // Offset of the record in the page
+    offset: usize,
+    rowid: Option<i64>,
+    table: Option<usize>,
+    attribution: Attribution,
+    values: Vec<serde_json::Value>,
+}
+
+struct Frame<'a> {
+    number: u32,
+    page: u32,
+    // Size of the database in pages for commit frames, 0 otherwise
+    commit: u32,
+    data: &'a [u8],
+}
+
+struct Carver<'a> {
+    image: Image<'a>,
+    tables: Vec<Table>,
+    // Table owning each page in use, None for pages of indexes
+    owners: HashMap<u32, Option<usize>>,
+    // Versions of pages that aren't in the latest state
+    old: Vec<(Source, Option<u32>, u32, &'a [u8])>,
+}
+
+impl<'a> Carver<'a> {
+    fn new(
+        db: &'a [u8],
+        wal: &'a [u8],
+        tables: Vec<Table>,
+        others: &[u32],
+    ) -> Result<Self, String> {
+        if db.len() < 100 || !sqliteview::has_magic(db) {
+            return Err("Not a SQLite database".to_owned());
+        }
+        let page_size = match u16::from_be_bytes([db[16], db[17]]) {
+            1 => 65536,
+            x => x as usize,
+        };
+        if page_size < 512 || db[20] as usize >= page_size - 480 {
+            return Err("Invalid database header".to_owned());
+        }
+
+        let (frames, committed) = wal_frames(wal, page_size);
+        let mut pages = HashMap::new();
+        let mut latest = HashMap::new();
+        let mut page_count = (db.len() / page_size) as u32;
+        for frame in &frames[..committed] {
+            pages.insert(frame.page, frame.data);
+            latest.insert(frame.page, frame.number);
+            if frame.commit != 0 {
+                page_count = frame.commit;
+            }
+        }
+
+        let mut old = Vec::new();
+        for frame in &frames {
+            if latest.get(&frame.page) != Some(&frame.number) {
+                old.push((Source::Wal, Some(frame.number), frame.page, frame.data));
+            }
+        }
+        let mut replaced: Vec<u32> = pages.keys().copied().collect();
+        replaced.sort();
+        for n in replaced {
+            let start = (n as usize - 1) * page_size;
+            if let Some(data) = db.get(start..start + page_size) {
+                old.push((Source::Prewal, None, n, data));
+            }
+        }
+
+        let mut carver = Carver {
+            image: Image {
+                db,
+                page_size,
+                usable: page_size - db[20] as usize,
+                encoding: be32(&db[56..60]),
+                pages,
+                page_count,
+            },
+            tables,
+            owners: HashMap::new(),
+            old,
+        };
+        for t in 0..carver.tables.len() {
+            carver.walk(carver.tables[t].root, Some(t));
+        }
+        for &root in others {
+            carver.walk(root, None);
+        }
+        Ok(carver)
+    }
+
+    fn carve(&self) -> Vec<Row> {
+        let mut rows = Vec::new();
+        for &(source, frame, n, data) in &self.old {
+            self.page_rows(&mut rows, source, frame, n, data);
+        }
+        for (n, trunk_end) in self.freelist() {
+            let Some(data) = self.image.page(n) else {
+                continue;
+            };
+            match trunk_end {
+                Some(end) => self.scan(
+                    &mut rows,
+                    (Source::Freelist, None, n),
+                    Area::Page,
+                    data,
+                    end..data.len(),
+                    None,
+                ),
+                None => self.page_rows(&mut rows, Source::Freelist, None, n, data),
+            }
+        }
+        let mut live_pages: Vec<u32> = self.owners.keys().copied().collect();
+        live_pages.sort();
+        for &n in &live_pages {
+            if let Some(data) = self.image.page(n) {
+                self.page_rows(&mut rows, Source::Live, None, n, data);
+            }
+        }
+
+        // Records still in the latest state, and ones found more than once
+        let mut live = HashSet::new();
+        for &n in &live_pages {
+            let (Some(data), Some(&Some(t))) = (self.image.page(n), self.owners.get(&n)) else {
+                continue;
+            };
+            if data[header_offset(n)] != LEAF_TABLE {
+                continue;
+            }
+            for offset in cell_pointers(data, header_offset(n), 8) {
+                if let Some((values, _)) =
+                    self.cell(data, offset).and_then(|(_, r)| self.record(&r))
+                {
+                    live.insert((Some(t), values_key(&values)));
+                }
+            }
+        }
+        let mut seen = HashSet::new();
+        let mut seen_values = HashSet::new();
+        rows.retain_mut(|row| {
+            let t = self
+                .tables
+                .iter()
+                .position(|x| Some(&x.name) == row.table.as_ref());
+            let key = (t, values_key(&row.values));
+            if live.contains(&key) {
+                return false;
+            }
+            if row.rowid.is_none() && seen_values.contains(&key) {
+                return false;
+            }
+            if !seen.insert((row.rowid, key.clone())) {
+                return false;
+            }
+            seen_values.insert(key);
+
+            if let (Some(t), Some(rowid)) = (t, row.rowid) {
+                if let Some(i) = self.tables[t].rowid_alias {
+                    row.values[i] = rowid.into();
+                }
+            }
+            true
+        });
+        rows
+    }
+
+    // Marks the pages of the b-tree at root as owned by table
+    fn walk(&mut self, root: u32, table: Option<usize>) {
+        let mut stack = vec![root];
+        while let Some(n) = stack.pop() {
+            if self.owners.contains_key(&n) {
+                continue;
+            }
+            let Some(data) = self.image.page(n) else {
+                continue;
+            };
+            self.owners.insert(n, table);
+            let hdr = header_offset(n);
+            if let INTERIOR_TABLE | INTERIOR_INDEX = data[hdr] {
+                stack.push(be32(&data[hdr + 8..hdr + 12]));
+                for offset in cell_pointers(data, hdr, 12) {
+                    if let Some(child) = data.get(offset..offset + 4) {
+                        stack.push(be32(child));
+                    }
+                }
+            }
+        }
+    }
+
+    // Pages of the freelist, with the end of the list of leaves for trunks
+    fn freelist(&self) -> Vec<(u32, Option<usize>)> {
+        let mut pages = Vec::new();
+        let mut visited = HashSet::new();
+        let Some(first) = self.image.page(1) else {
+            return pages;
+        };
+        let mut trunk = be32(&first[32..36]);
+        while trunk != 0 && visited.insert(trunk) {
+            let Some(data) = self.image.page(trunk) else {
+                break;
+            };
+            let count = std::cmp::min(be32(&data[4..8]) as usize, (data.len() - 8) / 4);
+            pages.push((trunk, Some(8 + count * 4)));
+            for leaf in data[8..8 + count * 4].chunks_exact(4) {
+                pages.push((be32(leaf), None));
+            }
+            trunk = be32(&data[..4]);
+        }
+        pages
+    }
+
+    // Rows of a version of a table b-tree page: the cells of leaves unless it
+    // is in use, then its freeblocks and unallocated space, where interior
+    // pages keep what they held as a leaf. Free pages that weren't part of a
+    // b-tree are scanned as a whole.
+    fn page_rows(
+        &self,
+        rows: &mut Vec<Row>,
+        source: Source,
+        frame: Option<u32>,
+        n: u32,
+        data: &[u8],
+    ) {
+        let hdr = header_offset(n);
+        let owner = self.owners.get(&n).copied().flatten();
+        let at = (source, frame, n);
+
+        let header_len = match (data.get(hdr), source) {
+            (Some(&LEAF_TABLE), _) => 8,
+            (Some(&INTERIOR_TABLE), _) => 12,
+            (Some(&(INTERIOR_INDEX | LEAF_INDEX)), _) => return,
+            (_, Source::Freelist) => {
+                return self.scan(rows, at, Area::Page, data, 0..data.len(), None)
+            }
+            _ => return,
+        };
+
+        if header_len == 8 && !matches!(source, Source::Live) {
+            for offset in cell_pointers(data, hdr, 8) {
+                let Some((rowid, record)) = self.cell(data, offset) else {
+                    continue;
+                };
+                let Some((values, _)) = self.record(&record) else {
+                    continue;
+                };
+                let (table, attribution) = self.attribute(owner, &values);
+                let found = Found {
+                    offset,
+                    rowid: Some(rowid),
+                    table,
+                    attribution,
+                    values,
+                };
+                rows.push(self.row(at, Area::Cell, found));
+            }
+        }
+
+        let mut freeblock = be16(&data[hdr + 1..hdr + 3]);
+        while freeblock != 0 {
+            let Some(header) = data.get(freeblock..freeblock + 4) else {
+                break;
+            };
+            let end = std::cmp::min(freeblock + be16(&header[2..4]), data.len());
+            self.scan(rows, at, Area::Freeblock, data, freeblock + 4..end, owner);
+            let next = be16(&header[..2]);
+            if next <= freeblock {
+                break;
+            }
+            freeblock = next;
+        }
+
+        let start = hdr + header_len + 2 * be16(&data[hdr + 3..hdr + 5]);
+        let content = match be16(&data[hdr + 5..hdr + 7]) {
+            0 => 65536,
+            x => x,
+        };
+        let end = std::cmp::min(content, data.len());
+        if start < end {
+            self.scan(rows, at, Area::Unallocated, data, start..end, owner);
+        }
+    }
+
+    // Records found by trying every offset of a region, as a record of the
+    // table owning the page or of any table. In freeblocks, where freed cells
+    // follow each other, the start of the freeblock and the end of each record
+    // are also tried as a cell whose first 4 bytes were overwritten.
+    fn scan(
+        &self,
+        rows: &mut Vec<Row>,
+        at: (Source, Option<u32>, u32),
+        area: Area,
+        data: &[u8],
+        range: Range<usize>,
+        owner: Option<usize>,
+    ) {
+        let candidates: Vec<usize> = match owner {
+            Some(t) => vec![t],
+            None => (0..self.tables.len()).collect(),
+        };
+        let mut offset = range.start;
+        let mut cell_start = area == Area::Freeblock;
+        while offset < range.end {
+            let region = &data[offset..range.end];
+            let found = candidates
+                .iter()
+                .find_map(|&t| self.carve_record(region, t, cell_start));
+            let Some((values, len)) = found else {
+                offset += 1;
+                cell_start = false;
+                continue;
+            };
+            let (table, attribution) = self.attribute(owner, &values);
+            let found = Found {
+                offset,
+                rowid: None,
+                table,
+                attribution,
+                values,
+            };
+            rows.push(self.row(at, area, found));
+            offset += std::cmp::max(len, 1);
+            cell_start = area == Area::Freeblock;
+        }
+    }
+
+    // Values and length of a record of table at the start of data. For the
+    // rest of a cell whose payload length, rowid and header length were
+    // overwritten, along with the serial types of the first lost columns, the
+    // lost columns are taken as NULL.
+    fn carve_record(
+        &self,
+        data: &[u8],
+        t: usize,
+        cell_start: bool,
+    ) -> Option<(Vec<serde_json::Value>, usize)> {
+        if let Some((values, len)) = self.record(data) {
+            if self.plausible(t, &values) {
+                return Some((values, len));
+            }
+        }
+        if !cell_start {
+            return None;
+        }
+        let columns = self.tables[t].columns;
+        (0..std::cmp::min(columns, 3)).find_map(|lost| {
+            let mut types = vec![0; lost];
+            let mut pos = 0;
+            for _ in lost..columns {
+                let (x, len) = varint(data.get(pos..)?)?;
+                types.push(x);
+                pos += len;
+            }
+            let (values, len) = self.values(&types, &data[pos..])?;
+            self.plausible(t, &values).then_some((values, pos + len))
+        })
+    }
+
+    fn fits(&self, t: usize, values: &[serde_json::Value]) -> bool {
+        let table = &self.tables[t];
+        values.len() == table.columns && table.rowid_alias.is_none_or(|i| values[i].is_null())
+    }
+
+    // Carved records also need a value and printable text
+    fn plausible(&self, t: usize, values: &[serde_json::Value]) -> bool {
+        self.fits(t, values)
+            && values.iter().any(|x| !x.is_null())
+            && values.iter().all(|x| {
+                x.as_str()
+                    .is_none_or(|s| !s.chars().any(|c| c.is_control() && !"\t\n\r".contains(c)))
+            })
+    }
+
+    fn attribute(
+        &self,
+        owner: Option<usize>,
+        values: &[serde_json::Value],
+    ) -> (Option<usize>, Attribution) {
+        if let Some(t) = owner.filter(|&t| self.fits(t, values)) {
+            return (Some(t), Attribution::Page);
+        }
+        let fits: Vec<usize> = (0..self.tables.len())
+            .filter(|&t| self.fits(t, values))
+            .collect();
+        match fits[..] {
+            [] => (None, Attribution::None),
+            [t] => (Some(t), Attribution::Unique),
+            [t, ..] => (Some(t), Attribution::Ambiguous),
+        }
+    }
+
+    fn row(
+        &self,
+        (source, frame, page): (Source, Option<u32>, u32),
+        area: Area,
+        found: Found,
+    ) -> Row {
+        let confidence = match (area, found.attribution) {
+            (Area::Cell, Attribution::Page) => 0.9,
+            (Area::Cell, Attribution::Unique) => 0.7,
+            (Area::Cell, Attribution::Ambiguous) => 0.5,
+            (Area::Cell, Attribution::None) => 0.3,
+            (_, Attribution::Page) => 0.6,
+            (_, Attribution::Unique) => 0.4,
+            (_, Attribution::Ambiguous) => 0.2,
+            (_, Attribution::None) => 0.1,
+        };
+        Row {
+            source,
+            area,
+            page,
+            frame,
+            offset: found.offset,
+            table: found.table.map(|t| self.tables[t].name.clone()),
+            rowid: found.rowid,
+            confidence,
+            values: found.values,
+        }
+    }
+
+    // Rowid and record of the cell of a table leaf page at offset, with the
+    // part stored in overflow pages
+    fn cell(&self, data: &[u8], offset: usize) -> Option<(i64, Vec<u8>)> {
+        let (payload, n1) = varint(data.get(offset..)?)?;
+        let (rowid, n2) = varint(data.get(offset + n1..)?)?;
+        let payload = usize::try_from(payload).ok()?;
+        if payload > self.image.page_count as usize * self.image.usable {
+            return None;
+        }
+        let start = offset + n1 + n2;
+        let local = self.local_size(payload);
+        let mut record = data.get(start..start + local)?.to_vec();
+        if local < payload {
+            let mut next = be32(data.get(start + local..start + local + 4)?);
+            while record.len() < payload {
+                let overflow = self.image.page(next)?;
+                let len = std::cmp::min(payload - record.len(), self.image.usable - 4);
+                record.extend_from_slice(overflow.get(4..4 + len)?);
+                next = be32(&overflow[..4]);
+            }
+        }
+        Some((rowid as i64, record))
+    }
+
+    // Part of a payload stored in the page itself
+    fn local_size(&self, payload: usize) -> usize {
+        let usable = self.image.usable;
+        let max_local = usable - 35;
+        if payload <= max_local {
+            return payload;
+        }
+        let min_local = (usable - 12) * 32 / 255 - 23;
+        let size = min_local + (payload - min_local) % (usable - 4);
+        if size <= max_local {
+            size
+        } else {
+            min_local
+        }
+    }
+
+    // Values and length of the record at the start of data
+    fn record(&self, data: &[u8]) -> Option<(Vec<serde_json::Value>, usize)> {
+        let (header_len, mut pos) = varint(data)?;
+        let header_len = usize::try_from(header_len).ok()?;
+        let header = data.get(..header_len)?;
+        let mut types = Vec::new();
+        while pos < header_len {
+            let (x, len) = varint(&header[pos..])?;
+            types.push(x);
+            pos += len;
+        }
+        if pos != header_len {
+            return None;
+        }
+        let (values, len) = self.values(&types, &data[header_len..])?;
+        Some((values, header_len + len))
+    }
+
+    // Values of the serial types of a record header, from the body in data
+    fn values(&self, types: &[u64], data: &[u8]) -> Option<(Vec<serde_json::Value>, usize)> {
+        let mut values = Vec::new();
+        let mut pos: usize = 0;
+        for &x in types {
+            let len = match x {
+                0 | 8 | 9 => 0,
+                1..=4 => x as usize,
+                5 => 6,
+                6 | 7 => 8,
+                10 | 11 => return None,
+                x => usize::try_from((x - 12) / 2).ok()?,
+            };
+            let bytes = data.get(pos..pos.checked_add(len)?)?;
+            values.push(match x {
+                0 => serde_json::Value::Null,
+                8 => 0.into(),
+                9 => 1.into(),
+                1..=6 => bytes
+                    .iter()
+                    .fold(if bytes[0] & 0x80 != 0 { -1i64 } else { 0 }, |v, &b| {
+                        (v << 8) | b as i64
+                    })
+                    .into(),
+                7 => f64::from_bits(u64::from_be_bytes(bytes.try_into().unwrap())).into(),
+                x if x.is_multiple_of(2) => hex(bytes).into(),
+                _ => self.text(bytes)?.into(),
+            });
+            pos += len;
+        }
+        Some((values, pos))
+    }
+
+    fn text(&self, bytes: &[u8]) -> Option<String> {
+        let utf16 = |f: fn([u8; 2]) -> u16| {
+            if !bytes.len().is_multiple_of(2) {
+                return None;
+            }
+            let units: Vec<u16> = bytes.chunks(2).map(|x| f([x[0], x[1]])).collect();
+            String::from_utf16(&units).ok()
+        };
+        match self.image.encoding {
+            2 => utf16(u16::from_le_bytes),
+            3 => utf16(u16::from_be_bytes),
+            _ => std::str::from_utf8(bytes).ok().map(str::to_owned),
+        }
+    }
+}
+
+// Frames of a WAL, and how many of them make up the committed part of the
+// current log: frames are only valid while their salts match the header and
+// their checksums are right, and count up to the last commit frame
+fn wal_frames(wal: &[u8], page_size: usize) -> (Vec<Frame<'_>>, usize) {
+    let mut frames = Vec::new();
+    if wal.len() < WAL_HEADER || be32(&wal[8..12]) as usize != page_size {
+        return (frames, 0);
+    }
+    let big_endian = match be32(&wal[..4]) {
+        0x377f0682 => false,
+        0x377f0683 => true,
+        _ => return (frames, 0),
+    };
+    let salt = &wal[16..24];
+    let mut sum = wal_checksum(big_endian, (0, 0), &wal[..24]);
+    let mut valid = sum == (be32(&wal[24..28]), be32(&wal[28..32]));
+    let mut committed = 0;
+
+    for (i, frame) in wal[WAL_HEADER..]
+        .chunks_exact(FRAME_HEADER + page_size)
+        .enumerate()
+    {
+        let (header, data) = frame.split_at(FRAME_HEADER);
+        if valid {
+            sum = wal_checksum(big_endian, sum, &header[..8]);
+            sum = wal_checksum(big_endian, sum, data);
+            valid = &header[8..16] == salt && sum == (be32(&header[16..20]), be32(&header[20..24]));
+        }
+        let commit = be32(&header[4..8]);
+        if valid && commit != 0 {
+            committed = i + 1;
+        }
+        frames.push(Frame {
+            number: i as u32 + 1,
+            page: be32(&header[..4]),
+            commit,
+            data,
+        });
+    }
+    (frames, committed)
+}
+
+fn wal_checksum(big_endian: bool, (mut s0, mut s1): (u32, u32), data: &[u8]) -> (u32, u32) {
+    let word = |x: &[u8]| {
+        let x = [x[0], x[1], x[2], x[3]];
+        if big_endian {
+            u32::from_be_bytes(x)
+        } else {
+            u32::from_le_bytes(x)
+        }
+    };
+    for x in data.chunks_exact(8) {
+        s0 = s0.wrapping_add(word(&x[..4])).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(&x[4..])).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+// The b-tree header of page 1 follows the database header
+fn header_offset(n: u32) -> usize {
+    if n == 1 {
+        100
+    } else {
+        0
+    }
+}
+
+// Offsets of the cells of a b-tree page, whose header is header_len long
+fn cell_pointers(data: &[u8], hdr: usize, header_len: usize) -> Vec<usize> {
+    let Some(count) = data.get(hdr + 3..hdr + 5) else {
+        return Vec::new();
+    };
+    let start = hdr + header_len;
+    (0..be16(count))
+        .map_while(|i| data.get(start + i * 2..start + i * 2 + 2))
+        .map(be16)
+        .collect()
+}
+
+fn varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &b) in data.iter().take(9).enumerate() {
+        if i == 8 {
+            return Some(((value << 8) | b as u64, 9));
+        }
+        value = (value << 7) | (b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn be16(x: &[u8]) -> usize {
+    u16::from_be_bytes([x[0], x[1]]) as usize
+}
+
+fn be32(x: &[u8]) -> u32 {
+    u32::from_be_bytes([x[0], x[1], x[2], x[3]])
+}
+
+fn values_key(values: &[serde_json::Value]) -> String {
+    serde_json::Value::from(values.to_vec()).to_string()
+}
+
+// Writes the rows to stdout, values as a JSON array in the CSV
+pub(crate) fn write(rows: &[Row], format: Format) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    match format {
+        Format::Csv => {
+            writeln!(out, "{}", csv_line(&COLUMNS))?;
+            for row in rows {
+                let line = csv_line(&[
+                    row.source.as_str().to_owned(),
+                    row.area.as_str().to_owned(),
+                    row.page.to_string(),
+                    row.frame.map(|x| x.to_string()).unwrap_or_default(),
+                    row.offset.to_string(),
+                    row.table.clone().unwrap_or_default(),
+                    row.rowid.map(|x| x.to_string()).unwrap_or_default(),
+                    row.confidence.to_string(),
+                    values_key(&row.values),
+                ]);
+                writeln!(out, "{}", line)?;
+            }
+        }
+        Format::JsonLines => {
+            for row in rows {
+                serde_json::to_writer(&mut out, row)?;
+                writeln!(out)?;
+            }
+        }
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::{
+        self,
+        tests::{pack_files, TempDir},
+    };
+
+    fn carver(page_size: usize) -> Carver<'static> {
+        Carver {
+            image: Image {
+                db: &[],
+                page_size,
+                usable: page_size,
+                encoding: 1,
+                pages: HashMap::new(),
+                page_count: 0,
+            },
+            tables: Vec::new(),
+            owners: HashMap::new(),
+            old: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn varints() {
+        assert_eq!(varint(&[0x7f]), Some((0x7f, 1)));
+        assert_eq!(varint(&[0x81, 0x00, 0xff]), Some((0x80, 2)));
+        assert_eq!(varint(&[0x82, 0x80, 0x01]), Some((0x8001, 3)));
+        // The ninth byte counts all of its 8 bits
+        assert_eq!(varint(&[0xff; 9]), Some((u64::MAX, 9)));
+        assert_eq!(varint(&[0x81]), None);
+        assert_eq!(varint(&[]), None);
+    }
+
+    #[test]
+    fn records() {
+        let carver = carver(4096);
+        let mut data = vec![9, 0, 1, 2, 8, 9, 7, 17, 16];
+        data.extend_from_slice(&[0x05, 0xff, 0xfe]);
+        data.extend_from_slice(&1.5f64.to_be_bytes());
+        data.extend_from_slice(b"ab");
+        data.extend_from_slice(&[0x01, 0x02]);
+        let len = data.len();
+        data.extend_from_slice(b"next");
+
+        let (values, n) = carver.record(&data).unwrap();
+        assert_eq!(n, len);
+        assert_eq!(
+            serde_json::Value::from(values),
+            serde_json::json!([null, 5, -2, 0, 1, 1.5, "ab", "0102"])
+        );
+
+        // Header lengths not ending on a serial type, reserved types and
+        // truncated bodies
+        assert_eq!(carver.record(&[3, 0x81, 0x00, 1]), None);
+        assert_eq!(carver.values(&[10], &[]), None);
+        assert_eq!(carver.values(&[1, 6], &[1, 2, 3]), None);
+        assert_eq!(carver.values(&[19], b"bob!"), Some((vec!["bob".into()], 3)));
+    }
+
+    #[test]
+    fn local_sizes() {
+        // Payloads up to usable - 35 stay in the page, larger ones keep
+        // between 489 and 4061 bytes of a 4096 byte page
+        let carver = carver(4096);
+        assert_eq!(carver.local_size(100), 100);
+        assert_eq!(carver.local_size(4061), 4061);
+        assert_eq!(carver.local_size(4062), 489);
+        assert_eq!(carver.local_size(5000), 489 + (5000 - 489) % 4092);
+        assert_eq!(carver.local_size(489 + 4092), 489);
+    }
+
+    #[test]
+    fn wal_checksums() {
+        let data = [1, 0, 0, 0, 2, 0, 0, 0];
+        assert_eq!(wal_checksum(false, (0, 0), &data), (1, 3));
+        assert_eq!(
+            wal_checksum(true, (0, 0), &data),
+            (0x0100_0000, 0x0300_0000)
+        );
+        assert_eq!(wal_checksum(false, (1, 3), &data), (5, 10));
+        assert_eq!(wal_checksum(false, (u32::MAX, 0), &data), (0, 2));
+    }
+
+    // Database and WAL as left by a connection still open, after deleting
+    // bob and checkpointing, then deleting carol
+    fn deleted_rows(dir: &TempDir) -> (Vec<u8>, Vec<u8>) {
+        let path = dir.0.join("people.sqlite");
+        let con = Connection::open(&path).unwrap();
+        con.pragma_update(None, "secure_delete", "OFF").unwrap();
+        con.pragma_update(None, "journal_mode", "WAL").unwrap();
+        con.execute_batch(
+            "CREATE TABLE people (id INTEGER PRIMARY KEY, name TEXT);
+             INSERT INTO people (name) VALUES ('alice'), ('bob'), ('carol'), ('dave');
+             DELETE FROM people WHERE name = 'bob';
+             PRAGMA wal_checkpoint(TRUNCATE);
+             DELETE FROM people WHERE name = 'carol';",
+        )
+        .unwrap();
+        let wal = path.with_extension("sqlite-wal");
+        let data = (std::fs::read(&path).unwrap(), std::fs::read(&wal).unwrap());
+        drop(con);
+        data
+    }
+
+    #[test]
+    fn wal_frames_up_to_the_last_valid_commit() {
+        let dir = TempDir::new("carve-wal");
+        let (_, mut wal) = deleted_rows(&dir);
+        let (frames, committed) = wal_frames(&wal, 4096);
+        assert!(!frames.is_empty());
+        assert_eq!(committed, frames.len());
+        assert_eq!(frames[0].number, 1);
+        assert_ne!(frames[committed - 1].commit, 0);
+
+        // A frame with a wrong checksum ends the committed part of the log
+        let last = WAL_HEADER + (frames.len() - 1) * (FRAME_HEADER + 4096);
+        wal[last + FRAME_HEADER] ^= 0xff;
+        let (frames, committed) = wal_frames(&wal, 4096);
+        assert!(committed < frames.len());
+        assert_eq!(wal_frames(&wal, 1024).0.len(), 0);
+    }
+
+    #[test]
+    fn deleted_rows_are_carved() {
+        let dir = TempDir::new("carve");
+        let (db, wal) = deleted_rows(&dir);
+        let backup = pack_files(
+            &dir,
+            &[
+                ("HomeDomain", "people.sqlite", &db),
+                ("HomeDomain", "people.sqlite-wal", &wal),
+            ],
+        );
+        let rows = carve(&backup, &backup::file_id("HomeDomain", "people.sqlite")).unwrap();
+        let find = |name: &str| {
+            rows.iter()
+                .find(|x| x.values.iter().any(|v| v == name))
+                .unwrap_or_else(|| panic!("{} not carved", name))
+        };
+
+        // Carol is still a cell of the page as it is in the database file
+        let carol = find("carol");
+        assert_eq!(carol.source, Source::Prewal);
+        assert_eq!(carol.area, Area::Cell);
+        assert_eq!(carol.table.as_deref(), Some("people"));
+        assert_eq!(carol.rowid, Some(3));
+        assert_eq!(carol.values, [serde_json::json!(3), "carol".into()]);
+
+        // Bob was checkpointed, leaving a freeblock in that page whose first
+        // bytes are overwritten
+        let bob = find("bob");
+        assert_eq!(bob.source, Source::Prewal);
+        assert_eq!(bob.area, Area::Freeblock);
+        assert_eq!(bob.table.as_deref(), Some("people"));
+        assert_eq!(bob.values[1], "bob");
+
+        assert!(!rows
+            .iter()
+            .any(|x| x.values.iter().any(|v| v == "alice" || v == "dave")));
+    }
+}
@@ -0,0 +1,641 @@
+use std::{collections::BTreeMap, io::Write};
+
+use rusqlite::Connection;
+
+use crate::{
+    backup::{file_id, Backup},
+    sqliteview::Snapshot,
+    util::{csv_line, APPLE_EPOCH},
+};
+
+const DOMAIN: &str = "HomeDomain";
+const CONTACTS_PATH: &str = "Library/AddressBook/AddressBook.sqlitedb";
+const IMAGES_PATH: &str = "Library/AddressBook/AddressBookImages.sqlitedb";
+
+// ABMultiValue properties
+const PHONE: i64 = 3;
+const EMAIL: i64 = 4;
+const ADDRESS: i64 = 5;
+const URL: i64 = 22;
+
+// Year of birthdays saved without one
+const NO_YEAR: i32 = 1604;
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Format {
+    // vCard 4.0, every contact in one file
+    VCard,
+    Csv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vcard" => Ok(Format::VCard),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("Unknown contacts format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Labeled {
+    // Label with Apple's _$!<..>!$_ markers removed, such as Mobile or Home
+    pub label: Option<String>,
+    pub value: String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Address {
+    pub label: Option<String>,
+    pub street: String,
+    pub city: String,
+    pub region: String,
+    pub postal_code: String,
+    pub country: String,
+}
+
+impl Address {
+    fn parts(&self) -> [&str; 5] {
+        [
+            &self.street,
+            &self.city,
+            &self.region,
+            &self.postal_code,
+            &self.country,
+        ]
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Contact {
+    pub rowid: i64,
+    pub first: String,
+    pub middle: String,
+    pub last: String,
+    pub prefix: String,
+    pub suffix: String,
+    pub nickname: String,
+    pub organization: String,
+    pub department: String,
+    pub job_title: String,
+    // YYYY-MM-DD, or --MM-DD without a year
+    pub birthday: Option<String>,
+    pub note: String,
+    pub phones: Vec<Labeled>,
+    pub emails: Vec<Labeled>,
+    pub urls: Vec<Labeled>,
+    pub addresses: Vec<Address>,
+    // Media type and contents
+    pub photo: Option<(&'static str, Vec<u8>)>,
+}
+
+impl Contact {
+    fn full_name(&self) -> String {
+        let name = [
+            &self.prefix,
+            &self.first,
+            &self.middle,
+            &self.last,
+            &self.suffix,
+        ]
+        .into_iter()
+        .filter(|x| !x.is_empty())
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ");
+        if !name.is_empty() {
+            name
+        } else if !self.organization.is_empty() {
+            self.organization.clone()
+        } else {
+            self.nickname.clone()
+        }
+    }
+}
+
+const COLUMNS: [&str; 17] = [
+    "rowid",
+    "first",
+    "middle",
+    "last",
+    "prefix",
+    "suffix",
+    "nickname",
+    "organization",
+    "department",
+    "jobTitle",
+    "birthday",
+    "note",
+    "phones",
+    "emails",
+    "addresses",
+    "urls",
+    "photo",
+];
+
+// Every person of the address book with their phones, emails, addresses, URLs
+// and photo
+pub(crate) fn contacts(backup: &Backup) -> Result<Vec<Contact>, String> {
+    let con = backup.open_database(&file_id(DOMAIN, CONTACTS_PATH), Snapshot::Latest)?;
+    let mut contacts = read_contacts(&con).map_err(|e| e.to_string())?;
+
+    // Photos are in a database of their own, which may be missing
+    let images_id = file_id(DOMAIN, IMAGES_PATH);
+    let photos = backup
+        .open_database(&images_id, Snapshot::Latest)
+        .and_then(|con| read_photos(&con).map_err(|e| e.to_string()));
+    match photos {
+        Ok(mut photos) => {
+            for contact in &mut contacts {
+                contact.photo = photos.remove(&contact.rowid);
+            }
+        }
+        Err(e) => eprintln!(
+            "warning: unreadable_database file_id={} error={}",
+            images_id, e
+        ),
+    }
+    Ok(contacts)
+}
+
+fn read_contacts(con: &Connection) -> rusqlite::Result<Vec<Contact>> {
+    let mut sta = con.prepare(
+        "SELECT ROWID, First, Middle, Last, Prefix, Suffix, Nickname, Organization, Department, \
+         JobTitle, Birthday, Note FROM ABPerson ORDER BY ROWID",
+    )?;
+    let mut contacts = sta
+        .query_map((), |r| {
+            Ok(Contact {
+                rowid: r.get(0)?,
+                first: text(r, 1)?,
+                middle: text(r, 2)?,
+                last: text(r, 3)?,
+                prefix: text(r, 4)?,
+                suffix: text(r, 5)?,
+                nickname: text(r, 6)?,
+                organization: text(r, 7)?,
+                department: text(r, 8)?,
+                job_title: text(r, 9)?,
+                birthday: birthday(r.get_ref(10)?),
+                note: text(r, 11)?,
+                ..Default::default()
+            })
+        })?
+        .map(|x| x.map(|c| (c.rowid, c)))
+        .collect::<rusqlite::Result<BTreeMap<_, _>>>()?;
+
+    // Parts of addresses, keyed by the UID of their multi-value
+    let mut entries: BTreeMap<i64, Vec<(String, String)>> = BTreeMap::new();
+    let mut sta = con.prepare(
+        "SELECT e.parent_id, k.value, e.value FROM ABMultiValueEntry e \
+         JOIN ABMultiValueEntryKey k ON k.ROWID = e.key",
+    )?;
+    let mut rows = sta.query(())?;
+    while let Some(r) = rows.next()? {
+        entries
+            .entry(r.get(0)?)
+            .or_default()
+            .push((r.get(1)?, text(r, 2)?));
+    }
+
+    let mut sta = con.prepare(
+        "SELECT mv.record_id, mv.property, mv.UID, l.value, mv.value FROM ABMultiValue mv \
+         LEFT JOIN ABMultiValueLabel l ON l.ROWID = mv.label ORDER BY mv.record_id, mv.UID",
+    )?;
+    let mut rows = sta.query(())?;
+    while let Some(r) = rows.next()? {
+        let Some(contact) = contacts.get_mut(&r.get(0)?) else {
+            continue;
+        };
+        let label = r.get::<_, Option<String>>(3)?.map(|x| strip_label(&x));
+        let labeled = || {
+            Ok::<_, rusqlite::Error>(Labeled {
+                label: label.clone(),
+                value: text(r, 4)?,
+            })
+        };
+        match r.get(1)? {
+            PHONE => contact.phones.push(labeled()?),
+            EMAIL => contact.emails.push(labeled()?),
+            URL => contact.urls.push(labeled()?),
+            ADDRESS => {
+                let mut address = Address {
+                    label,
+                    ..Default::default()
+                };
+                for (key, value) in entries.remove(&r.get(2)?).unwrap_or_default() {
+                    let part = match key.as_str() {
+                        "Street" => &mut address.street,
+                        "City" => &mut address.city,
+                        "State" => &mut address.region,
+                        "ZIP" => &mut address.postal_code,
+                        "Country" => &mut address.country,
+                        _ => continue,
+                    };
+                    *part = value;
+                }
+                contact.addresses.push(address);
+            }
+            _ => {}
+        }
+    }
+    Ok(contacts.into_values().collect())
+}
+
+// Full size photo of each person, or else its thumbnail when it is stored as
+// an image file rather than raw pixels
+fn read_photos(con: &Connection) -> rusqlite::Result<BTreeMap<i64, (&'static str, Vec<u8>)>> {
+    let mut photos = BTreeMap::new();
+    for table in ["ABThumbnailImage", "ABFullSizeImage"] {
+        let mut sta = con.prepare(&format!("SELECT record_id, data FROM {}", table))?;
+        let mut rows = sta.query(())?;
+        while let Some(r) = rows.next()? {
+            let data: Option<Vec<u8>> = r.get(1)?;
+            if let Some(photo) = data.and_then(|x| Some((media_type(&x)?, x))) {
+                photos.insert(r.get(0)?, photo);
+            }
+        }
+    }
+    Ok(photos)
+}
+
+// Text column read as empty when NULL
+fn text(r: &rusqlite::Row, i: usize) -> rusqlite::Result<String> {
+    Ok(r.get::<_, Option<String>>(i)?.unwrap_or_default())
+}
+
+fn media_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"\x89PNG") {
+        Some("image/png")
+    } else if data.get(4..12).is_some_and(|x| x == b"ftypheic") {
+        Some("image/heic")
+    } else {
+        None
+    }
+}
+
+// Birthdays are seconds since 2001, stored as text or a number
+fn birthday(value: rusqlite::types::ValueRef) -> Option<String> {
+    use rusqlite::types::ValueRef;
+    let secs = match value {
+        ValueRef::Integer(x) => x as f64,
+        ValueRef::Real(x) => x,
+        ValueRef::Text(x) => std::str::from_utf8(x).ok()?.trim().parse().ok()?,
+        _ => return None,
+    };
+    let date = chrono::DateTime::from_timestamp(secs.floor() as i64 + APPLE_EPOCH, 0)?.date_naive();
+    if chrono::Datelike::year(&date) == NO_YEAR {
+        Some(date.format("--%m-%d").to_string())
+    } else {
+        Some(date.format("%Y-%m-%d").to_string())
+    }
+}
+
+fn strip_label(label: &str) -> String {
+    label
+        .strip_prefix("_$!<")
+        .and_then(|x| x.strip_suffix(">!$_"))
+        .unwrap_or(label)
+        .to_owned()
+}
+
+// Writes the contacts to output, or stdout if it is "-"
+pub(crate) fn write(contacts: &[Contact], format: Format, output: &str) -> Result<(), String> {
+    let out: Box<dyn Write> = match output {
+        "-" => Box::new(std::io::stdout().lock()),
+        path => Box::new(std::fs::File::create(path).map_err(|e| e.to_string())?),
+    };
+    let mut out = std::io::BufWriter::new(out);
+    match format {
+        Format::VCard => {
+            for contact in contacts {
+                write_vcard(&mut out, contact).map_err(|e| e.to_string())?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "{}", csv_line(&COLUMNS)).map_err(|e| e.to_string())?;
+            for contact in contacts {
+                writeln!(out, "{}", csv_line(&csv_columns(contact))).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    out.flush().map_err(|e| e.to_string())
+}
+
+// Values in the order of COLUMNS, multi-values one per line as label: value,
+// and the media type of the photo
+fn csv_columns(contact: &Contact) -> [String; 17] {
+    fn lines<'a>(values: impl Iterator<Item = (&'a Option<String>, String)>) -> String {
+        values
+            .map(|(label, value)| match label {
+                Some(label) => format!("{}: {}", label, value),
+                None => value,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    fn labeled(values: &[Labeled]) -> String {
+        lines(values.iter().map(|x| (&x.label, x.value.clone())))
+    }
+    [
+        contact.rowid.to_string(),
+        contact.first.clone(),
+        contact.middle.clone(),
+        contact.last.clone(),
+        contact.prefix.clone(),
+        contact.suffix.clone(),
+        contact.nickname.clone(),
+        contact.organization.clone(),
+        contact.department.clone(),
+        contact.job_title.clone(),
+        contact.birthday.clone().unwrap_or_default(),
+        contact.note.clone(),
+        labeled(&contact.phones),
+        labeled(&contact.emails),
+        lines(contact.addresses.iter().map(|x| {
+            let parts: Vec<&str> = x.parts().into_iter().filter(|x| !x.is_empty()).collect();
+            (&x.label, parts.join(", "))
+        })),
+        labeled(&contact.urls),
+        contact
+            .photo
+            .as_ref()
+            .map(|x| x.0.to_owned())
+            .unwrap_or_default(),
+    ]
+}
+
+fn write_vcard(out: &mut impl Write, contact: &Contact) -> std::io::Result<()> {
+    let mut lines = vec![
+        "BEGIN:VCARD".to_owned(),
+        "VERSION:4.0".to_owned(),
+        format!("FN:{}", escape(&contact.full_name())),
+        format!(
+            "N:{}",
+            [
+                &contact.last,
+                &contact.first,
+                &contact.middle,
+                &contact.prefix,
+                &contact.suffix
+            ]
+            .map(|x| escape(x))
+            .join(";")
+        ),
+    ];
+    let mut optional = |name: &str, value: &str| {
+        if !value.is_empty() {
+            lines.push(format!("{}:{}", name, value));
+        }
+    };
+    optional("NICKNAME", &escape(&contact.nickname));
+    if !contact.organization.is_empty() || !contact.department.is_empty() {
+        optional(
+            "ORG",
+            &format!(
+                "{};{}",
+                escape(&contact.organization),
+                escape(&contact.department)
+            ),
+        );
+    }
+    optional("TITLE", &escape(&contact.job_title));
+    optional("BDAY", contact.birthday.as_deref().unwrap_or_default());
+    optional("NOTE", &escape(&contact.note));
+
+    // Labels without a vCard type are kept as Apple does, in an X-ABLabel of
+    // the same group
+    let mut group = 0;
+    let mut labeled = |name: &str, label: &Option<String>, value: String| match label
+        .as_deref()
+        .map(|x| (x, vcard_type(x)))
+    {
+        Some((_, Some(""))) | None => lines.push(format!("{}:{}", name, value)),
+        Some((_, Some(types))) => lines.push(format!("{};TYPE={}:{}", name, types, value)),
+        Some((label, None)) => {
+            group += 1;
+            lines.push(format!("item{}.{}:{}", group, name, value));
+            lines.push(format!("item{}.X-ABLabel:{}", group, escape(label)));
+        }
+    };
+    for x in &contact.phones {
+        labeled("TEL", &x.label, escape(&x.value));
+    }
+    for x in &contact.emails {
+        labeled("EMAIL", &x.label, escape(&x.value));
+    }
+    for x in &contact.addresses {
+        let parts = x.parts().map(escape);
+        labeled("ADR", &x.label, format!(";;{}", parts.join(";")));
+    }
+    for x in &contact.urls {
+        labeled("URL", &x.label, escape(&x.value));
+    }
+
+    if let Some((media_type, data)) = &contact.photo {
+        lines.push(format!("PHOTO:data:{};base64,{}", media_type, base64(data)));
+    }
+    lines.push("END:VCARD".to_owned());
+
+    for line in lines {
+        write!(out, "{}\r\n", fold(&line))?;
+    }
+    Ok(())
+}
+
+// TYPE of the labels vCard has one for, empty for labels that need none
+fn vcard_type(label: &str) -> Option<&'static str> {
+    match label {
+        "Mobile" | "iPhone" => Some("cell"),
+        "Home" => Some("home"),
+        "Work" => Some("work"),
+        "Main" => Some("voice"),
+        "HomeFAX" => Some("home,fax"),
+        "WorkFAX" => Some("work,fax"),
+        "OtherFAX" => Some("fax"),
+        "Pager" => Some("pager"),
+        "Other" | "HomePage" => Some(""),
+        _ => None,
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+// Lines are folded after 75 octets, continuation lines starting with a space
+fn fold(line: &str) -> String {
+    let mut out = String::with_capacity(line.len() + line.len() / 37);
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            len = 1;
+        }
+        out.push(c);
+        len += c.len_utf8();
+    }
+    out
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // AddressBook.sqlitedb with the tables and columns read by read_contacts
+    fn address_book() -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute_batch(
+            "CREATE TABLE ABPerson (ROWID INTEGER PRIMARY KEY, First TEXT, Middle TEXT, \
+                 Last TEXT, Prefix TEXT, Suffix TEXT, Nickname TEXT, Organization TEXT, \
+                 Department TEXT, JobTitle TEXT, Birthday TEXT, Note TEXT);
+             CREATE TABLE ABMultiValue (UID INTEGER PRIMARY KEY, record_id INTEGER, \
+                 property INTEGER, label INTEGER, value TEXT);
+             CREATE TABLE ABMultiValueLabel (ROWID INTEGER PRIMARY KEY, value TEXT);
+             CREATE TABLE ABMultiValueEntry (parent_id INTEGER, key INTEGER, value TEXT);
+             CREATE TABLE ABMultiValueEntryKey (ROWID INTEGER PRIMARY KEY, value TEXT);
+
+             INSERT INTO ABPerson (ROWID, First, Last, Birthday)
+                 VALUES (1, 'Ann', 'Lee', -12516292800);
+             INSERT INTO ABPerson (ROWID, Organization, Birthday)
+                 VALUES (2, 'Acme, Inc.', ' -344260800.0');
+             INSERT INTO ABMultiValueLabel VALUES
+                 (1, '_$!<Mobile>!$_'), (2, '_$!<HomeFAX>!$_'), (3, 'Boat'), (4, '_$!<Work>!$_'),
+                 (5, '_$!<Other>!$_'), (6, '_$!<Home>!$_');
+             INSERT INTO ABMultiValue VALUES
+                 (1, 1, 3, 1, '+1 555 0100'),
+                 (2, 1, 3, 2, '+1 555 0101'),
+                 (3, 1, 3, 3, '+1 555 0102'),
+                 (4, 1, 3, NULL, '+1 555 0103'),
+                 (5, 1, 4, 4, 'ann@work.example'),
+                 (6, 1, 4, 5, 'ann@example.com'),
+                 (7, 1, 5, 6, NULL),
+                 (8, 3, 3, 1, '+1 555 0199');
+             INSERT INTO ABMultiValueEntryKey VALUES
+                 (1, 'Street'), (2, 'City'), (3, 'ZIP'), (4, 'Country');
+             INSERT INTO ABMultiValueEntry VALUES
+                 (7, 1, '1 Main St'), (7, 2, 'Springfield'), (7, 3, '12345'), (7, 4, 'USA');",
+        )
+        .unwrap();
+        con
+    }
+
+    #[test]
+    fn contacts_are_written_as_vcards() {
+        let contacts = read_contacts(&address_book()).unwrap();
+        assert_eq!(contacts.len(), 2);
+        let ann = &contacts[0];
+        let phones = ann
+            .phones
+            .iter()
+            .map(|x| (x.label.as_deref(), x.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            phones,
+            [
+                (Some("Mobile"), "+1 555 0100"),
+                (Some("HomeFAX"), "+1 555 0101"),
+                (Some("Boat"), "+1 555 0102"),
+                (None, "+1 555 0103"),
+            ]
+        );
+        assert_eq!(ann.addresses[0].label.as_deref(), Some("Home"));
+        assert_eq!(ann.addresses[0].city, "Springfield");
+
+        let mut out = Vec::new();
+        for contact in &contacts {
+            write_vcard(&mut out, contact).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out)
+                .unwrap()
+                .split("\r\n")
+                .collect::<Vec<_>>(),
+            [
+                "BEGIN:VCARD",
+                "VERSION:4.0",
+                "FN:Ann Lee",
+                "N:Lee;Ann;;;",
+                "BDAY:--05-17",
+                "TEL;TYPE=cell:+1 555 0100",
+                "TEL;TYPE=home,fax:+1 555 0101",
+                "item1.TEL:+1 555 0102",
+                "item1.X-ABLabel:Boat",
+                "TEL:+1 555 0103",
+                "EMAIL;TYPE=work:ann@work.example",
+                "EMAIL:ann@example.com",
+                "ADR;TYPE=home:;;1 Main St;Springfield;;12345;USA",
+                "END:VCARD",
+                "BEGIN:VCARD",
+                "VERSION:4.0",
+                "FN:Acme\\, Inc.",
+                "N:;;;;",
+                "ORG:Acme\\, Inc.;",
+                "BDAY:1990-02-03",
+                "END:VCARD",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xff, 0xfe]), "//4=");
+    }
+
+    #[test]
+    fn folding() {
+        let a = |n| "a".repeat(n);
+        assert_eq!(fold(&a(75)), a(75));
+        assert_eq!(fold(&a(76)), format!("{}\r\n a", a(75)));
+        // Continuation lines hold 74 octets after their space
+        assert_eq!(fold(&a(150)), format!("{}\r\n {}\r\n a", a(75), a(74)));
+
+        // Characters are never split, even when that leaves a line short
+        assert_eq!(fold(&format!("{}é", a(74))), format!("{}\r\n é", a(74)));
+        assert_eq!(fold(&format!("{}é", a(73))), format!("{}é", a(73)));
+        let emoji = "😀".repeat(40);
+        let folded = fold(&emoji);
+        for line in folded.split("\r\n") {
+            assert!(line.len() <= 75);
+        }
+        assert_eq!(folded.replace("\r\n ", ""), emoji);
+        assert_eq!(folded.split("\r\n").next().unwrap(), "😀".repeat(18));
+    }
+}
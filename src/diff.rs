@@ -0,0 +1,592 @@
+use std::collections::BTreeMap;
+
+use rusqlite::{types::ValueRef, Connection};
+
+use crate::{
+    backup::Backup,
+    manifestdb::MBFile,
+    sqliteview::Snapshot,
+    util::{format_time, hex, SQLITE_MAGIC},
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Modified => "modified",
+        }
+    }
+
+    fn as_char(&self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Removed => 'D',
+            ChangeKind::Modified => 'M',
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct FieldChange {
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Change {
+    pub kind: ChangeKind,
+    #[serde(rename = "fileID")]
+    pub id: String,
+    pub domain: String,
+    #[serde(rename = "relativePath")]
+    pub path: String,
+    // 1 for files, 2 for folders and 4 for symlinks
+    pub flags: i64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<&'static str, FieldChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<Vec<String>>,
+}
+
+struct Entry {
+    domain: String,
+    path: String,
+    flags: i64,
+    file: MBFile,
+}
+
+// Files, folders and symlinks. Records whose MBFile can't be decoded are
+// reported and left out.
+fn load_files(con: &Connection) -> BTreeMap<String, Entry> {
+    let mut sta = con
+        .prepare("SELECT fileID, domain, relativePath, flags, file FROM Files")
+        .unwrap();
+    let mut rows = sta.query(()).unwrap();
+
+    let mut files = BTreeMap::new();
+    while let Some(row) = rows.next().unwrap() {
+        let id: String = row.get(0).unwrap();
+        let file = match row.get(4) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("warning: invalid_mbfile file_id={} error={}", id, e);
+                continue;
+            }
+        };
+        let entry = Entry {
+            domain: row.get(1).unwrap(),
+            path: row.get(2).unwrap(),
+            flags: row.get(3).unwrap(),
+            file,
+        };
+        files.insert(id, entry);
+    }
+    files
+}
+
+// Files are matched on fileID, which is derived from domain and relativePath.
+// An entry is modified when its type, size, times, digest, mode, symlink target
+// or protection class changed. With content set, modified plist and SQLite
+// files are also compared by content.
+pub(crate) fn diff(old: &Backup, new: &Backup, content: bool) -> Vec<Change> {
+    let old_files = load_files(&old.con);
+    let new_files = load_files(&new.con);
+
+    let mut changes = changes(&old_files, &new_files);
+    if content {
+        for change in &mut changes {
+            if change.kind != ChangeKind::Modified {
+                continue;
+            }
+            let (o, n) = (&old_files[&change.id], &new_files[&change.id]);
+            if o.flags == 1 && n.flags == 1 {
+                change.content = content_changes(old, new, &change.id, &o.file, &n.file);
+            }
+        }
+    }
+    changes
+}
+
+fn changes(
+    old_files: &BTreeMap<String, Entry>,
+    new_files: &BTreeMap<String, Entry>,
+) -> Vec<Change> {
+    let mut changes = Vec::new();
+
+    for (id, n) in new_files {
+        let (kind, fields) = match old_files.get(id) {
+            None => (ChangeKind::Added, BTreeMap::new()),
+            Some(o) => {
+                let fields = field_changes(o, n);
+                if fields.is_empty() {
+                    continue;
+                }
+                (ChangeKind::Modified, fields)
+            }
+        };
+        changes.push(Change {
+            kind,
+            id: id.clone(),
+            domain: n.domain.clone(),
+            path: n.path.clone(),
+            flags: n.flags,
+            fields,
+            content: None,
+        });
+    }
+
+    for (id, o) in old_files {
+        if !new_files.contains_key(id) {
+            changes.push(Change {
+                kind: ChangeKind::Removed,
+                id: id.clone(),
+                domain: o.domain.clone(),
+                path: o.path.clone(),
+                flags: o.flags,
+                fields: BTreeMap::new(),
+                content: None,
+            });
+        }
+    }
+
+    changes.sort_by(|a, b| (&a.domain, &a.path).cmp(&(&b.domain, &b.path)));
+    changes
+}
+
+fn field_changes(old: &Entry, new: &Entry) -> BTreeMap<&'static str, FieldChange> {
+    let mut fields = BTreeMap::new();
+    let mut compare = |name, old: String, new: String| {
+        if old != new {
+            fields.insert(name, FieldChange { old, new });
+        }
+    };
+
+    compare("flags", old.flags.to_string(), new.flags.to_string());
+    let (old, new) = (&old.file, &new.file);
+    compare("size", old.size.to_string(), new.size.to_string());
+    compare(
+        "mtime",
+        format_time(old.last_modified),
+        format_time(new.last_modified),
+    );
+    compare(
+        "ctime",
+        format_time(old.last_status_change),
+        format_time(new.last_status_change),
+    );
+    compare(
+        "digest",
+        old.digest.as_ref().map(|d| hex(d.as_ref())).unwrap_or_default(),
+        new.digest.as_ref().map(|d| hex(d.as_ref())).unwrap_or_default(),
+    );
+    compare("mode", format!("{:o}", old.mode), format!("{:o}", new.mode));
+    compare(
+        "target",
+        old.target.clone().unwrap_or_default(),
+        new.target.clone().unwrap_or_default(),
+    );
+    compare(
+        "protection_class",
+        old.protection_class.to_string(),
+        new.protection_class.to_string(),
+    );
+
+    fields
+}
+
+// Databases are opened in place through the VFS, so no decrypted copy of them
+// is written out
+fn content_changes(
+    old: &Backup,
+    new: &Backup,
+    id: &str,
+    old_file: &MBFile,
+    new_file: &MBFile,
+) -> Option<Vec<String>> {
+    let mut out = Vec::new();
+
+    let is_sqlite = |backup: &Backup, file| {
+        backup
+            .read_head(id, file, SQLITE_MAGIC.len())
+            .is_some_and(|x| x.starts_with(SQLITE_MAGIC))
+    };
+    if is_sqlite(old, old_file) && is_sqlite(new, new_file) {
+        sqlite_changes(old, new, id, &mut out);
+        return Some(out);
+    }
+
+    let old = plist::from_bytes::<plist::Value>(&old.read_file(id, old_file)?).ok()?;
+    let new = plist::from_bytes::<plist::Value>(&new.read_file(id, new_file)?).ok()?;
+    plist_changes("", &old, &new, &mut out);
+    Some(out)
+}
+
+fn plist_changes(path: &str, old: &plist::Value, new: &plist::Value, out: &mut Vec<String>) {
+    use plist::Value;
+
+    match (old, new) {
+        (Value::Dictionary(o), Value::Dictionary(n)) => {
+            for (key, ov) in o {
+                let path = format!("{}/{}", path, key);
+                match n.get(key) {
+                    Some(nv) => plist_changes(&path, ov, nv, out),
+                    None => out.push(format!("- {}: {}", path, render(ov))),
+                }
+            }
+            for (key, nv) in n {
+                if !o.contains_key(key) {
+                    out.push(format!("+ {}/{}: {}", path, key, render(nv)));
+                }
+            }
+        }
+        (Value::Array(o), Value::Array(n)) => {
+            for (i, (ov, nv)) in o.iter().zip(n).enumerate() {
+                plist_changes(&format!("{}/{}", path, i), ov, nv, out);
+            }
+            for (i, ov) in o.iter().enumerate().skip(n.len()) {
+                out.push(format!("- {}/{}: {}", path, i, render(ov)));
+            }
+            for (i, nv) in n.iter().enumerate().skip(o.len()) {
+                out.push(format!("+ {}/{}: {}", path, i, render(nv)));
+            }
+        }
+        (o, n) if o != n => out.push(format!("~ {}: {} -> {}", path, render(o), render(n))),
+        _ => (),
+    }
+}
+
+fn render(value: &plist::Value) -> String {
+    use plist::Value;
+
+    match value {
+        Value::Array(a) => format!("[{} items]", a.len()),
+        Value::Dictionary(d) => format!("{{{} keys}}", d.len()),
+        Value::Boolean(b) => b.to_string(),
+        Value::Data(d) if d.len() > 32 => format!("<{}... {} bytes>", hex(&d[..32]), d.len()),
+        Value::Data(d) => format!("<{}>", hex(d)),
+        Value::Date(d) => chrono::DateTime::<chrono::Utc>::from(std::time::SystemTime::from(*d))
+            .to_rfc3339(),
+        Value::Real(r) => r.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::String(s) => format!("{:?}", s),
+        Value::Uid(u) => format!("uid({})", u.get()),
+        _ => format!("{:?}", value),
+    }
+}
+
+// Compares table schemas and rows of two SQLite databases
+fn sqlite_changes(old: &Backup, new: &Backup, id: &str, out: &mut Vec<String>) {
+    let tables = |backup: &Backup| {
+        let con = backup.open_database(id, Snapshot::Latest).ok()?;
+        tables(&con)
+    };
+    let (Some(old), Some(new)) = (tables(old), tables(new)) else {
+        out.push("! unable to open database".to_owned());
+        return;
+    };
+    table_changes(&old, &new, out);
+}
+
+// Rows are compared by content, a row edited in place counting as one removed
+// and one added
+fn table_changes(
+    old: &BTreeMap<String, Table>,
+    new: &BTreeMap<String, Table>,
+    out: &mut Vec<String>,
+) {
+    for (name, o) in old {
+        match new.get(name) {
+            None => out.push(format!("- table {} ({} rows)", name, o.count)),
+            Some(n) => {
+                if o.sql != n.sql {
+                    out.push(format!("~ table {}: schema changed", name));
+                }
+                let (Some(orows), Some(nrows)) = (&o.rows, &n.rows) else {
+                    if o.count != n.count {
+                        out.push(format!("~ table {}: rows {} -> {}", name, o.count, n.count));
+                    }
+                    continue;
+                };
+                let only_in = |a: &BTreeMap<u64, i64>, b: &BTreeMap<u64, i64>| -> i64 {
+                    a.iter()
+                        .map(|(hash, count)| (count - b.get(hash).unwrap_or(&0)).max(0))
+                        .sum()
+                };
+                let (removed, added) = (only_in(orows, nrows), only_in(nrows, orows));
+                if removed != 0 || added != 0 {
+                    out.push(format!(
+                        "~ table {}: rows {} -> {} ({} added, {} removed)",
+                        name, o.count, n.count, added, removed
+                    ));
+                }
+            }
+        }
+    }
+    for (name, n) in new {
+        if !old.contains_key(name) {
+            out.push(format!("+ table {} ({} rows)", name, n.count));
+        }
+    }
+}
+
+struct Table {
+    sql: Option<String>,
+    count: i64,
+    // Number of rows with each hash of their values, None if they can't be read
+    rows: Option<BTreeMap<u64, i64>>,
+}
+
+fn tables(con: &Connection) -> Option<BTreeMap<String, Table>> {
+    let mut sta = con
+        .prepare("SELECT name, sql FROM sqlite_master WHERE type = 'table'")
+        .ok()?;
+    let tables = sta
+        .query_map((), |r| Ok((r.get::<_, String>(0)?, r.get(1)?)))
+        .ok()?
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+
+    let mut res = BTreeMap::new();
+    for (name, sql) in tables {
+        let rows = row_hashes(con, &name).ok();
+        let count = match &rows {
+            Some(rows) => rows.values().sum(),
+            None => -1,
+        };
+        res.insert(name, Table { sql, count, rows });
+    }
+    Some(res)
+}
+
+fn row_hashes(con: &Connection, table: &str) -> rusqlite::Result<BTreeMap<u64, i64>> {
+    use std::hash::{Hash, Hasher};
+
+    let mut sta = con.prepare(&format!("SELECT * FROM \"{}\"", table.replace('"', "\"\"")))?;
+    let columns = sta.column_count();
+    let mut rows = sta.query(())?;
+    let mut hashes = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        for i in 0..columns {
+            match row.get_ref(i)? {
+                ValueRef::Null => 0u8.hash(&mut hasher),
+                ValueRef::Integer(x) => (1u8, x).hash(&mut hasher),
+                ValueRef::Real(x) => (2u8, x.to_bits()).hash(&mut hasher),
+                ValueRef::Text(x) => (3u8, x).hash(&mut hasher),
+                ValueRef::Blob(x) => (4u8, x).hash(&mut hasher),
+            }
+        }
+        *hashes.entry(hasher.finish()).or_insert(0) += 1;
+    }
+    Ok(hashes)
+}
+
+pub(crate) fn print_text(changes: &[Change]) {
+    for change in changes {
+        println!(
+            "{} {}/{}",
+            change.kind.as_char(),
+            change.domain,
+            change.path
+        );
+        for (name, field) in &change.fields {
+            println!("    {}: {} -> {}", name, field.old, field.new);
+        }
+        for line in change.content.iter().flatten() {
+            println!("    {}", line);
+        }
+    }
+}
+
+pub(crate) fn print_json(changes: &[Change]) {
+    serde_json::to_writer_pretty(std::io::stdout().lock(), changes).unwrap();
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_records_are_skipped() {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute_batch(
+            "CREATE TABLE Files (fileID TEXT PRIMARY KEY, domain TEXT, relativePath TEXT, flags INTEGER, file BLOB)",
+        )
+        .unwrap();
+        let valid = MBFile::new("Library/a", 0o100644, 0).to_bytes();
+        for (id, file) in [("aa", valid.as_slice()), ("bb", b"not an archive")] {
+            con.execute(
+                "INSERT INTO Files VALUES (?, 'HomeDomain', 'Library/a', 1, ?)",
+                (id, file),
+            )
+            .unwrap();
+        }
+
+        let files = load_files(&con);
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["aa"]);
+        assert_eq!(files["aa"].path, "Library/a");
+    }
+
+    fn manifest(files: &[(&str, &str, i64, &MBFile)]) -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute_batch(
+            "CREATE TABLE Files (fileID TEXT PRIMARY KEY, domain TEXT, relativePath TEXT, flags INTEGER, file BLOB)",
+        )
+        .unwrap();
+        for (id, path, flags, file) in files {
+            con.execute(
+                "INSERT INTO Files VALUES (?, 'HomeDomain', ?, ?, ?)",
+                (id, path, flags, file.to_bytes()),
+            )
+            .unwrap();
+        }
+        con
+    }
+
+    #[test]
+    fn changes_are_sorted_by_kind() {
+        let folder = MBFile::new("Library", 0o40755, 0);
+        let kept = MBFile::new("Library/kept", 0o100644, 0);
+        let removed = MBFile::new("Library/removed", 0o100644, 0);
+        let empty = MBFile::new("Library/resized", 0o100644, 0);
+        let mut resized = MBFile::new("Library/resized", 0o100644, 0);
+        resized.size = 10;
+        let mut link = MBFile::new("Library/link", 0o120755, 0);
+        link.target = Some("kept".to_owned());
+        let old = manifest(&[
+            ("01", "Library", 2, &folder),
+            ("02", "Library/kept", 1, &kept),
+            ("03", "Library/resized", 1, &empty),
+            ("04", "Library/removed", 1, &removed),
+        ]);
+        let new = manifest(&[
+            ("01", "Library", 2, &MBFile::new("Library", 0o40700, 0)),
+            ("02", "Library/kept", 1, &kept),
+            ("03", "Library/resized", 1, &resized),
+            ("05", "Library/link", 4, &link),
+        ]);
+
+        let changes = changes(&load_files(&old), &load_files(&new));
+        let summary = changes
+            .iter()
+            .map(|x| (x.kind, x.path.as_str(), x.flags))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (ChangeKind::Modified, "Library", 2),
+                (ChangeKind::Added, "Library/link", 4),
+                (ChangeKind::Removed, "Library/removed", 1),
+                (ChangeKind::Modified, "Library/resized", 1),
+            ]
+        );
+        assert_eq!(
+            changes[0].fields.keys().copied().collect::<Vec<_>>(),
+            ["mode"]
+        );
+        assert_eq!(changes[0].fields["mode"].new, "40700");
+        assert_eq!(
+            changes[3].fields.keys().copied().collect::<Vec<_>>(),
+            ["size"]
+        );
+        assert_eq!(changes[3].fields["size"].old, "0");
+        assert_eq!(changes[3].fields["size"].new, "10");
+    }
+
+    #[test]
+    fn type_and_target_changes_are_fields() {
+        let entry = |flags, target: Option<&str>| {
+            let mut file = MBFile::new("Library/a", 0o120755, 0);
+            file.target = target.map(str::to_owned);
+            Entry {
+                domain: "HomeDomain".to_owned(),
+                path: "Library/a".to_owned(),
+                flags,
+                file,
+            }
+        };
+
+        let fields = field_changes(&entry(4, Some("b")), &entry(4, Some("c")));
+        assert_eq!(fields.keys().copied().collect::<Vec<_>>(), ["target"]);
+        assert_eq!((&*fields["target"].old, &*fields["target"].new), ("b", "c"));
+
+        let fields = field_changes(&entry(1, None), &entry(4, Some("b")));
+        assert_eq!(
+            fields.keys().copied().collect::<Vec<_>>(),
+            ["flags", "target"]
+        );
+        assert_eq!((&*fields["flags"].old, &*fields["flags"].new), ("1", "4"));
+
+        assert!(field_changes(&entry(2, None), &entry(2, None)).is_empty());
+    }
+
+    #[test]
+    fn plist_changes_are_listed_by_path() {
+        let old = plist::Value::from_reader_xml(
+            &br#"<plist version="1.0"><dict>
+                <key>kept</key><integer>1</integer>
+                <key>changed</key><string>a</string>
+                <key>removed</key><true/>
+                <key>list</key><array><integer>1</integer><integer>2</integer></array>
+            </dict></plist>"#[..],
+        )
+        .unwrap();
+        let new = plist::Value::from_reader_xml(
+            &br#"<plist version="1.0"><dict>
+                <key>kept</key><integer>1</integer>
+                <key>changed</key><string>b</string>
+                <key>added</key><dict><key>x</key><integer>1</integer></dict>
+                <key>list</key><array><integer>1</integer><integer>3</integer><integer>4</integer></array>
+            </dict></plist>"#[..],
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        plist_changes("", &old, &new, &mut out);
+        assert_eq!(
+            out,
+            [
+                r#"~ /changed: "a" -> "b""#,
+                "- /removed: true",
+                "~ /list/1: 2 -> 3",
+                "+ /list/2: 4",
+                "+ /added: {1 keys}",
+            ]
+        );
+    }
+
+    #[test]
+    fn rows_are_compared_by_content() {
+        let database = |rows: &str| {
+            let con = Connection::open_in_memory().unwrap();
+            con.execute_batch(&format!(
+                "CREATE TABLE t (a, b); INSERT INTO t VALUES {}; CREATE TABLE same (a); INSERT INTO same VALUES (1)",
+                rows
+            ))
+            .unwrap();
+            tables(&con).unwrap()
+        };
+        let old = database("(1, 'x'), (2, 'y'), (2, 'y')");
+
+        let mut out = Vec::new();
+        table_changes(&old, &database("(2, 'y'), (1, 'x'), (2, 'y')"), &mut out);
+        assert!(out.is_empty(), "{:?}", out);
+
+        table_changes(&old, &database("(1, 'x'), (2, 'y'), (3, X'00')"), &mut out);
+        assert_eq!(out, ["~ table t: rows 3 -> 3 (1 added, 1 removed)"]);
+
+        out.clear();
+        table_changes(&old, &database("(1, 'x')"), &mut out);
+        assert_eq!(out, ["~ table t: rows 3 -> 1 (0 added, 2 removed)"]);
+    }
+}
@@ -1,128 +1,434 @@
-use std::{fs::File, os::unix::fs::FileExt};
+use std::{
+    cell::RefCell,
+    io::{Read, Seek, SeekFrom},
+    os::unix::fs::FileExt,
+};
 
-use aes::cipher::{BlockDecryptMut, KeyIvInit};
+use aes::cipher::{block_padding::NoPadding, BlockDecryptMut, KeyIvInit};
+
+use crate::cache::{self, PAGE_SIZE};
 
 type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
 
-pub struct CbcCache {
-    cbc: Aes256CbcDec,
-    key: [u8; 32],
-    offset: u64,
+// Reader for AES-256-CBC encrypted files with a zero IV, as stored in backups.
+//
+// Reads at any offset are supported: every CBC block only depends on the
+// ciphertext block before it, so a read needs a single read of the ciphertext
+// starting one block earlier. The logical length defaults to the ciphertext
+// length, set_len (for instance to padded_len) excludes the padding.
+pub struct DecryptingReader<R> {
+    inner: RefCell<BlockReader<R>>,
+    ciphertext_len: u64,
+    len: u64,
+    pos: u64,
 }
 
-impl CbcCache {
-    pub fn new(key: [u8; 32], iv: &[u8; 16], offset: u64) -> Self {
-        CbcCache {
-            cbc: Aes256CbcDec::new_from_slices(&key, iv).unwrap(),
-            key,
-            offset,
+impl<R: Read + Seek> DecryptingReader<R> {
+    pub fn new(mut source: R, key: [u8; 32]) -> std::io::Result<Self> {
+        let ciphertext_len = source.seek(SeekFrom::End(0))?;
+        let ciphertext_len = ciphertext_len - ciphertext_len % 16;
+        Ok(DecryptingReader {
+            inner: RefCell::new(BlockReader::new(source, key, ciphertext_len)),
+            ciphertext_len,
+            len: ciphertext_len,
+            pos: 0,
+        })
+    }
+
+    // Decrypts readahead bytes past the end of each read for following reads to use
+    pub fn readahead(self, readahead: usize) -> Self {
+        self.inner.borrow_mut().readahead = readahead;
+        self
+    }
+
+    // Shares decrypted pages of the blob at path with other readers, if the cache is enabled
+    pub fn with_cache(self, path: &std::path::Path) -> Self {
+        let mut cache = cache::global();
+        if cache.enabled() {
+            self.inner.borrow_mut().cache_file = Some(cache.file_id(path));
+        }
+        self
+    }
+
+    // Plaintext length given by the PKCS#5 padding of the last block, None if it is invalid.
+    // An empty blob holds an empty file.
+    pub fn padded_len(&self) -> Option<u64> {
+        if self.ciphertext_len == 0 {
+            return Some(0);
+        }
+        let mut bytes = [0u8; 16];
+        self.inner
+            .borrow_mut()
+            .read_at(&mut bytes, self.ciphertext_len - 16)
+            .ok()?;
+
+        let num_padding_bytes = bytes[15];
+
+        if num_padding_bytes == 0 || num_padding_bytes > 0x10 {
+            return None;
+        }
+        if !bytes[16 - num_padding_bytes as usize..]
+            .iter()
+            .all(|x| *x == num_padding_bytes)
+        {
+            return None;
         }
+        Some(self.ciphertext_len - num_padding_bytes as u64)
     }
 
-    fn recreate(&mut self, iv: &[u8; 16], offset: u64) {
-        self.cbc = Aes256CbcDec::new_from_slices(&self.key, iv).unwrap();
-        self.offset = offset;
+    // Sets the logical length, limited to the ciphertext length
+    pub fn set_len(&mut self, len: u64) {
+        self.len = std::cmp::min(len, self.ciphertext_len);
     }
 
-    fn set_offset(&mut self, offset: u64) {
-        self.offset = offset;
+    pub fn len(&self) -> u64 {
+        self.len
     }
 
-    fn get_offset(&self) -> u64 {
-        self.offset
+    pub fn ciphertext_len(&self) -> u64 {
+        self.ciphertext_len
+    }
+
+    // Reads decrypted data at offset, returning less than buf.len() only at the end of the file
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let end = std::cmp::min(offset.saturating_add(buf.len() as u64), self.len);
+        if offset >= end {
+            return Ok(0);
+        }
+        let n = (end - offset) as usize;
+        self.inner.borrow_mut().read_at(&mut buf[..n], offset)?;
+        Ok(n)
     }
 }
 
-pub fn has_correct_pkcs5_padding(
-    file: &File,
-    cbc_cache: &mut CbcCache,
-    padding_offset: u64,
-) -> bool {
-    let mut bytes = [0u8; 16];
+impl<R: Read + Seek> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = DecryptingReader::read_at(self, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
 
-    read_encrypted(file, cbc_cache, bytes.as_mut_ptr(), 16, padding_offset);
+impl<R: Read + Seek> Seek for DecryptingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => self.len.checked_add_signed(x),
+            SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before start")
+        })?;
+        Ok(self.pos)
+    }
+}
 
-    let num_padding_bytes = bytes[15];
+impl<R: Read + Seek> FileExt for DecryptingReader<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        DecryptingReader::read_at(self, buf, offset)
+    }
 
-    if num_padding_bytes > 0x10 {
-        return false;
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::Unsupported.into())
     }
-    bytes[16 - num_padding_bytes as usize..]
-        .iter()
-        .all(|x| *x == num_padding_bytes)
 }
 
-pub fn read_encrypted(
-    file: &File,
-    cbc_cache: &mut CbcCache,
-    p_out: *mut u8,
+// Decrypted data is kept in a window reused by following reads, which is
+// extended by readahead bytes so sequential reads mostly hit the window.
+// Readers using the cache keep their pages in the shared cache instead.
+struct BlockReader<R> {
+    source: R,
+    key: [u8; 32],
+    // Length of the ciphertext
     len: u64,
-    offset: u64,
-) {
-    let foffset = offset % 16;
-
-    if foffset != 0 {
-        let to_write = 16 - foffset;
-        let mut out = [0u8; 16];
-        read_encrypted(
-            file,
-            cbc_cache,
-            &mut out as *mut _ as _,
-            16,
-            offset - foffset,
+    readahead: usize,
+    // Ciphertext of the window preceded by the block used as its IV
+    scratch: Vec<u8>,
+    window: Vec<u8>,
+    window_offset: u64,
+    cache_file: Option<u64>,
+}
+
+impl<R: Read + Seek> BlockReader<R> {
+    fn new(source: R, key: [u8; 32], len: u64) -> Self {
+        BlockReader {
+            source,
+            key,
+            len,
+            readahead: 0,
+            scratch: Vec::new(),
+            window: Vec::new(),
+            window_offset: 0,
+            cache_file: None,
+        }
+    }
+
+    // Fills out, which must end within the ciphertext
+    fn read_at(&mut self, out: &mut [u8], offset: u64) -> std::io::Result<()> {
+        let end = offset + out.len() as u64;
+
+        if let Some(cache_file) = self.cache_file {
+            return self.read_cached(cache_file, out, offset);
+        }
+
+        // Aligned reads at least as large as the readahead bypass the window
+        if offset.is_multiple_of(16) && out.len().is_multiple_of(16) && out.len() >= self.readahead
+        {
+            self.read_ciphertext(offset, end)?;
+            Self::decryptor(&self.key, &self.scratch, offset)
+                .decrypt_padded_b2b_mut::<NoPadding>(&self.scratch[Self::iv_len(offset)..], out)
+                .unwrap();
+            return Ok(());
+        }
+
+        let window_end = self.window_offset + self.window.len() as u64;
+        if offset < self.window_offset || end > window_end {
+            let start = offset - offset % 16;
+            let fill_end = std::cmp::max(end, offset + self.readahead as u64);
+            let fill_end = std::cmp::min(fill_end.next_multiple_of(16), self.len);
+            self.fill(start, fill_end)?;
+        }
+
+        let pos = (offset - self.window_offset) as usize;
+        out.copy_from_slice(&self.window[pos..pos + out.len()]);
+        Ok(())
+    }
+
+    fn read_cached(&mut self, cache_file: u64, out: &mut [u8], offset: u64) -> std::io::Result<()> {
+        let end = offset + out.len() as u64;
+        let readahead_end = std::cmp::min(
+            std::cmp::max(end, offset + self.readahead as u64),
+            self.len,
         );
-        unsafe {
-            std::ptr::copy(
-                out[foffset as usize..].as_ptr(),
-                p_out as *mut _,
-                std::cmp::min(to_write as usize, len as usize),
-            );
+
+        for page in offset / PAGE_SIZE..readahead_end.div_ceil(PAGE_SIZE) {
+            let cached = cache::global().get(cache_file, page);
+            let data = match cached {
+                Some(data) => data,
+                None => {
+                    let start = page * PAGE_SIZE;
+                    self.fill(start, std::cmp::min(start + PAGE_SIZE, self.len))?;
+                    let data: std::sync::Arc<[u8]> = self.window.as_slice().into();
+                    cache::global().insert(cache_file, page, data.clone());
+                    data
+                }
+            };
+
+            let page_start = page * PAGE_SIZE;
+            let from = std::cmp::max(offset, page_start);
+            let to = std::cmp::min(end, page_start + data.len() as u64);
+            if from < to {
+                out[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+                    &data[(from - page_start) as usize..(to - page_start) as usize],
+                );
+            }
         }
-        if (len as i64 - to_write as i64) > 0 {
-            return read_encrypted(
-                file,
-                cbc_cache,
-                unsafe { p_out.add(to_write as usize) },
-                len - to_write,
-                offset + to_write,
-            );
+        Ok(())
+    }
+
+    fn iv_len(start: u64) -> usize {
+        if start == 0 {
+            0
+        } else {
+            16
         }
-        return;
     }
 
-    let rem_len = len % 16;
-    let len = len - rem_len;
+    fn decryptor(key: &[u8; 32], scratch: &[u8], start: u64) -> Aes256CbcDec {
+        let iv = if start == 0 { &[0; 16] } else { &scratch[..16] };
+        Aes256CbcDec::new_from_slices(key, iv).unwrap()
+    }
+
+    // Reads ciphertext start..end preceded by its IV block into scratch
+    fn read_ciphertext(&mut self, start: u64, end: u64) -> std::io::Result<()> {
+        let iv_len = Self::iv_len(start);
+        self.scratch.resize(iv_len + (end - start) as usize, 0);
+        self.source.seek(SeekFrom::Start(start - iv_len as u64))?;
+        self.source.read_exact(&mut self.scratch)
+    }
+
+    fn fill(&mut self, start: u64, end: u64) -> std::io::Result<()> {
+        self.read_ciphertext(start, end)?;
+        self.window.resize((end - start) as usize, 0);
+        self.window_offset = start;
+        Self::decryptor(&self.key, &self.scratch, start)
+            .decrypt_padded_b2b_mut::<NoPadding>(
+                &self.scratch[Self::iv_len(start)..],
+                &mut self.window,
+            )
+            .unwrap();
+        Ok(())
+    }
+}
 
-    assert!(offset % 16 == 0);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enc_writer::{encrypt, random_bytes};
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        random_bytes(&mut data).unwrap();
+        data
+    }
+
+    fn block_reader(ciphertext: &[u8], readahead: usize) -> BlockReader<std::io::Cursor<&[u8]>> {
+        let mut reader = BlockReader::new(
+            std::io::Cursor::new(ciphertext),
+            KEY,
+            ciphertext.len() as u64,
+        );
+        reader.readahead = readahead;
+        reader
+    }
+
+    #[test]
+    fn aligned_reads_bypass_window() {
+        let data = plaintext(1000);
+        let ciphertext = encrypt(&KEY, &data);
+        let mut reader = block_reader(&ciphertext, 64);
+
+        for (offset, len) in [(0, 64), (16, 64), (64, 128), (944, 64), (0, 1008)] {
+            let mut out = vec![0; len];
+            reader.read_at(&mut out, offset as u64).unwrap();
+            assert!(reader.window.is_empty());
+            let end = std::cmp::min(offset + len, data.len());
+            assert_eq!(out[..end - offset], data[offset..end]);
+        }
+    }
 
-    let buf = unsafe { std::slice::from_raw_parts_mut(p_out as *mut u8, len as usize) };
+    #[test]
+    fn fast_path_and_window_agree() {
+        let data = plaintext(4096);
+        let ciphertext = encrypt(&KEY, &data);
+        let mut reader = block_reader(&ciphertext, 256);
 
-    file.read_exact_at(buf, offset as u64).unwrap();
+        // Small reads fill the window, aligned large ones skip it, and the
+        // window stays usable in between
+        for (offset, len) in [(5, 10), (512, 256), (20, 100), (1024, 1024), (300, 1)] {
+            let mut out = vec![0; len];
+            reader.read_at(&mut out, offset as u64).unwrap();
+            assert_eq!(out, data[offset..offset + len]);
+        }
+    }
 
-    if cbc_cache.get_offset() != offset {
-        let mut iv = [0; 16];
+    // xorshift64, for reproducible offsets and lengths
+    struct Rng(u64);
 
-        if offset != 0 {
-            file.read_exact_at(&mut iv, offset as u64 - 16).unwrap();
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
         }
-        cbc_cache.recreate(&iv, offset);
     }
 
-    cbc_cache.cbc.decrypt_blocks_mut(unsafe {
-        std::slice::from_raw_parts_mut(buf.as_mut_ptr() as _, len as usize / 16)
-    });
+    fn reader(ciphertext: &[u8]) -> DecryptingReader<std::io::Cursor<&[u8]>> {
+        DecryptingReader::new(std::io::Cursor::new(ciphertext), KEY).unwrap()
+    }
+
+    #[test]
+    fn reads_match_whole_decrypt() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..50 {
+            let data = plaintext(rng.below(5000) as usize);
+            let ciphertext = encrypt(&KEY, &data);
+            let readahead = rng.below(600) as usize;
+            let mut reader = reader(&ciphertext).readahead(readahead);
+            assert_eq!(reader.padded_len(), Some(data.len() as u64));
+            reader.set_len(data.len() as u64);
 
-    cbc_cache.set_offset(offset + len);
+            for _ in 0..20 {
+                let offset = rng.below(data.len() as u64 + 40);
+                let len = rng.below(700) as usize;
+                let start = std::cmp::min(offset as usize, data.len());
+                let end = std::cmp::min(start + len, data.len());
+                let expected = &data[start..end];
 
-    if rem_len > 0 {
-        let mut out = [0u8; 16];
-        read_encrypted(file, cbc_cache, &mut out as *mut _ as _, 16, offset + len);
-        unsafe {
-            std::ptr::copy(
-                out.as_ptr(),
-                p_out.add(len as usize) as *mut _,
-                rem_len as usize,
-            );
+                let mut out = vec![0; len];
+                let n = DecryptingReader::read_at(&reader, &mut out, offset).unwrap();
+                assert_eq!(out[..n], *expected);
+
+                let mut out = vec![0; len];
+                reader.seek(SeekFrom::Start(offset)).unwrap();
+                let n = reader.read(&mut out).unwrap();
+                assert_eq!(out[..n], *expected);
+                assert_eq!(reader.stream_position().unwrap(), offset + n as u64);
+
+                let mut out = vec![0; len];
+                let result = reader.read_exact_at(&mut out, offset);
+                if expected.len() == len {
+                    result.unwrap();
+                    assert_eq!(out, expected);
+                } else {
+                    assert_eq!(
+                        result.unwrap_err().kind(),
+                        std::io::ErrorKind::UnexpectedEof
+                    );
+                }
+            }
+
+            let mut out = Vec::new();
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            reader.read_to_end(&mut out).unwrap();
+            assert_eq!(out, data);
         }
     }
+
+    #[test]
+    fn zero_padding_byte_is_invalid() {
+        let mut data = plaintext(16);
+        data[15] = 0;
+        // Without padding, the first block is all there is
+        let ciphertext = &encrypt(&KEY, &data)[..16];
+        let reader = reader(ciphertext);
+        assert_eq!(reader.padded_len(), None);
+        assert_eq!(reader.len(), 16);
+
+        let mut out = [1; 16];
+        reader.read_exact_at(&mut out, 0).unwrap();
+        assert_eq!(out[..], data[..]);
+    }
+
+    #[test]
+    fn empty_blob() {
+        let mut reader = reader(&[]);
+        assert_eq!(reader.ciphertext_len(), 0);
+        assert_eq!(reader.padded_len(), Some(0));
+        assert_eq!(reader.len(), 0);
+
+        let mut out = [0; 16];
+        assert_eq!(DecryptingReader::read_at(&reader, &mut out, 0).unwrap(), 0);
+        assert_eq!(reader.read(&mut out).unwrap(), 0);
+        assert!(reader.read_exact_at(&mut out, 0).is_err());
+    }
+
+    #[test]
+    fn set_len_limits_reads() {
+        let data = plaintext(100);
+        let ciphertext = encrypt(&KEY, &data);
+        let mut reader = reader(&ciphertext);
+        assert_eq!(reader.len(), 112);
+
+        reader.set_len(40);
+        assert_eq!(reader.len(), 40);
+        let mut out = [0; 64];
+        assert_eq!(
+            DecryptingReader::read_at(&reader, &mut out, 30).unwrap(),
+            10
+        );
+        assert_eq!(out[..10], data[30..40]);
+        assert_eq!(DecryptingReader::read_at(&reader, &mut out, 40).unwrap(), 0);
+        assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), 36);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data[36..40]);
+
+        // Never past the ciphertext
+        reader.set_len(1000);
+        assert_eq!(reader.len(), 112);
+    }
 }
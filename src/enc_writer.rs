@@ -0,0 +1,36 @@
+use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+
+// Encrypts plaintext the way backups store files: AES-256-CBC with a zero IV
+// and PKCS#5 padding, so the ciphertext is always 1 to 16 bytes longer
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+    let mut out = vec![0; (plaintext.len() / 16 + 1) * 16];
+    Aes256CbcEnc::new_from_slices(key, &[0; 16])
+        .unwrap()
+        .encrypt_padded_b2b_mut::<Pkcs7>(plaintext, &mut out)
+        .unwrap();
+    out
+}
+
+pub fn random_bytes(out: &mut [u8]) -> std::io::Result<()> {
+    std::io::Read::read_exact(&mut std::fs::File::open("/dev/urandom")?, out)
+}
+
+// Fresh key for a file written by the mount
+pub fn random_key() -> std::io::Result<[u8; 32]> {
+    let mut key = [0; 32];
+    random_bytes(&mut key)?;
+    Ok(key)
+}
+
+// Replaces the file at path without leaving it partially written
+pub fn write_atomic(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::File::open(&tmp)?.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    crate::cache::global().invalidate(path);
+    Ok(())
+}
@@ -0,0 +1,313 @@
+use std::io::Write;
+
+use crate::{
+    backup::Backup,
+    manifestdb::MBFile,
+    util::{csv_line, format_time, hex},
+};
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Format {
+    Csv,
+    JsonLines,
+    Sqlite,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::JsonLines),
+            "sqlite" => Ok(Format::Sqlite),
+            _ => Err(format!("Unknown inventory format: {}", s)),
+        }
+    }
+}
+
+// Metadata of one record of Manifest.db, times in RFC 3339 and mode in octal
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Entry {
+    pub domain: String,
+    #[serde(rename = "relativePath")]
+    pub path: String,
+    #[serde(rename = "fileID")]
+    pub id: String,
+    pub flags: i64,
+    pub size: u64,
+    pub mode: String,
+    pub uid: i64,
+    pub gid: i64,
+    pub birth: String,
+    pub mtime: String,
+    pub ctime: String,
+    #[serde(rename = "protectionClass")]
+    pub protection_class: u64,
+    pub digest: String,
+    pub xattrs: Vec<String>,
+    pub target: Option<String>,
+}
+
+const COLUMNS: [&str; 15] = [
+    "domain",
+    "relativePath",
+    "fileID",
+    "flags",
+    "size",
+    "mode",
+    "uid",
+    "gid",
+    "birth",
+    "mtime",
+    "ctime",
+    "protectionClass",
+    "digest",
+    "xattrs",
+    "target",
+];
+
+impl Entry {
+    fn new(id: String, domain: String, path: String, flags: i64, file: &MBFile) -> Self {
+        let xattrs = match file.extended_attributes.as_ref().map(|x| &x.0) {
+            Some(plist::Value::Dictionary(d)) => d.keys().cloned().collect(),
+            _ => Vec::new(),
+        };
+        Entry {
+            domain,
+            path,
+            id,
+            flags,
+            size: file.size,
+            mode: format!("{:o}", file.mode),
+            uid: file.user_i_d,
+            gid: file.group_i_d,
+            birth: format_time(file.birth),
+            mtime: format_time(file.last_modified),
+            ctime: format_time(file.last_status_change),
+            protection_class: file.protection_class,
+            digest: file
+                .digest
+                .as_ref()
+                .map(|d| hex(d.as_ref()))
+                .unwrap_or_default(),
+            xattrs,
+            target: file.target.clone(),
+        }
+    }
+
+    // Values in the order of COLUMNS, xattr names separated by commas
+    fn columns(&self) -> [String; 15] {
+        [
+            self.domain.clone(),
+            self.path.clone(),
+            self.id.clone(),
+            self.flags.to_string(),
+            self.size.to_string(),
+            self.mode.clone(),
+            self.uid.to_string(),
+            self.gid.to_string(),
+            self.birth.clone(),
+            self.mtime.clone(),
+            self.ctime.clone(),
+            self.protection_class.to_string(),
+            self.digest.clone(),
+            self.xattrs.join(","),
+            self.target.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+// Every record of Manifest.db, in domain and relativePath order
+pub(crate) fn inventory(backup: &Backup) -> Vec<Entry> {
+    backup
+        .records()
+        .into_iter()
+        .map(|(id, domain, path, flags, file)| Entry::new(id, domain, path, flags, &file))
+        .collect()
+}
+
+// Writes the entries to output, or stdout if it is "-" (not for SQLite)
+pub(crate) fn write(entries: &[Entry], format: Format, output: &str) -> Result<(), String> {
+    if let Format::Sqlite = format {
+        if std::path::Path::new(output).exists() {
+            return Err(format!("Output already exists: {}", output));
+        }
+        return write_sqlite(entries, output).map_err(|e| e.to_string());
+    }
+
+    let out: Box<dyn Write> = match output {
+        "-" => Box::new(std::io::stdout().lock()),
+        path => Box::new(std::fs::File::create(path).map_err(|e| e.to_string())?),
+    };
+    let mut out = std::io::BufWriter::new(out);
+    match format {
+        Format::Csv => {
+            writeln!(out, "{}", csv_line(&COLUMNS)).map_err(|e| e.to_string())?;
+            for entry in entries {
+                writeln!(out, "{}", csv_line(&entry.columns())).map_err(|e| e.to_string())?;
+            }
+        }
+        Format::JsonLines => {
+            for entry in entries {
+                serde_json::to_writer(&mut out, entry).map_err(|e| e.to_string())?;
+                writeln!(out).map_err(|e| e.to_string())?;
+            }
+        }
+        Format::Sqlite => unreachable!(),
+    }
+    out.flush().map_err(|e| e.to_string())
+}
+
+// Plaintext database with an inventory table holding the columns of the CSV
+fn write_sqlite(entries: &[Entry], output: &str) -> rusqlite::Result<()> {
+    let mut con = rusqlite::Connection::open(output)?;
+    let tx = con.transaction()?;
+    tx.execute_batch(
+        "CREATE TABLE inventory (domain TEXT, relativePath TEXT, fileID TEXT PRIMARY KEY, \
+         flags INTEGER, size INTEGER, mode TEXT, uid INTEGER, gid INTEGER, birth TEXT, \
+         mtime TEXT, ctime TEXT, protectionClass INTEGER, digest TEXT, xattrs TEXT, target TEXT)",
+    )?;
+    {
+        let mut sta = tx.prepare(
+            "INSERT INTO inventory VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        for e in entries {
+            sta.execute(rusqlite::params![
+                e.domain,
+                e.path,
+                e.id,
+                e.flags,
+                e.size,
+                e.mode,
+                e.uid,
+                e.gid,
+                e.birth,
+                e.mtime,
+                e.ctime,
+                e.protection_class,
+                e.digest,
+                e.xattrs.join(","),
+                e.target,
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup::tests::TempDir, manifestdb::NestedPlist};
+
+    fn entries() -> Vec<Entry> {
+        let mut file = MBFile::new("Library/a,b.txt", 0o100644, 1_600_000_000);
+        file.size = 3;
+        file.protection_class = 3;
+        file.digest = Some(vec![0xab, 0x01].into());
+        let mut xattrs = plist::Dictionary::new();
+        for name in ["com.apple.a", "com.apple.b"] {
+            xattrs.insert(name.to_owned(), plist::Value::Data(b"x".to_vec()));
+        }
+        file.extended_attributes = Some(NestedPlist(xattrs.into()));
+
+        let mut link = MBFile::new("Library/link", 0o120755, 0);
+        link.target = Some("a,b.txt".to_owned());
+
+        vec![
+            Entry::new(
+                "aa".to_owned(),
+                "HomeDomain".to_owned(),
+                "Library/a,b.txt".to_owned(),
+                1,
+                &file,
+            ),
+            Entry::new(
+                "bb".to_owned(),
+                "HomeDomain".to_owned(),
+                "Library/link".to_owned(),
+                4,
+                &link,
+            ),
+        ]
+    }
+
+    #[test]
+    fn entries_are_written_in_every_format() {
+        let dir = TempDir::new("inventory");
+        let output = |name: &str| dir.0.join(name).to_str().unwrap().to_owned();
+        let entries = entries();
+
+        write(&entries, Format::Csv, &output("inventory.csv")).unwrap();
+        let csv = std::fs::read_to_string(output("inventory.csv")).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                "domain,relativePath,fileID,flags,size,mode,uid,gid,birth,mtime,ctime,protectionClass,digest,xattrs,target",
+                "HomeDomain,\"Library/a,b.txt\",aa,1,3,100644,501,501,2020-09-13T12:26:40+00:00,2020-09-13T12:26:40+00:00,2020-09-13T12:26:40+00:00,3,ab01,\"com.apple.a,com.apple.b\",",
+                "HomeDomain,Library/link,bb,4,0,120755,501,501,1970-01-01T00:00:00+00:00,1970-01-01T00:00:00+00:00,1970-01-01T00:00:00+00:00,0,,,\"a,b.txt\"",
+            ]
+        );
+
+        write(&entries, Format::JsonLines, &output("inventory.jsonl")).unwrap();
+        let jsonl = std::fs::read_to_string(output("inventory.jsonl")).unwrap();
+        let lines = jsonl
+            .lines()
+            .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["relativePath"], "Library/a,b.txt");
+        assert_eq!(lines[0]["fileID"], "aa");
+        assert_eq!(lines[0]["protectionClass"], 3);
+        assert_eq!(
+            lines[0]["xattrs"],
+            serde_json::json!(["com.apple.a", "com.apple.b"])
+        );
+        assert_eq!(lines[0]["target"], serde_json::Value::Null);
+        assert_eq!(lines[1]["mode"], "120755");
+        assert_eq!(lines[1]["target"], "a,b.txt");
+
+        write(&entries, Format::Sqlite, &output("inventory.sqlite")).unwrap();
+        let con = rusqlite::Connection::open(output("inventory.sqlite")).unwrap();
+        let rows = con
+            .prepare(
+                "SELECT fileID, flags, size, digest, xattrs, target FROM inventory ORDER BY fileID",
+            )
+            .unwrap()
+            .query_map((), |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, i64>(1)?,
+                    r.get::<_, i64>(2)?,
+                    r.get::<_, String>(3)?,
+                    r.get::<_, String>(4)?,
+                    r.get::<_, Option<String>>(5)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                (
+                    "aa".to_owned(),
+                    1,
+                    3,
+                    "ab01".to_owned(),
+                    "com.apple.a,com.apple.b".to_owned(),
+                    None
+                ),
+                (
+                    "bb".to_owned(),
+                    4,
+                    0,
+                    String::new(),
+                    String::new(),
+                    Some("a,b.txt".to_owned())
+                ),
+            ]
+        );
+        assert!(write(&entries, Format::Sqlite, &output("inventory.sqlite")).is_err());
+    }
+}
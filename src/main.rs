@@ -1,95 +1,636 @@
+const USAGE: &str = "\
+usage: iphonebackupfs [backup_location] [mount_path] [password] ([backup_location] [password])...
+           [--union] [--snapshot=refuse|consistent] [--read-write] [--stable-inodes]
+           [--plist-views[=named|all]] [--sqlite-views[=named|all]] [--sqlite-pre-wal]
+           [--cache-size=<MiB>] [--size-mismatch=trust-padding|trust-manifest|fail]
+           [--check-blobs] [--hide-missing] [--missing-report=<file>]
+       iphonebackupfs diff [old_backup] [old_password] [new_backup] [new_password] [--json] [--content]
+       iphonebackupfs inventory [backup] [password] [output] [--format=csv|jsonl|sqlite]
+       iphonebackupfs timeline [backup] [password] [--format=bodyfile|csv] [--from=<time>] [--to=<time>]
+           [--domain=<domain>[,<domain>...]]
+       iphonebackupfs carve-sqlite [backup] [password] [domain] [relativePath] [--format=jsonl|csv]
+       iphonebackupfs messages [backup] [password] [output] [--format=html|json|text]
+       iphonebackupfs contacts [backup] [password] [output] [--format=vcard|csv]
+       iphonebackupfs calls [backup] [password] [output] [--format=csv|jsonl]
+       iphonebackupfs photos [backup] [password] [output]
+       iphonebackupfs pack [source_tree] [output_backup] [password] [--base=<backup> --base-password=<password>]
+           [--iterations=<n>] [--protection-class=<n>]
+       iphonebackupfs nska-dump [archive] [--json]";
+
 fn main() {
-    let base_path = std::path::PathBuf::from(std::env::args().nth(1).unwrap());
-    let mountpoint = std::path::PathBuf::from(std::env::args().nth(2).unwrap());
-    let password = std::env::args().nth(3).unwrap();
+    let args = Args::parse();
+
+    let cache_size: usize = args.parsed("cache-size").unwrap_or(64);
+    cache::global().set_budget(cache_size * 1024 * 1024);
+
+    match args.positional.first().map(String::as_str) {
+        Some("diff") => diff(&args),
+        Some("pack") => pack(&args),
+        Some("nska-dump") => nska_dump(&args),
+        Some("inventory") => inventory(&args),
+        Some("timeline") => timeline(&args),
+        Some("carve-sqlite") => carve_sqlite(&args),
+        Some("messages") => messages(&args),
+        Some("contacts") => contacts(&args),
+        Some("calls") => calls(&args),
+        Some("photos") => photos(&args),
+        _ => mount(&args),
+    }
+}
+
+fn open_backup(args: &Args, base_path: &str, password: &str) -> backup::Backup {
+    let snapshot_policy = args
+        .parsed("snapshot")
+        .unwrap_or(backup::SnapshotPolicy::Refuse);
+
+    let backup =
+        backup::Backup::open(base_path.into(), password, snapshot_policy).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        });
+    if let Some(status) = &backup.status {
+        eprintln!("** STATUS {}", status.describe());
+    }
+    backup
+}
+
+// diff [old_backup] [old_password] [new_backup] [new_password] [--json] [--content]
+fn diff(args: &Args) {
+    let positional = args.arguments(5);
+    let old = open_backup(args, &positional[1], &positional[2]);
+    let new = open_backup(args, &positional[3], &positional[4]);
+
+    let changes = diff::diff(&old, &new, args.flag("content"));
+
+    if args.flag("json") {
+        diff::print_json(&changes);
+    } else {
+        diff::print_text(&changes);
+    }
+}
+
+// pack [source_tree] [output_backup] [password] [--base=<backup> --base-password=<password>]
+//      [--iterations=<n>] [--protection-class=<n>]
+fn pack(args: &Args) {
+    let positional = args.arguments(4);
+    let iterations = args.parsed("iterations").unwrap_or(10_000_000);
+    let protection_class = args.parsed("protection-class").unwrap_or(3);
+    let base = args
+        .value("base")
+        .map(|path| open_backup(args, path, args.value("base-password").unwrap_or_default()));
+
+    let options = pack::Options {
+        base,
+        iterations,
+        protection_class,
+    };
+
+    pack::pack(
+        positional[1].as_ref(),
+        positional[2].as_ref(),
+        &positional[3],
+        options,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    })
+}
+
+// inventory [backup] [password] [output] [--format=csv|jsonl|sqlite]
+fn inventory(args: &Args) {
+    let positional = args.arguments(4);
+    let format = args.parsed("format").unwrap_or(inventory::Format::Csv);
+    let backup = open_backup(args, &positional[1], &positional[2]);
+
+    let entries = inventory::inventory(&backup);
+    inventory::write(&entries, format, &positional[3]).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    })
+}
+
+// timeline [backup] [password] [--format=bodyfile|csv] [--from=<time>] [--to=<time>]
+//          [--domain=<domain>[,<domain>...]]
+fn timeline(args: &Args) {
+    let positional = args.arguments(3);
+    let format = args.parsed("format").unwrap_or(timeline::Format::Bodyfile);
+    let time = |name| args.parsed_with(name, timeline::parse_time);
+
+    let filter = timeline::Filter {
+        from: time("from"),
+        to: time("to"),
+        domains: args
+            .value("domain")
+            .map(|x| x.split(',').map(str::to_owned).collect())
+            .unwrap_or_default(),
+    };
+
+    let backup = open_backup(args, &positional[1], &positional[2]);
+    timeline::timeline(&backup, format, &filter);
+}
+
+// carve-sqlite [backup] [password] [domain] [relativePath] [--format=jsonl|csv]
+fn carve_sqlite(args: &Args) {
+    let positional = args.arguments(5);
+    let format = args.parsed("format").unwrap_or(carve::Format::JsonLines);
+    let backup = open_backup(args, &positional[1], &positional[2]);
+
+    let id = backup::file_id(&positional[3], &positional[4]);
+    carve::carve(&backup, &id)
+        .and_then(|rows| carve::write(&rows, format).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        })
+}
+
+// messages [backup] [password] [output] [--format=html|json|text]
+fn messages(args: &Args) {
+    let positional = args.arguments(4);
+    let format = args.parsed("format").unwrap_or(messages::Format::Html);
+    let backup = open_backup(args, &positional[1], &positional[2]);
+
+    messages::conversations(&backup)
+        .and_then(|mut conversations| {
+            messages::export(&backup, &mut conversations, format, positional[3].as_ref())
+                .map_err(|e| e.to_string())
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        })
+}
+
+// contacts [backup] [password] [output] [--format=vcard|csv]
+fn contacts(args: &Args) {
+    let positional = args.arguments(4);
+    let format = args.parsed("format").unwrap_or(contacts::Format::VCard);
+    let backup = open_backup(args, &positional[1], &positional[2]);
+
+    contacts::contacts(&backup)
+        .and_then(|contacts| contacts::write(&contacts, format, &positional[3]))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        })
+}
+
+// calls [backup] [password] [output] [--format=csv|jsonl]
+fn calls(args: &Args) {
+    let positional = args.arguments(4);
+    let format = args.parsed("format").unwrap_or(calls::Format::Csv);
+    let backup = open_backup(args, &positional[1], &positional[2]);
 
-    println!("** READING Manifest.plist");
+    // A device without a phone has no voicemail, and the other way round
+    // while the call history hasn't been created
+    let calls = calls::calls(&backup)
+        .inspect_err(|e| eprintln!("warning: unreadable_call_history error={}", e))
+        .ok();
+    let mut voicemails = calls::voicemails(&backup)
+        .inspect_err(|e| eprintln!("warning: unreadable_voicemail error={}", e))
+        .ok();
+    if calls.is_none() && voicemails.is_none() {
+        eprintln!("No call history or voicemail in the backup");
+        std::process::exit(1)
+    }
 
-    let manifest: manifest::Manifest = manifest::read_manifest(&base_path);
+    calls::export(
+        &backup,
+        calls.as_deref(),
+        voicemails.as_deref_mut(),
+        format,
+        positional[3].as_ref(),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    })
+}
 
-    println!("** VERIFYING PASSPHRASE");
+// photos [backup] [password] [output]
+fn photos(args: &Args) {
+    let positional = args.arguments(4);
+    let backup = open_backup(args, &positional[1], &positional[2]);
 
-    let keys = verify_passphrase(manifest.backup_key_bag, password.as_bytes());
+    photos::assets(&backup)
+        .and_then(|mut assets| {
+            photos::export(&backup, &mut assets, positional[3].as_ref()).map_err(|e| e.to_string())
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        })
+}
 
-    let mut manifestdb_key = [0u8; 32];
-    keys[&u32::from_le_bytes(manifest.manifest_key.as_ref()[0..4].try_into().unwrap())]
-        .unwrap(&manifest.manifest_key.as_ref()[4..], &mut manifestdb_key)
-        .unwrap();
+// nska-dump [archive] [--json]
+fn nska_dump(args: &Args) {
+    let positional = args.arguments(2);
+    let value = std::fs::read(&positional[1])
+        .map_err(|e| e.to_string())
+        .and_then(|data| nska::decode_bytes(&data).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        });
 
-    vfs::register(manifestdb_key);
+    let stdout = std::io::stdout().lock();
+    if args.flag("json") {
+        serde_json::to_writer_pretty(stdout, &nska::to_json(&value)).unwrap();
+        println!();
+    } else {
+        value.to_writer_xml(stdout).unwrap();
+        println!();
+    }
+}
+
+// [backup_location] [mount_path] [password] ([backup_location] [password])... [--union] [--read-write]
+fn mount(args: &Args) {
+    // The first backup is followed by the mount path, further ones come in
+    // pairs with their password
+    let positional = &args.positional;
+    if positional.len() < 3 || positional.len().is_multiple_of(2) {
+        usage();
+    }
+    let mountpoint = std::path::PathBuf::from(&positional[1]);
+    let mut locations = vec![(&positional[0], &positional[2])];
+    for x in positional[3..].chunks(2) {
+        locations.push((&x[0], &x[1]));
+    }
+
+    let read_write = args.flag("read-write");
+    if read_write && locations.len() > 1 {
+        eprintln!("--read-write is only supported with a single backup");
+        std::process::exit(1);
+    }
+
+    let plist_selection = args
+        .parsed("plist-views")
+        .unwrap_or(manifestdb::Selection::Named);
+    let sqlite_selection = args
+        .parsed("sqlite-views")
+        .unwrap_or(manifestdb::Selection::Named);
+    let size_policy = args
+        .parsed("size-mismatch")
+        .unwrap_or(backupfuse::SizePolicy::TrustManifest);
+
+    let mut backups = Vec::new();
+    for (base_path, password) in locations {
+        backups.push(open_backup(args, base_path, password));
+    }
 
     println!("** READING Manifest.db");
 
     let mut fs = manifestdb::FS::new();
 
-    let con = rusqlite::Connection::open(base_path.join("Manifest.db")).unwrap();
-    let mut sta = con
-        .prepare("SELECT * FROM Files ORDER BY domain, relativePath")
-        .unwrap();
-    let mut rows = sta.query(()).unwrap();
-
-    while let Some(row) = rows.next().unwrap() {
-        let domain = row.get_ref(1).unwrap().as_str().unwrap();
-        let path = row.get_ref(2).unwrap().as_str().unwrap();
-        let id = row.get_ref(0).unwrap().as_str().unwrap();
-        let ftype = row.get_ref(3).unwrap().as_i64().unwrap();
-        let data = row.get_ref(4).unwrap().as_blob().unwrap();
-
-        let _x = plist::from_bytes::<
-            manifestdb::NSKeyedArchive<manifestdb::NSKeyed<manifestdb::MBFile>>,
-        >(data)
-        .map_err(|e| {
-            dbg!(plist::from_bytes::<plist::Value>(data)).unwrap();
-            e
-        })
-        .unwrap();
+    if backups.len() == 1 {
+        fs.insert_backup(1, 0, &backups[0].con, false);
+    } else if args.flag("union") {
+        // Newest first, older backups only fill in files missing from newer ones
+        backups.sort_by_key(|b| std::cmp::Reverse(b.manifest.date));
 
-        if ftype != 4 {
-            fs.insert_file(domain, path, id, ftype.into());
+        for (i, backup) in backups.iter().enumerate() {
+            fs.insert_backup(1, i, &backup.con, true);
+        }
+    } else {
+        // Side by side: one folder per backup plus a diff of each consecutive pair
+        backups.sort_by_key(|b| b.manifest.date);
+
+        let labels = backup::Backup::labels(&backups);
+        for (i, backup) in backups.iter().enumerate() {
+            let root = fs.mkdir(1, &labels[i]);
+            fs.insert_backup(root, i, &backup.con, false);
+        }
+
+        let diff_root = fs.mkdir(1, "diff");
+        for i in 1..backups.len() {
+            let (old, new) = (&backups[i - 1], &backups[i]);
+            let (old_label, new_label) = (&labels[i - 1], &labels[i]);
+            println!("** COMPARING {} {}", old_label, new_label);
+            let root = fs.mkdir(diff_root, &format!("{}..{}", old_label, new_label));
+            for change in diff::diff(old, new, false) {
+                if change.flags == 4 {
+                    continue;
+                }
+                let kind_root = fs.mkdir(root, change.kind.as_str());
+                let backup = match change.kind {
+                    diff::ChangeKind::Removed => i - 1,
+                    diff::ChangeKind::Added | diff::ChangeKind::Modified => i,
+                };
+                fs.insert_file(
+                    kind_root,
+                    backup,
+                    &change.domain,
+                    &change.path,
+                    &change.id,
+                    manifestdb::FileType::from(change.flags),
+                );
+            }
         }
     }
 
+    let missing_report = args.value("missing-report");
+    if args.flag("check-blobs") || args.flag("hide-missing") || missing_report.is_some() {
+        println!("** CHECKING BLOBS");
+
+        let missing = check_blobs(&mut fs, &backups);
+        println!("** {} missing blobs", missing.len());
+
+        if let Some(path) = missing_report {
+            std::fs::write(path, missing_report_lines(&missing)).unwrap();
+        }
+
+        if args.flag("hide-missing") {
+            fs.remove_missing();
+        }
+    }
+
+    if args.flag("plist-views") {
+        println!("** ADDING PLIST VIEWS");
+
+        add_plist_views(&mut fs, &backups, plist_selection);
+    }
+
+    if args.flag("sqlite-views") {
+        println!("** ADDING SQLITE VIEWS");
+
+        let snapshots: &[sqliteview::Snapshot] = match args.flag("sqlite-pre-wal") {
+            false => &[sqliteview::Snapshot::Latest],
+            true => &[
+                sqliteview::Snapshot::Latest,
+                sqliteview::Snapshot::BeforeWal,
+            ],
+        };
+        add_sqlite_views(&mut fs, &backups, sqlite_selection, snapshots);
+    }
+
     println!("** Removing Empty Directories");
 
     fs.remove_empty_directories();
 
-    let filesystem = backupfuse::BackupFS::new(fs, &con, keys, base_path);
+    if read_write {
+        println!("** OPENING Manifest.db FOR WRITING");
+        backups[0].make_writable().unwrap();
+    }
+
+    let options = backupfuse::Options {
+        verify_digests: true,
+        size_policy,
+        read_write,
+        stable_inodes: args.flag("stable-inodes"),
+    };
+
+    let filesystem = backupfuse::BackupFS::new(fs, backups, options);
 
     println!("** Serving Filesystem");
 
     fuser::mount2(filesystem, mountpoint, &[fuser::MountOption::AllowOther]).unwrap()
 }
 
-mod manifestdb;
+// Marks files whose blob is absent, returning their fileID and path. Files
+// made up by the mount have no blob to check.
+fn check_blobs(fs: &mut manifestdb::FS, backups: &[backup::Backup]) -> Vec<(String, String)> {
+    let mut missing = Vec::new();
+    for (path, inode_nr) in fs.files() {
+        let inode = &mut fs.backing[inode_nr];
+        let Some(backup) = inode.backup else {
+            continue;
+        };
+        let id = inode.id.as_stringid();
+        if !backups[backup].blob_path(id.as_str()).is_file() {
+            inode.missing = true;
+            missing.push((id.as_str().to_owned(), path));
+        }
+    }
+    missing
+}
 
-mod backupfuse;
+// One line per missing blob: fileID, tab, path in the mount
+fn missing_report_lines(missing: &[(String, String)]) -> String {
+    missing
+        .iter()
+        .map(|(id, path)| format!("{}\t{}\n", id, path))
+        .collect()
+}
 
-fn verify_passphrase(
-    bkb: manifest::KeyBag,
-    password: &[u8],
-) -> std::collections::BTreeMap<u32, aes_kw::KekAes256> {
-    let mut res = std::collections::BTreeMap::new();
-
-    let mut round1 = [0u8; 32];
-    let mut key = [0u8; 32];
-    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password, &bkb.dpsl, bkb.dpic, &mut round1);
-    pbkdf2::pbkdf2_hmac::<sha1::Sha1>(&round1, &bkb.salt, bkb.iter, &mut key);
-
-    let kek = aes_kw::Kek::from(key);
-    for x in &bkb.others {
-        if x.wrap & 0x2 == 0x2 {
-            let mut ukey = [0u8; 32];
-            if let Err(x) = kek.unwrap(&x.wpky, &mut ukey) {
-                if let aes_kw::Error::IntegrityCheckFailed = x {
-                    panic!("Incorrect Passphrase")
-                }
-                panic!("Unknown AES_KW ERROR {}", x);
+// Adds converted views of plists, found by name or also by their first bytes
+fn add_plist_views(
+    fs: &mut manifestdb::FS,
+    backups: &[backup::Backup],
+    selection: manifestdb::Selection,
+) {
+    fs.add_plist_views(|name, inode| {
+        if plistview::is_plist_name(name) {
+            return true;
+        }
+        let (manifestdb::Selection::All, Some(backup), false) =
+            (selection, inode.backup, inode.missing)
+        else {
+            return false;
+        };
+        let backup = &backups[backup];
+        let id = inode.id.as_stringid();
+        backup
+            .record(id.as_str())
+            .ok()
+            .and_then(|(_, _, _, mbfile)| {
+                backup.read_head(id.as_str(), &mbfile, plistview::MAGIC_LEN)
+            })
+            .is_some_and(|head| plistview::has_magic(&head))
+    });
+}
+
+// Adds exports of the tables of databases, found by name or also by their
+// first bytes. The databases are read through the VFS without extracting them.
+// Snapshots before the -wal are only added for databases with a -wal.
+fn add_sqlite_views(
+    fs: &mut manifestdb::FS,
+    backups: &[backup::Backup],
+    selection: manifestdb::Selection,
+    snapshots: &[sqliteview::Snapshot],
+) {
+    fs.add_sqlite_views(snapshots, |name, inode, snapshot| {
+        let (Some(backup), false) = (inode.backup, inode.missing) else {
+            return None;
+        };
+        let backup = &backups[backup];
+        let id = inode.id.as_stringid();
+        if snapshot == sqliteview::Snapshot::BeforeWal && !backup.has_wal(id.as_str()) {
+            return None;
+        }
+        let (_, _, _, mbfile) = backup.record(id.as_str()).ok()?;
+        if !sqliteview::is_sqlite_name(name) {
+            let manifestdb::Selection::All = selection else {
+                return None;
+            };
+            let head = backup.read_head(id.as_str(), &mbfile, util::SQLITE_MAGIC.len())?;
+            if !sqliteview::has_magic(&head) {
+                return None;
+            }
+        }
+        let tables = backup
+            .open_database(id.as_str(), snapshot)
+            .and_then(|con| sqliteview::tables(&con).map_err(|e| e.to_string()));
+        match tables {
+            Ok(tables) => Some(tables),
+            Err(e) => {
+                eprintln!(
+                    "warning: unreadable_database file_id={} error={}",
+                    id.as_str(),
+                    e
+                );
+                None
             }
-            res.insert(x.clas, aes_kw::Kek::from(ukey));
         }
+    });
+}
+
+// Positional arguments with --name or --name=value options mixed in
+struct Args {
+    positional: Vec<String>,
+    options: std::collections::BTreeMap<String, Option<String>>,
+}
+
+impl Args {
+    fn parse() -> Self {
+        let mut positional = Vec::new();
+        let mut options = std::collections::BTreeMap::new();
+        for arg in std::env::args().skip(1) {
+            match arg.strip_prefix("--") {
+                Some(opt) => match opt.split_once('=') {
+                    Some((name, value)) => options.insert(name.to_owned(), Some(value.to_owned())),
+                    None => options.insert(opt.to_owned(), None),
+                },
+                None => {
+                    positional.push(arg);
+                    continue;
+                }
+            };
+        }
+        Args {
+            positional,
+            options,
+        }
+    }
+
+    // The positional arguments, command included, exiting with the usage
+    // unless there are count of them
+    fn arguments(&self, count: usize) -> &[String] {
+        if self.positional.len() != count {
+            usage();
+        }
+        &self.positional
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.options.get(name)?.as_deref()
+    }
+
+    // Value of an option, exiting with the usage if it can't be parsed
+    fn parsed<T: std::str::FromStr>(&self, name: &str) -> Option<T>
+    where
+        T::Err: std::fmt::Display,
+    {
+        self.parsed_with(name, str::parse)
+    }
+
+    fn parsed_with<T, E: std::fmt::Display>(
+        &self,
+        name: &str,
+        parse: impl Fn(&str) -> Result<T, E>,
+    ) -> Option<T> {
+        self.value(name).map(|x| {
+            parse(x).unwrap_or_else(|e| {
+                eprintln!("--{}: {}", name, e);
+                usage()
+            })
+        })
     }
-    res
 }
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::tests::{pack_files, TempDir};
+
+    #[test]
+    fn missing_blobs_are_reported_and_hidden() {
+        let dir = TempDir::new("check-blobs");
+        let backup = pack_files(
+            &dir,
+            &[
+                ("HomeDomain", "a.txt", b"one"),
+                ("HomeDomain", "b.txt", b"two"),
+            ],
+        );
+        let id = backup::file_id("HomeDomain", "b.txt");
+        std::fs::remove_file(backup.blob_path(&id)).unwrap();
+
+        let mut fs = manifestdb::FS::new();
+        fs.insert_backup(1, 0, &backup.con, false);
+        // A file made up by the mount, with no backup behind it
+        let folder = fs.mkdir(1, "views");
+        fs.backing.push(manifestdb::Inode {
+            id: manifestdb::RawId::parse(&id),
+            backup: None,
+            ftype: manifestdb::FileType::File,
+            children: None,
+            missing: false,
+            view: None,
+        });
+        let made_up = fs.backing.len() - 1;
+        fs.backing[folder]
+            .children
+            .as_mut()
+            .unwrap()
+            .insert("c.txt".to_owned(), made_up);
+
+        let missing = check_blobs(&mut fs, &[backup]);
+        assert_eq!(missing, [(id.clone(), "/HomeDomain/b.txt".to_owned())]);
+        assert_eq!(
+            missing_report_lines(&missing),
+            format!("{}\t/HomeDomain/b.txt\n", id)
+        );
+        assert!(!fs.backing[made_up].missing);
+
+        fs.remove_missing();
+        let files = fs.files().into_iter().map(|(path, _)| path);
+        assert_eq!(
+            files.collect::<Vec<_>>(),
+            ["/HomeDomain/a.txt", "/views/c.txt"]
+        );
+    }
+}
+
+mod manifestdb;
+
+mod backupfuse;
+
+mod backup;
+mod cache;
+mod calls;
+mod carve;
+mod contacts;
+mod diff;
 mod enc_reader;
+mod enc_writer;
+mod inventory;
 mod manifest;
+mod messages;
+mod nska;
+mod pack;
+mod photos;
+mod plistview;
+mod sqliteview;
+mod timeline;
+mod util;
 mod vfs;
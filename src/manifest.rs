@@ -51,8 +51,40 @@ pub struct KeyBagClass {
     pub wpky: Vec<u8>,
 }
 
-pub(crate) fn read_manifest(path: &std::path::Path) -> Manifest {
-    plist::from_file(path.join("Manifest.plist")).unwrap()
+impl KeyBag {
+    // TLV encoding read by read_backup_key_bag
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn put(out: &mut Vec<u8>, tag: &[u8; 4], value: &[u8]) {
+            out.extend_from_slice(tag);
+            out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            out.extend_from_slice(value);
+        }
+
+        let mut out = Vec::new();
+        put(&mut out, b"VERS", &self.vers.to_be_bytes());
+        put(&mut out, b"TYPE", &self.ktype.to_be_bytes());
+        put(&mut out, b"UUID", &self.uuid);
+        put(&mut out, b"HMCK", &self.hmck);
+        put(&mut out, b"WRAP", &self.wrap.to_be_bytes());
+        put(&mut out, b"SALT", &self.salt);
+        put(&mut out, b"ITER", &self.iter.to_be_bytes());
+        put(&mut out, b"DPWT", &self.dpwt.to_be_bytes());
+        put(&mut out, b"DPIC", &self.dpic.to_be_bytes());
+        put(&mut out, b"DPSL", &self.dpsl);
+        for x in &self.others {
+            put(&mut out, b"UUID", &x.uuid);
+            put(&mut out, b"CLAS", &x.clas.to_be_bytes());
+            put(&mut out, b"WRAP", &x.wrap.to_be_bytes());
+            put(&mut out, b"KTYP", &x.ktyp.to_be_bytes());
+            put(&mut out, b"WPKY", &x.wpky);
+        }
+        out
+    }
+}
+
+pub(crate) fn read_manifest(path: &std::path::Path) -> Result<Manifest, String> {
+    let path = path.join("Manifest.plist");
+    plist::from_file(&path).map_err(|e| format!("Can't read {}: {}", path.display(), e))
 }
 
 fn read_backup_key_bag<'de, D>(de: D) -> Result<KeyBag, D::Error>
@@ -222,3 +254,46 @@ fn read_4tlv(input: &[u8]) -> nom::IResult<&[u8], (u32, &[u8])> {
 
     Ok((out2.0, (out.1 .0, out2.1)))
 }
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct Status {
+    pub backup_state: String,
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub is_full_backup: bool,
+    pub snapshot_state: String,
+    #[serde(rename = "UUID")]
+    pub uuid: String,
+    pub version: String,
+}
+
+impl Status {
+    pub fn is_finished(&self) -> bool {
+        self.snapshot_state == "finished"
+    }
+
+    pub fn describe(&self) -> String {
+        let kind = match self.is_full_backup {
+            true => "full",
+            false => "incremental",
+        };
+        format!(
+            "{} backup {} of {}, state {}, snapshot {}, version {}",
+            kind,
+            self.uuid,
+            self.date.to_rfc3339(),
+            self.backup_state,
+            self.snapshot_state,
+            self.version
+        )
+    }
+}
+
+// None for backups without a Status.plist, an error if it can't be parsed
+pub(crate) fn read_status(path: &std::path::Path) -> Result<Option<Status>, plist::Error> {
+    let path = path.join("Status.plist");
+    if !path.exists() {
+        return Ok(None);
+    }
+    plist::from_file(path).map(Some)
+}
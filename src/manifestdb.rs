@@ -1,204 +1,227 @@
-#[derive(Debug, serde::Deserialize)]
-#[serde(deny_unknown_fields)]
-struct NSKeyedArchiver {
-    #[serde(rename = "$version")]
-    version: u64,
-    #[serde(rename = "$archiver")]
-    archiver: String,
-    #[serde(rename = "$top")]
-    top: NSKeyedArchiverTop,
-    #[serde(rename = "$objects")]
-    objects: Vec<plist::Value>,
-}
-
-#[derive(Debug, serde::Deserialize)]
-#[serde(deny_unknown_fields)]
-struct NSKeyedArchiverTop {
-    root: plist::Uid,
-}
+use crate::nska;
 
-#[derive(Debug, serde::Deserialize)]
-struct NSKeyedArchiverClass {
-    #[serde(rename = "$classname")]
-    classname: String,
-    #[serde(rename = "$classes")]
-    classes: Vec<String>,
-}
-
-#[derive(Debug, serde::Deserialize)]
-#[serde(deny_unknown_fields, rename_all = "PascalCase")]
+// Decoded from the plain values nska::decode gives for an archived MBFile.
+// Unknown keys are kept in extra, and reported once per key. Fields missing
+// from records of other iOS versions are left at their default when possible.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
 pub struct MBFile {
     pub last_modified: u64,
+    #[serde(default)]
     pub flags: u64,
-    #[serde(deserialize_with = "use_nska_objects", default)]
+    #[serde(
+        serialize_with = "nska::object",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
     pub extended_attributes: Option<NestedPlist>,
+    #[serde(default)]
     pub group_i_d: i64,
-    #[allow(dead_code)]
-    #[serde(deserialize_with = "use_nska_objects", default)]
+    #[serde(
+        serialize_with = "nska::object",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
     pub target: Option<String>,
+    #[serde(default)]
     pub last_status_change: u64,
-    #[allow(dead_code)]
-    #[serde(deserialize_with = "use_nska_objects")]
+    #[serde(serialize_with = "nska::object")]
     pub relative_path: String,
+    #[serde(default)]
     pub birth: u64,
-    #[serde(deserialize_with = "use_nska_objects", default)]
-    pub encryption_key: Option<NSKeyed<NSMutableData>>,
+    #[serde(
+        serialize_with = "nska::object",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub encryption_key: Option<NSMutableData>,
+    #[serde(default)]
     pub size: u64,
-    #[serde(deserialize_with = "use_nska_objects", default)]
+    #[serde(
+        serialize_with = "nska::object",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
     pub digest: Option<plist::Data>,
-    #[allow(dead_code)]
+    #[serde(default)]
     pub inode_number: u64,
-    #[allow(dead_code)]
     pub mode: u64,
+    #[serde(default)]
     pub user_i_d: i64,
-    #[allow(dead_code)]
+    #[serde(default)]
     pub protection_class: u64,
-    #[serde(rename = "$class")]
-    _skipped: serde::de::IgnoredAny,
+    // Filled in by from_bytes, with references replaced by the objects they point to
+    #[serde(flatten, skip_deserializing, serialize_with = "serialize_extra")]
+    pub extra: std::collections::BTreeMap<String, plist::Value>,
+    #[serde(
+        rename = "$class",
+        deserialize_with = "verify_mbfile_class",
+        serialize_with = "mbfile_class"
+    )]
+    _class: serde::de::IgnoredAny,
 }
 
-impl rusqlite::types::FromSql for MBFile {
-    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
-        Ok(
-            plist::from_bytes::<NSKeyedArchive<NSKeyed<MBFile>>>(value.as_blob().unwrap())
-                .unwrap()
-                .0
-                 .0,
-        )
+// Keys read by the fields of MBFile
+const MBFILE_FIELDS: &[&str] = &[
+    "$class",
+    "LastModified",
+    "Flags",
+    "ExtendedAttributes",
+    "GroupID",
+    "Target",
+    "LastStatusChange",
+    "RelativePath",
+    "Birth",
+    "EncryptionKey",
+    "Size",
+    "Digest",
+    "InodeNumber",
+    "Mode",
+    "UserID",
+    "ProtectionClass",
+];
+
+// Unknown MBFile keys already reported
+static WARNED_FIELDS: std::sync::Mutex<std::collections::BTreeSet<String>> =
+    std::sync::Mutex::new(std::collections::BTreeSet::new());
+
+// Objects of classes nska::decode doesn't know are decoded with their class name
+fn verify_mbfile_class<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<serde::de::IgnoredAny, D::Error> {
+    let class = String::deserialize(deserializer)?;
+    if class != "MBFile" {
+        return Err(D::Error::custom(format!(
+            "Expected MBFile class got {}",
+            class
+        )));
     }
+    Ok(serde::de::IgnoredAny)
 }
 
-#[derive(serde::Deserialize, Debug)]
-pub struct NSMutableData {
-    #[serde(rename = "NS.data")]
-    pub data: plist::Data,
-    #[serde(rename = "$class")]
-    _skipped: serde::de::IgnoredAny,
+// MBFile is written as a map because of its flattened extra fields, which
+// leaves its class to be given explicitly
+fn mbfile_class<S: serde::Serializer>(
+    _: &serde::de::IgnoredAny,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    nska::class("MBFile", &["MBFile", "NSObject"], serializer)
 }
 
-impl NSClassName for NSMutableData {
-    fn verify(class: &plist::Value) -> Result<(), String> {
-        let class: NSKeyedArchiverClass = plist::from_value(class)
-            .map_err(|e| format!("Error parsing NSKeyedArchiveClassTree: {}", e))?;
-
-        if class.classname != "NSMutableData"
-            || class.classes != ["NSMutableData", "NSData", "NSObject"]
-        {
-            return Err(format!(
-                "Expected NSMutableData class got {:#?}",
-                class.classes
-            ));
-        }
+// Archived back into Foundation objects, as decoded by from_bytes
+fn serialize_extra<S: serde::Serializer>(
+    extra: &std::collections::BTreeMap<String, plist::Value>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    struct Field<'a>(&'a plist::Value);
 
-        Ok(())
+    impl serde::Serialize for Field<'_> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            nska::foundation(self.0, serializer)
+        }
     }
-}
 
-fn use_nska_objects<'de, D, T: serde::de::DeserializeOwned>(deserializer: D) -> Result<T, D::Error>
-where
-    D: serde::de::Deserializer<'de>,
-{
-    let x = plist::Uid::deserialize(deserializer)?;
-    thread_scoped_ref::with(&NSKA_OBJECTS, |objects| {
-        Ok(plist::from_value(&objects.unwrap()[x.get() as usize])
-            .map_err(|e| format!("Error parsing {}: {}", std::any::type_name::<T>(), e))
-            .map_err(D::Error::custom)?)
-    })
+    serializer.collect_map(extra.iter().map(|(k, v)| (k, Field(v))))
 }
 
-thread_scoped_ref::thread_scoped_ref!(NSKA_OBJECTS, [plist::Value]);
+impl MBFile {
+    // Record owned by mobile, without data or protection class
+    pub fn new(relative_path: &str, mode: u64, now: u64) -> Self {
+        MBFile {
+            last_modified: now,
+            flags: 0,
+            extended_attributes: None,
+            group_i_d: 501,
+            target: None,
+            last_status_change: now,
+            relative_path: relative_path.to_owned(),
+            birth: now,
+            encryption_key: None,
+            size: 0,
+            digest: None,
+            inode_number: 0,
+            mode,
+            user_i_d: 501,
+            protection_class: 0,
+            extra: Default::default(),
+            _class: serde::de::IgnoredAny,
+        }
+    }
 
-#[derive(Debug)]
-pub struct NSKeyed<T: serde::de::DeserializeOwned>(pub T);
-
-trait NSClassName {
-    fn get_and_verify_class(value: &plist::Value) -> Result<(), String> {
-        thread_scoped_ref::with(&NSKA_OBJECTS, |objects| {
-            let class_ref = value
-                .as_dictionary()
-                .ok_or_else(|| "Expected NSKeyedObject to be Dictionary")?
-                .get("$class")
-                .ok_or_else(|| "Expected NSKeyedObject.$class to exist")?
-                .as_uid()
-                .ok_or_else(|| "Expected NSKeyedObject.$class to be an Uid")?
-                .get();
-
-            let class = objects
-                .unwrap()
-                .get(class_ref as usize)
-                .ok_or_else(|| "Expected objects[objects[root].$class] to exist")?;
-
-            Self::verify(class)
-        })
-    }
-
-    fn verify(class: &plist::Value) -> Result<(), String>;
-}
+    // Record for a new file or folder in this folder, keeping its owner and protection class
+    pub fn child(&self, relative_path: &str, mode: u64, now: u64) -> Self {
+        MBFile {
+            group_i_d: self.group_i_d,
+            user_i_d: self.user_i_d,
+            protection_class: self.protection_class,
+            ..MBFile::new(relative_path, mode, now)
+        }
+    }
 
-impl NSClassName for MBFile {
-    fn verify(class: &plist::Value) -> Result<(), String> {
-        let class: NSKeyedArchiverClass = plist::from_value(class)
-            .map_err(|e| format!("Error parsing NSKeyedArchiveClassTree: {}", e))?;
+    pub fn to_bytes(&self) -> Vec<u8> {
+        nska::to_bytes(self).unwrap()
+    }
 
-        if class.classname != "MBFile" || class.classes != ["MBFile", "NSObject"] {
-            return Err(format!("Expected MBFile class got {:#?}", class.classes));
+    pub fn from_bytes(data: &[u8]) -> Result<Self, nska::Error> {
+        let object = nska::decode_bytes(data)?;
+        let mut file: MBFile = nska::from_value(&object)?;
+        for (key, value) in object.into_dictionary().into_iter().flatten() {
+            if MBFILE_FIELDS.contains(&key.as_str()) {
+                continue;
+            }
+            if WARNED_FIELDS.lock().unwrap().insert(key.clone()) {
+                eprintln!("warning: unknown_mbfile_field key={}", key);
+            }
+            file.extra.insert(key, value);
         }
+        Ok(file)
+    }
+}
 
-        Ok(())
+impl rusqlite::types::FromSql for MBFile {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        MBFile::from_bytes(value.as_blob()?)
+            .map_err(|e| rusqlite::types::FromSqlError::Other(e.into()))
     }
 }
 
-impl<'de, T: serde::de::DeserializeOwned + NSClassName> serde::Deserialize<'de> for NSKeyed<T> {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let value = plist::Value::deserialize(deserializer)?;
+#[derive(serde::Serialize, Debug)]
+pub struct NSMutableData {
+    #[serde(rename = "NS.data")]
+    pub data: plist::Data,
+    #[serde(rename = "$class", serialize_with = "nsmutabledata_class")]
+    _class: serde::de::IgnoredAny,
+}
 
-        T::get_and_verify_class(&value).map_err(D::Error::custom)?;
+fn nsmutabledata_class<S: serde::Serializer>(
+    _: &serde::de::IgnoredAny,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    nska::class(
+        "NSMutableData",
+        &["NSMutableData", "NSData", "NSObject"],
+        serializer,
+    )
+}
 
-        Ok(NSKeyed(
-            plist::from_value(&value)
-                .map_err(|e| format!("Error parsing {}: {}", std::any::type_name::<T>(), e))
-                .map_err(D::Error::custom)?,
-        ))
+impl NSMutableData {
+    pub fn new(data: Vec<u8>) -> Self {
+        NSMutableData {
+            data: data.into(),
+            _class: serde::de::IgnoredAny,
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct NSKeyedArchive<T: serde::de::DeserializeOwned>(pub T);
-
-impl<'de, T: serde::de::DeserializeOwned> serde::Deserialize<'de> for NSKeyedArchive<T> {
+// Decoded archives hold its bytes as plain data
+impl<'de> serde::Deserialize<'de> for NSMutableData {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        let nska = NSKeyedArchiver::deserialize(deserializer)?;
-        if nska.version != 100000 {
-            return Err(D::Error::custom(format!(
-                "Incorrect version: {}",
-                nska.version
-            )));
-        }
-        if nska.archiver != "NSKeyedArchiver" {
-            return Err(D::Error::custom(format!(
-                "Incorrect archiver: {}",
-                nska.archiver
-            )));
-        }
-
-        thread_scoped_ref::scoped(&NSKA_OBJECTS, &nska.objects, || {
-            Ok(NSKeyedArchive(
-                plist::from_value(
-                    nska.objects
-                        .get(nska.top.root.get() as usize)
-                        .ok_or_else(|| "Expected objects[top.root] to exist")
-                        .map_err(D::Error::custom)?,
-                )
-                .map_err(|e| format!("Error parsing {}: {}", std::any::type_name::<T>(), e))
-                .map_err(D::Error::custom)?,
-            ))
-        })
+        Ok(NSMutableData::new(
+            plist::Data::deserialize(deserializer)?.into(),
+        ))
     }
 }
 
@@ -218,6 +241,16 @@ impl<'de> serde::Deserialize<'de> for NestedPlist {
     }
 }
 
+impl serde::Serialize for NestedPlist {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut data = Vec::new();
+        self.0
+            .to_writer_binary(&mut data)
+            .map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(&data)
+    }
+}
+
 /*
 Easily navigable format
 
@@ -240,13 +273,19 @@ impl FS {
             backing: vec![
                 Inode {
                     id: RawId([0; 20]),
+                    backup: None,
                     ftype: FileType::File,
                     children: None,
+                    missing: false,
+                    view: None,
                 }, // inode 0 doesn't exist
                 Inode {
                     id: RawId([0; 20]),
+                    backup: None,
                     ftype: FileType::Folder,
                     children: Some(Default::default()),
+                    missing: false,
+                    view: None,
                 },
             ], // root inode
         }
@@ -256,8 +295,43 @@ impl FS {
 #[derive(Debug)]
 pub struct Inode {
     pub id: RawId,
+    // Index of the backup holding this file, None for folders created by the mount itself
+    pub backup: Option<usize>,
     pub ftype: FileType,
     pub children: Option<std::collections::BTreeMap<String, usize>>,
+    // Set for files whose blob is absent from the backup
+    pub missing: bool,
+    // Set for the converted copies of a plist or database, with the inode of
+    // the plist or database. They share its id and backup.
+    pub view: Option<(View, usize)>,
+}
+
+#[derive(Clone, Debug)]
+pub enum View {
+    Plist(crate::plistview::View),
+    // Folder holding the exports of the tables of a database
+    Tables,
+    Table(String, crate::sqliteview::View, crate::sqliteview::Snapshot),
+}
+
+// Which files get views: the ones named .plist (or as a database), or also any
+// file starting with the binary plist (or SQLite) magic
+#[derive(Copy, Clone, Debug)]
+pub enum Selection {
+    Named,
+    All,
+}
+
+impl std::str::FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "named" => Ok(Selection::Named),
+            "all" => Ok(Selection::All),
+            _ => Err(format!("Unknown view selection: {}", s)),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -266,6 +340,26 @@ pub struct RawId([u8; 20]);
 pub struct StringId([u8; 40]);
 
 impl RawId {
+    pub fn parse(id: &str) -> Self {
+        let mut id_b = [0u8; 20];
+
+        let mut id_i = id
+            .as_bytes()
+            .chunks(2)
+            .map(std::str::from_utf8)
+            .map(Result::unwrap)
+            .map(|s| u8::from_str_radix(s, 16))
+            .map(Result::unwrap);
+
+        id_b.fill_with(|| id_i.next().unwrap());
+
+        RawId(id_b)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
     pub fn as_stringid(&self) -> StringId {
         use std::io::Write;
         struct ToHex<'a>(&'a [u8]);
@@ -339,6 +433,151 @@ impl FS {
         true
     }
 
+    // Full path of every file below the root folder
+    pub fn files(&self) -> Vec<(String, usize)> {
+        let mut res = Vec::new();
+        let mut stack = vec![(String::new(), 1)];
+        while let Some((path, inode_nr)) = stack.pop() {
+            for (name, child) in self.backing[inode_nr].children.as_ref().unwrap() {
+                let path = format!("{}/{}", path, name);
+                match self.backing[*child].ftype {
+                    FileType::File => res.push((path, *child)),
+                    FileType::Folder => stack.push((path, *child)),
+                }
+            }
+        }
+        res.sort();
+        res
+    }
+
+    // Adds name.xml and name.json next to every file for which is_plist is
+    // true, unless a file with that name exists
+    pub fn add_plist_views(&mut self, mut is_plist: impl FnMut(&str, &Inode) -> bool) {
+        for inode_nr in 0..self.backing.len() {
+            let Some(children) = &self.backing[inode_nr].children else {
+                continue;
+            };
+            let plists: Vec<(String, usize)> = children
+                .iter()
+                .filter(|(_, &x)| matches!(self.backing[x].ftype, FileType::File))
+                .map(|(name, &x)| (name.clone(), x))
+                .collect();
+
+            for (name, source) in plists {
+                if !is_plist(&name, &self.backing[source]) {
+                    continue;
+                }
+                for view in crate::plistview::View::ALL {
+                    let view_name = format!("{}.{}", name, view.extension());
+                    if self.backing[inode_nr]
+                        .children
+                        .as_ref()
+                        .unwrap()
+                        .contains_key(&view_name)
+                    {
+                        continue;
+                    }
+                    let new_inode = self.add_view(source, FileType::File, View::Plist(view));
+                    self.backing[inode_nr]
+                        .children
+                        .as_mut()
+                        .unwrap()
+                        .insert(view_name, new_inode);
+                }
+            }
+        }
+    }
+
+    // Adds a name.tables folder (name.prewal.tables before the -wal) for each
+    // snapshot next to every file for which tables returns the tables of a
+    // database, holding table.csv and table.jsonl for each of them, unless a
+    // file with that name exists
+    pub fn add_sqlite_views(
+        &mut self,
+        snapshots: &[crate::sqliteview::Snapshot],
+        mut tables: impl FnMut(&str, &Inode, crate::sqliteview::Snapshot) -> Option<Vec<String>>,
+    ) {
+        for inode_nr in 0..self.backing.len() {
+            let Some(children) = &self.backing[inode_nr].children else {
+                continue;
+            };
+            let files: Vec<(String, usize)> = children
+                .iter()
+                .filter(|(_, &x)| matches!(self.backing[x].ftype, FileType::File))
+                .map(|(name, &x)| (name.clone(), x))
+                .collect();
+
+            for ((name, source), &snapshot) in files
+                .iter()
+                .flat_map(|x| snapshots.iter().map(move |s| (x, s)))
+            {
+                let source = *source;
+                let folder_name = format!("{}.{}", name, snapshot.folder_extension());
+                if self.backing[inode_nr]
+                    .children
+                    .as_ref()
+                    .unwrap()
+                    .contains_key(&folder_name)
+                {
+                    continue;
+                }
+                let Some(tables) = tables(name, &self.backing[source], snapshot) else {
+                    continue;
+                };
+
+                let folder = self.add_view(source, FileType::Folder, View::Tables);
+                self.backing[inode_nr]
+                    .children
+                    .as_mut()
+                    .unwrap()
+                    .insert(folder_name, folder);
+                for table in tables {
+                    for view in crate::sqliteview::View::ALL {
+                        let file_name = format!("{}.{}", table, view.extension());
+                        let file = self.add_view(
+                            source,
+                            FileType::File,
+                            View::Table(table.clone(), view, snapshot),
+                        );
+                        self.backing[folder]
+                            .children
+                            .as_mut()
+                            .unwrap()
+                            .insert(file_name, file);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_view(&mut self, source: usize, ftype: FileType, view: View) -> usize {
+        let new_inode = self.backing.len();
+        let source_inode = &self.backing[source];
+        self.backing.push(Inode {
+            id: RawId(source_inode.id.0),
+            backup: source_inode.backup,
+            ftype,
+            children: match ftype {
+                FileType::Folder => Some(std::collections::BTreeMap::new()),
+                FileType::File => None,
+            },
+            missing: source_inode.missing,
+            view: Some((view, source)),
+        });
+        new_inode
+    }
+
+    // Removes files marked missing from their folders
+    pub fn remove_missing(&mut self) {
+        for inode_nr in 0..self.backing.len() {
+            let Some(mut children) = self.backing[inode_nr].children.take() else {
+                continue;
+            };
+            children.retain(|_, v| !self.backing[*v].missing);
+            self.backing[inode_nr].children = Some(children);
+        }
+    }
+
     pub fn remove_empty_directories(&mut self) {
         let mut children = self.backing[1].children.take();
 
@@ -350,71 +589,355 @@ impl FS {
         self.backing[1].children.replace(children.unwrap());
     }
 
-    // Parent folder must be inserted before children
-    pub fn insert_file(&mut self, domain: &str, path: &str, id: &str, ftype: FileType) {
-        let mut id_b = [0u8; 20];
-
-        let mut id_i = id
-            .as_bytes()
-            .chunks(2)
-            .map(std::str::from_utf8)
-            .map(Result::unwrap)
-            .map(|s| u8::from_str_radix(s, 16))
-            .map(Result::unwrap);
-
-        id_b.fill_with(|| id_i.next().unwrap());
-
-        let new_inode = self.backing.len();
-        if path == "" {
-            assert!(self.backing[1]
-                .children
-                .as_mut()
-                .unwrap()
-                .insert(domain.to_string(), new_inode)
-                .is_none());
-            self.backing.push(Inode {
-                id: RawId(id_b),
-                ftype: ftype,
-                children: match ftype {
-                    FileType::Folder => Some(std::collections::BTreeMap::new()),
-                    FileType::File => None,
-                },
-            });
-
-            return;
+    // Returns the folder called name in parent, creating an empty one if needed
+    pub fn mkdir(&mut self, parent: usize, name: &str) -> usize {
+        if let Some(x) = self.backing[parent].children.as_ref().unwrap().get(name) {
+            return *x;
         }
+        let new_inode = self.backing.len();
+        self.backing.push(Inode {
+            id: RawId([0; 20]),
+            backup: None,
+            ftype: FileType::Folder,
+            children: Some(std::collections::BTreeMap::new()),
+            missing: false,
+            view: None,
+        });
+        self.backing[parent]
+            .children
+            .as_mut()
+            .unwrap()
+            .insert(name.to_owned(), new_inode);
+        new_inode
+    }
 
-        let mut inode_nr = self.backing[1].children.as_ref().unwrap()[domain];
-
+    // Inserts domain/path below the root folder. Missing parent folders are created
+    // empty, and take over the identity of their record if it is inserted later.
+    // An entry clashing with one already there is reported and left out.
+    pub fn insert_file(
+        &mut self,
+        root: usize,
+        backup: usize,
+        domain: &str,
+        path: &str,
+        id: &str,
+        ftype: FileType,
+    ) -> Option<usize> {
+        let path_str = path;
         let path = std::path::Path::new(path);
+        let mut components = std::iter::once(domain)
+            .chain(path.components().map(|x| x.as_os_str().to_str().unwrap()))
+            .peekable();
+
+        let conflict = || {
+            eprintln!(
+                "warning: conflicting_entry file_id={} path={}/{}",
+                id, domain, path_str
+            );
+            None
+        };
+
+        let mut inode_nr = root;
+        let name = loop {
+            let x = components.next().unwrap();
+            if components.peek().is_none() {
+                break x;
+            }
+            inode_nr = self.mkdir(inode_nr, x);
+            if let FileType::File = self.backing[inode_nr].ftype {
+                return conflict();
+            }
+        };
 
-        for x in path.parent().unwrap().components() {
-            inode_nr =
-                self.backing[inode_nr].children.as_ref().unwrap()[x.as_os_str().to_str().unwrap()]
+        if let Some(&existing) = self.backing[inode_nr].children.as_ref().unwrap().get(name) {
+            let inode = &mut self.backing[existing];
+            if inode.backup.is_some() || !matches!(ftype, FileType::Folder) {
+                return conflict();
+            }
+            inode.id = RawId::parse(id);
+            inode.backup = Some(backup);
+            return Some(existing);
         }
 
-        assert!(self.backing[inode_nr]
+        let new_inode = self.backing.len();
+        self.backing[inode_nr]
             .children
             .as_mut()
             .unwrap()
-            .insert(
-                path.components()
-                    .last()
-                    .unwrap()
-                    .as_os_str()
-                    .to_str()
-                    .unwrap()
-                    .to_owned(),
-                new_inode
-            )
-            .is_none());
+            .insert(name.to_owned(), new_inode);
         self.backing.push(Inode {
-            id: RawId(id_b),
-            ftype: ftype,
+            id: RawId::parse(id),
+            backup: Some(backup),
+            ftype,
             children: match ftype {
                 FileType::Folder => Some(std::collections::BTreeMap::new()),
                 FileType::File => None,
             },
+            missing: false,
+            view: None,
         });
+        Some(new_inode)
+    }
+
+    // Whether domain/path can be added below root without hiding an existing
+    // entry. Only a folder can take the place of a folder made up for the
+    // entries below it.
+    fn is_vacant(&self, root: usize, domain: &str, path: &str, ftype: FileType) -> bool {
+        let mut inode_nr = root;
+        let components = std::iter::once(domain).chain(
+            std::path::Path::new(path)
+                .components()
+                .map(|x| x.as_os_str().to_str().unwrap()),
+        );
+        for x in components {
+            match self.backing[inode_nr].children.as_ref().unwrap().get(x) {
+                None => return true,
+                Some(n) => inode_nr = *n,
+            }
+            if let FileType::File = self.backing[inode_nr].ftype {
+                return false;
+            }
+        }
+        self.backing[inode_nr].backup.is_none() && matches!(ftype, FileType::Folder)
+    }
+
+    // Adds every file and folder of a Manifest.db below the root folder. With overlay
+    // set, entries already present (from a newer backup) are kept instead.
+    pub fn insert_backup(
+        &mut self,
+        root: usize,
+        backup: usize,
+        con: &rusqlite::Connection,
+        overlay: bool,
+    ) {
+        let mut sta = con
+            .prepare("SELECT * FROM Files ORDER BY domain, relativePath")
+            .unwrap();
+        let mut rows = sta.query(()).unwrap();
+
+        while let Some(row) = rows.next().unwrap() {
+            let domain = row.get_ref(1).unwrap().as_str().unwrap();
+            let path = row.get_ref(2).unwrap().as_str().unwrap();
+            let id = row.get_ref(0).unwrap().as_str().unwrap();
+            let ftype = row.get_ref(3).unwrap().as_i64().unwrap();
+
+            if ftype == 4 {
+                continue;
+            }
+            let ftype = FileType::from(ftype);
+
+            if overlay && !self.is_vacant(root, domain, path, ftype) {
+                continue;
+            }
+
+            // Only records that make it into the tree are decoded, as the
+            // mount reads their MBFile back from the database and relies on
+            // it being valid
+            let data = row.get_ref(4).unwrap().as_blob().unwrap();
+            if let Err(e) = MBFile::from_bytes(data) {
+                eprintln!("warning: invalid_mbfile file_id={} error={}", id, e);
+                continue;
+            }
+
+            self.insert_file(root, backup, domain, path, id, ftype);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::FromSql;
+
+    fn mbfile() -> MBFile {
+        let mut xattrs = plist::Dictionary::new();
+        xattrs.insert(
+            "com.apple.assetsd.UUID".to_owned(),
+            plist::Value::Data(b"uuid".to_vec()),
+        );
+        MBFile {
+            flags: 4,
+            extended_attributes: Some(NestedPlist(xattrs.into())),
+            target: Some("../b".to_owned()),
+            encryption_key: Some(NSMutableData::new(vec![3, 0, 0, 0, 1, 2])),
+            size: 1234,
+            digest: Some(vec![0xaa; 20].into()),
+            inode_number: 77,
+            protection_class: 3,
+            ..MBFile::new("Library/a", 0o100644, 1_700_000_000)
+        }
+    }
+
+    fn class_chain(archive: &plist::Value, object: &plist::Value) -> Vec<String> {
+        let objects = archive.as_dictionary().unwrap()["$objects"]
+            .as_array()
+            .unwrap();
+        let class = object.as_dictionary().unwrap()["$class"].as_uid().unwrap();
+        objects[class.get() as usize].as_dictionary().unwrap()["$classes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x.as_string().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn mbfile_round_trip() {
+        let bytes = mbfile().to_bytes();
+
+        let decoded = nska::decode_bytes(&bytes).unwrap();
+        let fields = decoded.as_dictionary().unwrap();
+        assert_eq!(fields["$class"].as_string(), Some("MBFile"));
+        assert_eq!(fields["RelativePath"].as_string(), Some("Library/a"));
+        assert_eq!(fields["Target"].as_string(), Some("../b"));
+        assert_eq!(
+            fields["LastModified"].as_unsigned_integer(),
+            Some(1_700_000_000)
+        );
+        assert_eq!(fields["Size"].as_unsigned_integer(), Some(1234));
+        assert_eq!(fields["Mode"].as_unsigned_integer(), Some(0o100644));
+        assert_eq!(fields["ProtectionClass"].as_unsigned_integer(), Some(3));
+        assert_eq!(fields["Digest"].as_data(), Some(&[0xaa; 20][..]));
+        assert_eq!(
+            fields["EncryptionKey"].as_data(),
+            Some(&[3, 0, 0, 0, 1, 2][..])
+        );
+        let xattrs = plist::Value::from_reader(std::io::Cursor::new(
+            fields["ExtendedAttributes"].as_data().unwrap(),
+        ))
+        .unwrap();
+        assert_eq!(
+            xattrs.as_dictionary().unwrap()["com.apple.assetsd.UUID"].as_data(),
+            Some(&b"uuid"[..])
+        );
+
+        let file = MBFile::column_result(rusqlite::types::ValueRef::Blob(&bytes)).unwrap();
+        let expected = mbfile();
+        assert_eq!(file.relative_path, expected.relative_path);
+        assert_eq!(file.target, expected.target);
+        assert_eq!(file.flags, expected.flags);
+        assert_eq!(file.size, expected.size);
+        assert_eq!(file.mode, expected.mode);
+        assert_eq!(file.birth, expected.birth);
+        assert_eq!(file.last_modified, expected.last_modified);
+        assert_eq!(file.last_status_change, expected.last_status_change);
+        assert_eq!(file.inode_number, expected.inode_number);
+        assert_eq!(file.user_i_d, expected.user_i_d);
+        assert_eq!(file.group_i_d, expected.group_i_d);
+        assert_eq!(file.protection_class, expected.protection_class);
+        assert_eq!(file.digest, expected.digest);
+        assert_eq!(
+            file.encryption_key.unwrap().data,
+            expected.encryption_key.unwrap().data
+        );
+        assert_eq!(
+            file.extended_attributes.unwrap().0,
+            expected.extended_attributes.unwrap().0
+        );
+        assert!(file.extra.is_empty());
+    }
+
+    #[test]
+    fn unknown_fields_round_trip() {
+        use plist::Value;
+
+        let mut owner = plist::Dictionary::new();
+        owner.insert("$class".to_owned(), "MBOwner".into());
+        owner.insert("Name".to_owned(), "mobile".into());
+        owner.insert("Uid".to_owned(), 501.into());
+        let mut dict = plist::Dictionary::new();
+        dict.insert("key".to_owned(), "value".into());
+        dict.insert(
+            "nested".to_owned(),
+            Value::Array(vec![1.into(), "two".into()]),
+        );
+
+        let mut file = mbfile();
+        let date = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let before_1970 = std::time::UNIX_EPOCH - std::time::Duration::from_secs(86400);
+        file.extra = [
+            ("Boolean", true.into()),
+            ("Integer", Value::Integer((-7).into())),
+            ("Real", 2.5.into()),
+            ("String", "text".into()),
+            ("Data", Value::Data(vec![1, 2, 3])),
+            ("Date", Value::Date(date.into())),
+            ("OldDate", Value::Date(before_1970.into())),
+            ("Array", Value::Array(vec![dict.clone().into(), 3.into()])),
+            ("Dictionary", dict.into()),
+            ("Owner", owner.into()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v))
+        .collect();
+
+        // Nothing but the class descriptions is stored inline as a dictionary
+        let archive = nska::to_value(&file).unwrap();
+        let objects = archive.as_dictionary().unwrap()["$objects"]
+            .as_array()
+            .unwrap();
+        let root = objects[1].as_dictionary().unwrap();
+        for key in ["Date", "Array", "Dictionary", "Owner", "String", "Data"] {
+            assert!(root[key].as_uid().is_some(), "{} is inline", key);
+        }
+
+        let decoded = MBFile::from_bytes(&file.to_bytes()).unwrap();
+        assert_eq!(decoded.extra, file.extra);
+        assert_eq!(decoded.relative_path, file.relative_path);
+    }
+
+    #[test]
+    fn nsmutabledata_round_trip() {
+        let archive = nska::to_value(&NSMutableData::new(vec![1, 2, 3])).unwrap();
+        let objects = archive.as_dictionary().unwrap()["$objects"]
+            .as_array()
+            .unwrap();
+        assert_eq!(
+            class_chain(&archive, &objects[1]),
+            ["NSMutableData", "NSData", "NSObject"]
+        );
+        assert_eq!(
+            nska::decode(&archive).unwrap().as_data(),
+            Some(&[1, 2, 3][..])
+        );
+
+        // As the EncryptionKey of an MBFile, sharing the archive's class descriptions
+        let archive = nska::to_value(&mbfile()).unwrap();
+        let objects = archive.as_dictionary().unwrap()["$objects"]
+            .as_array()
+            .unwrap();
+        let root = &objects[1];
+        assert_eq!(class_chain(&archive, root), ["MBFile", "NSObject"]);
+        let key = root.as_dictionary().unwrap()["EncryptionKey"]
+            .as_uid()
+            .unwrap();
+        assert_eq!(
+            class_chain(&archive, &objects[key.get() as usize]),
+            ["NSMutableData", "NSData", "NSObject"]
+        );
+    }
+
+    #[test]
+    fn file_clashing_with_folder_is_skipped() {
+        let mut fs = FS::new();
+        let id = "ab".repeat(20);
+
+        // A newer backup only has entries below HomeDomain/a, which is made up
+        fs.insert_file(1, 0, "HomeDomain", "a/b", &id, FileType::File)
+            .unwrap();
+        assert!(!fs.is_vacant(1, "HomeDomain", "a", FileType::File));
+        assert!(fs.is_vacant(1, "HomeDomain", "a", FileType::Folder));
+        assert!(!fs.is_vacant(1, "HomeDomain", "a/b/c", FileType::File));
+
+        assert!(fs
+            .insert_file(1, 1, "HomeDomain", "a", &id, FileType::File)
+            .is_none());
+        assert!(fs
+            .insert_file(1, 1, "HomeDomain", "a/b/c", &id, FileType::File)
+            .is_none());
+        let folder = fs
+            .insert_file(1, 1, "HomeDomain", "a", &id, FileType::Folder)
+            .unwrap();
+        assert_eq!(fs.backing[folder].backup, Some(1));
     }
 }
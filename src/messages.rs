@@ -0,0 +1,673 @@
+use std::{io::Write, path::Path};
+
+use rusqlite::Connection;
+
+use crate::{
+    backup::{file_id, Backup},
+    nska,
+    sqliteview::{has_column, Snapshot},
+    util::{apple_time, percent_encode},
+};
+
+const SMS_DOMAIN: &str = "HomeDomain";
+const SMS_PATH: &str = "Library/SMS/sms.db";
+const ATTACHMENT_DOMAIN: &str = "MediaDomain";
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Format {
+    Html,
+    Json,
+    Text,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Html => "html",
+            Format::Json => "json",
+            Format::Text => "txt",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "html" => Ok(Format::Html),
+            "json" => Ok(Format::Json),
+            "text" => Ok(Format::Text),
+            _ => Err(format!("Unknown messages format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Attachment {
+    pub name: Option<String>,
+    pub mime_type: Option<String>,
+    // Path recorded by Messages, under ~/Library/SMS/Attachments
+    pub filename: Option<String>,
+    // Backup file it resolves to, None if it isn't in the backup
+    pub domain: Option<String>,
+    #[serde(rename = "relativePath")]
+    pub path: Option<String>,
+    #[serde(rename = "fileID")]
+    pub id: Option<String>,
+    // Copy next to the transcripts, relative to them
+    pub exported: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Message {
+    pub rowid: i64,
+    pub guid: String,
+    // RFC 3339
+    pub time: Option<String>,
+    pub read_time: Option<String>,
+    pub from_me: bool,
+    // Handle of the sender, None when sent from the device
+    pub sender: Option<String>,
+    pub service: Option<String>,
+    pub subject: Option<String>,
+    // From the text column, or else decoded from attributedBody
+    pub text: Option<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Conversation {
+    pub rowid: i64,
+    pub guid: String,
+    pub identifier: String,
+    pub service: Option<String>,
+    pub display_name: Option<String>,
+    // Handles of the other participants
+    pub participants: Vec<String>,
+    pub messages: Vec<Message>,
+}
+
+impl Conversation {
+    fn title(&self) -> &str {
+        match &self.display_name {
+            Some(name) if !name.is_empty() => name,
+            _ => &self.identifier,
+        }
+    }
+
+    // Name of its transcripts, made unique by the chat's rowid
+    fn file_stem(&self) -> String {
+        let identifier: String = self
+            .identifier
+            .chars()
+            .map(|c| if c == '/' || c.is_control() { '_' } else { c })
+            .collect();
+        format!("{}-{}", self.rowid, identifier)
+    }
+}
+
+// Every chat of sms.db with its messages in time order. Messages that aren't
+// in a chat are left out.
+pub(crate) fn conversations(backup: &Backup) -> Result<Vec<Conversation>, String> {
+    let con = backup.open_database(&file_id(SMS_DOMAIN, SMS_PATH), Snapshot::Latest)?;
+    read_conversations(&con).map_err(|e| e.to_string())
+}
+
+fn read_conversations(con: &Connection) -> rusqlite::Result<Vec<Conversation>> {
+    let mut sta = con.prepare(
+        "SELECT ROWID, guid, chat_identifier, service_name, display_name FROM chat ORDER BY ROWID",
+    )?;
+    let mut conversations = sta
+        .query_map((), |r| {
+            Ok(Conversation {
+                rowid: r.get(0)?,
+                guid: r.get(1)?,
+                identifier: r.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                service: r.get(3)?,
+                display_name: r.get(4)?,
+                participants: Vec::new(),
+                messages: Vec::new(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut participants = con.prepare(
+        "SELECT h.id FROM chat_handle_join j JOIN handle h ON h.ROWID = j.handle_id \
+         WHERE j.chat_id = ? ORDER BY h.ROWID",
+    )?;
+
+    // attributedBody holds the text of messages sent from iOS 16 on, older
+    // databases don't have it
+    let body = if has_column(con, "message", "attributedBody")? {
+        "m.attributedBody"
+    } else {
+        "NULL"
+    };
+    let mut messages = con.prepare(&format!(
+        "SELECT m.ROWID, m.guid, m.date, m.date_read, m.is_from_me, h.id, m.service, \
+         m.subject, m.text, {} FROM chat_message_join j JOIN message m ON m.ROWID = j.message_id \
+         LEFT JOIN handle h ON h.ROWID = m.handle_id WHERE j.chat_id = ? ORDER BY m.date, m.ROWID",
+        body
+    ))?;
+
+    let mut attachments = con.prepare(
+        "SELECT a.transfer_name, a.mime_type, a.filename FROM message_attachment_join j \
+         JOIN attachment a ON a.ROWID = j.attachment_id WHERE j.message_id = ? ORDER BY a.ROWID",
+    )?;
+
+    for conversation in &mut conversations {
+        conversation.participants = participants
+            .query_map([conversation.rowid], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        conversation.messages = messages
+            .query_map([conversation.rowid], |r| {
+                let from_me: bool = r.get(4)?;
+                let text: Option<String> = r.get(8)?;
+                let body: Option<Vec<u8>> = r.get(9)?;
+                Ok(Message {
+                    rowid: r.get(0)?,
+                    guid: r.get(1)?,
+                    time: r.get::<_, Option<i64>>(2)?.and_then(apple_time),
+                    read_time: r.get::<_, Option<i64>>(3)?.and_then(apple_time),
+                    from_me,
+                    sender: if from_me { None } else { r.get(5)? },
+                    service: r.get(6)?,
+                    subject: r.get(7)?,
+                    text: text
+                        .filter(|x| !x.is_empty())
+                        .or_else(|| body.as_deref().and_then(attributed_text))
+                        .map(|x| x.replace('\u{fffc}', "").trim().to_owned())
+                        .filter(|x| !x.is_empty()),
+                    attachments: Vec::new(),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        for message in &mut conversation.messages {
+            message.attachments = attachments
+                .query_map([message.rowid], |r| {
+                    let filename: Option<String> = r.get(2)?;
+                    let (domain, path, id) = match filename.as_deref().and_then(attachment_path) {
+                        Some(path) => (
+                            Some(ATTACHMENT_DOMAIN.to_owned()),
+                            Some(path.to_owned()),
+                            Some(file_id(ATTACHMENT_DOMAIN, path)),
+                        ),
+                        None => (None, None, None),
+                    };
+                    Ok(Attachment {
+                        name: r.get(0)?,
+                        mime_type: r.get(1)?,
+                        filename,
+                        domain,
+                        path,
+                        id,
+                        exported: None,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+        }
+    }
+    Ok(conversations)
+}
+
+// relativePath in MediaDomain of an attachment recorded as
+// ~/Library/SMS/Attachments/.. or /var/mobile/Library/SMS/Attachments/..
+fn attachment_path(filename: &str) -> Option<&str> {
+    filename
+        .strip_prefix("~/")
+        .or_else(|| filename.strip_prefix("/var/mobile/"))
+        .or_else(|| filename.strip_prefix("/private/var/mobile/"))
+}
+
+// Text of an NSAttributedString, archived with NSArchiver (a typedstream) as
+// Messages does, or with NSKeyedArchiver
+pub(crate) fn attributed_text(data: &[u8]) -> Option<String> {
+    if data.starts_with(b"bplist") {
+        return match nska::decode_bytes(data).ok()? {
+            plist::Value::String(x) => Some(x),
+            _ => None,
+        };
+    }
+
+    // The string is the first object of the typedstream, an NSString whose
+    // contents follow its class as a '+' (C string) value
+    let class = data.windows(8).position(|x| x == b"NSString")? + 8;
+    let value = class + data[class..].iter().take(8).position(|&x| x == b'+')? + 1;
+    let (len, start) = match *data.get(value)? {
+        0x81 => (
+            u16::from_le_bytes(data.get(value + 1..value + 3)?.try_into().ok()?) as usize,
+            value + 3,
+        ),
+        0x82 => (
+            u32::from_le_bytes(data.get(value + 1..value + 5)?.try_into().ok()?) as usize,
+            value + 5,
+        ),
+        x => (x as usize, value + 1),
+    };
+    String::from_utf8(data.get(start..start.checked_add(len)?)?.to_vec()).ok()
+}
+
+// Writes a transcript per conversation into the output folder, and the
+// attachments found in the backup into its attachments folder
+pub(crate) fn export(
+    backup: &Backup,
+    conversations: &mut [Conversation],
+    format: Format,
+    output: &Path,
+) -> std::io::Result<()> {
+    let attachments = output.join("attachments");
+    std::fs::create_dir_all(&attachments)?;
+
+    for conversation in conversations.iter_mut() {
+        for attachment in conversation
+            .messages
+            .iter_mut()
+            .flat_map(|x| &mut x.attachments)
+        {
+            attachment.exported = export_attachment(backup, attachment, &attachments)?;
+        }
+
+        let path = output.join(format!(
+            "{}.{}",
+            conversation.file_stem(),
+            format.extension()
+        ));
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        match format {
+            Format::Html => write_html(&mut out, conversation)?,
+            Format::Json => {
+                serde_json::to_writer_pretty(&mut out, conversation)?;
+                writeln!(out)?;
+            }
+            Format::Text => write_text(&mut out, conversation)?,
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+// Copies an attachment as attachments/<fileID>-<name>, None if it isn't in
+// the backup
+fn export_attachment(
+    backup: &Backup,
+    attachment: &Attachment,
+    folder: &Path,
+) -> std::io::Result<Option<String>> {
+    let (Some(id), Some(path)) = (&attachment.id, &attachment.path) else {
+        return Ok(None);
+    };
+    let name = format!("{}-{}", id, path.rsplit('/').next().unwrap_or_default());
+    let target = folder.join(&name);
+    if !target.exists() {
+        let data = backup
+            .record(id)
+            .ok()
+            .and_then(|(_, _, _, mbfile)| backup.read_file(id, &mbfile));
+        let Some(data) = data else {
+            eprintln!("warning: missing_attachment file_id={} path={}", id, path);
+            return Ok(None);
+        };
+        std::fs::write(&target, data)?;
+    }
+    Ok(Some(format!("attachments/{}", name)))
+}
+
+fn sender(message: &Message) -> &str {
+    match &message.sender {
+        _ if message.from_me => "Me",
+        Some(x) => x,
+        None => "Unknown",
+    }
+}
+
+fn write_text(out: &mut impl Write, conversation: &Conversation) -> std::io::Result<()> {
+    writeln!(out, "{}", conversation.title())?;
+    if !conversation.participants.is_empty() {
+        writeln!(
+            out,
+            "Participants: {}",
+            conversation.participants.join(", ")
+        )?;
+    }
+    for message in &conversation.messages {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "[{}] {}:",
+            message.time.as_deref().unwrap_or("unknown time"),
+            sender(message)
+        )?;
+        if let Some(subject) = &message.subject {
+            writeln!(out, "Subject: {}", subject)?;
+        }
+        if let Some(text) = &message.text {
+            writeln!(out, "{}", text)?;
+        }
+        for attachment in &message.attachments {
+            writeln!(
+                out,
+                "<attachment: {}>",
+                attachment
+                    .exported
+                    .as_deref()
+                    .or(attachment.filename.as_deref())
+                    .or(attachment.name.as_deref())
+                    .unwrap_or("missing")
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_html(out: &mut impl Write, conversation: &Conversation) -> std::io::Result<()> {
+    let title = escape(conversation.title());
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(
+        out,
+        "<html><head><meta charset=\"utf-8\"><title>{}</title>",
+        title
+    )?;
+    writeln!(
+        out,
+        "<style>.message{{margin:.5em 0}}.from-me{{text-align:right}}\
+         .meta{{color:#888;font-size:small}}.text{{white-space:pre-wrap}}\
+         img{{max-width:20em}}</style></head><body>"
+    )?;
+    writeln!(out, "<h1>{}</h1>", title)?;
+    if !conversation.participants.is_empty() {
+        writeln!(
+            out,
+            "<p>Participants: {}</p>",
+            escape(&conversation.participants.join(", "))
+        )?;
+    }
+    for message in &conversation.messages {
+        let class = if message.from_me {
+            "message from-me"
+        } else {
+            "message"
+        };
+        writeln!(out, "<div class=\"{}\">", class)?;
+        writeln!(
+            out,
+            "<div class=\"meta\">{} {}</div>",
+            escape(sender(message)),
+            escape(message.time.as_deref().unwrap_or_default())
+        )?;
+        if let Some(subject) = &message.subject {
+            writeln!(out, "<div class=\"subject\">{}</div>", escape(subject))?;
+        }
+        if let Some(text) = &message.text {
+            writeln!(out, "<div class=\"text\">{}</div>", escape(text))?;
+        }
+        for attachment in &message.attachments {
+            let name = attachment
+                .name
+                .as_deref()
+                .or(attachment.filename.as_deref())
+                .unwrap_or("attachment");
+            match &attachment.exported {
+                Some(href)
+                    if attachment
+                        .mime_type
+                        .as_deref()
+                        .is_some_and(|x| x.starts_with("image/")) =>
+                {
+                    writeln!(
+                        out,
+                        "<div><a href=\"{0}\"><img src=\"{0}\" alt=\"{1}\"></a></div>",
+                        percent_encode(href),
+                        escape(name)
+                    )?
+                }
+                Some(href) => writeln!(
+                    out,
+                    "<div><a href=\"{}\">{}</a></div>",
+                    percent_encode(href),
+                    escape(name)
+                )?,
+                None => writeln!(out, "<div>{} (missing)</div>", escape(name))?,
+            }
+        }
+        writeln!(out, "</div>")?;
+    }
+    writeln!(out, "</body></html>")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nska::tests::{archive, class, instance};
+
+    // attributedBody as written by iOS 16: an NSAttributedString archived
+    // with NSArchiver, whose NSString is followed by the attribute runs. No
+    // captured blob is at hand, so this follows the documented layout.
+    fn typedstream(text: &str) -> Vec<u8> {
+        let mut data = vec![0x04, 0x0b];
+        data.extend_from_slice(b"streamtyped");
+        data.extend_from_slice(&[0x81, 0xe8, 0x03, 0x84, 0x01, 0x40, 0x84, 0x84, 0x84, 0x12]);
+        data.extend_from_slice(b"NSAttributedString\0");
+        data.extend_from_slice(&[0x84, 0x84, 0x08]);
+        data.extend_from_slice(b"NSObject\0");
+        data.extend_from_slice(&[0x85, 0x92, 0x84, 0x84, 0x84, 0x08]);
+        data.extend_from_slice(b"NSString");
+        data.extend_from_slice(&[0x01, 0x94, 0x84, 0x01, b'+']);
+        match text.len() {
+            len @ 0..0x80 => data.push(len as u8),
+            len => {
+                data.push(0x81);
+                data.extend_from_slice(&(len as u16).to_le_bytes());
+            }
+        }
+        data.extend_from_slice(text.as_bytes());
+        data.extend_from_slice(&[0x86, 0x84, 0x02, b'i', b'I', 0x01, 0x05, 0x92, 0x84, 0x84]);
+        data.extend_from_slice(b"\x84\x0cNSDictionary\0\x94\x84\x01i\x01\x92\x84\x96\x96");
+        data.extend_from_slice(b"\x1d__kIMMessagePartAttributeName\x86\x92\x84\x84\x84\x08");
+        data.extend_from_slice(b"NSNumber\0\x84\x84\x07NSValue\0\x94\x84\x01*");
+        data.extend_from_slice(b"\x84\x99\x99\x00\x86\x86\x86");
+        data
+    }
+
+    // sms.db with the tables and columns read by read_conversations
+    fn sms_db() -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute_batch(
+            "CREATE TABLE chat (ROWID INTEGER PRIMARY KEY, guid TEXT, chat_identifier TEXT, \
+                 service_name TEXT, display_name TEXT);
+             CREATE TABLE handle (ROWID INTEGER PRIMARY KEY, id TEXT);
+             CREATE TABLE chat_handle_join (chat_id INTEGER, handle_id INTEGER);
+             CREATE TABLE message (ROWID INTEGER PRIMARY KEY, guid TEXT, date INTEGER, \
+                 date_read INTEGER, is_from_me INTEGER, handle_id INTEGER, service TEXT, \
+                 subject TEXT, text TEXT, attributedBody BLOB);
+             CREATE TABLE chat_message_join (chat_id INTEGER, message_id INTEGER);
+             CREATE TABLE attachment (ROWID INTEGER PRIMARY KEY, transfer_name TEXT, \
+                 mime_type TEXT, filename TEXT);
+             CREATE TABLE message_attachment_join (message_id INTEGER, attachment_id INTEGER);
+
+             INSERT INTO chat VALUES (1, 'iMessage;-;+15555550100', '+15555550100', 'iMessage', NULL);
+             INSERT INTO chat VALUES (2, 'iMessage;+;chat1', 'chat1', 'iMessage', 'Family');
+             INSERT INTO handle VALUES (1, '+15555550100'), (2, 'ann@example.com');
+             INSERT INTO chat_handle_join VALUES (1, 1), (2, 2), (2, 1);
+
+             INSERT INTO message VALUES
+                 (1, 'm1', 700000000000000000, 700000060000000000, 0, 1, 'iMessage', NULL, 'Hi', NULL),
+                 (2, 'm2', 700000120000000000, 0, 1, 1, 'iMessage', NULL, NULL, NULL),
+                 (3, 'm3', 600000000, 0, 0, 2, 'SMS', 'Subject', '', NULL),
+                 (4, 'm4', 700000000000000000, 0, 0, 1, 'iMessage', NULL, 'Nowhere', NULL),
+                 (5, 'm5', 500000000, 0, 0, 1, 'SMS', NULL, char(65532), NULL);
+             INSERT INTO chat_message_join VALUES (1, 2), (1, 1), (2, 3), (2, 5);
+
+             INSERT INTO attachment VALUES
+                 (1, 'IMG_1.jpg', 'image/jpeg', '~/Library/SMS/Attachments/ab/01/IMG_1.jpg'),
+                 (2, 'a.pdf', 'application/pdf', '/var/mobile/Library/SMS/Attachments/cd/02/a.pdf'),
+                 (3, 'gone.txt', 'text/plain', '/tmp/gone.txt');
+             INSERT INTO message_attachment_join VALUES (3, 2), (3, 1), (5, 3);",
+        )
+        .unwrap();
+        con
+    }
+
+    #[test]
+    fn conversations_are_read_from_sms_db() {
+        let con = sms_db();
+        for (rowid, text) in [(2, "Reply"), (3, "From body")] {
+            con.execute(
+                "UPDATE message SET attributedBody = ? WHERE ROWID = ?",
+                (typedstream(text), rowid),
+            )
+            .unwrap();
+        }
+
+        let conversations = read_conversations(&con).unwrap();
+        assert_eq!(conversations.len(), 2);
+
+        let direct = &conversations[0];
+        assert_eq!(direct.title(), "+15555550100");
+        assert_eq!(direct.participants, ["+15555550100"]);
+        let summary = direct
+            .messages
+            .iter()
+            .map(|m| (m.guid.as_str(), m.sender.as_deref(), m.text.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("m1", Some("+15555550100"), Some("Hi")),
+                ("m2", None, Some("Reply")),
+            ]
+        );
+        assert_eq!(
+            direct.messages[0].time.as_deref(),
+            Some("2023-03-08T20:26:40+00:00")
+        );
+        assert_eq!(
+            direct.messages[0].read_time.as_deref(),
+            Some("2023-03-08T20:27:40+00:00")
+        );
+        assert!(direct.messages[1].from_me);
+        assert_eq!(direct.messages[1].read_time, None);
+
+        let group = &conversations[1];
+        assert_eq!(group.title(), "Family");
+        assert_eq!(group.participants, ["+15555550100", "ann@example.com"]);
+        assert_eq!(
+            group.messages.iter().map(|m| m.rowid).collect::<Vec<_>>(),
+            [5, 3]
+        );
+        // Only an attachment character as text
+        assert_eq!(group.messages[0].text, None);
+
+        let message = &group.messages[1];
+        assert_eq!(message.sender.as_deref(), Some("ann@example.com"));
+        assert_eq!(message.subject.as_deref(), Some("Subject"));
+        assert_eq!(message.text.as_deref(), Some("From body"));
+        let attachments = message
+            .attachments
+            .iter()
+            .map(|a| (a.name.as_deref().unwrap(), a.path.as_deref(), a.id.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            attachments,
+            [
+                (
+                    "IMG_1.jpg",
+                    Some("Library/SMS/Attachments/ab/01/IMG_1.jpg"),
+                    Some(file_id(
+                        "MediaDomain",
+                        "Library/SMS/Attachments/ab/01/IMG_1.jpg"
+                    ))
+                ),
+                (
+                    "a.pdf",
+                    Some("Library/SMS/Attachments/cd/02/a.pdf"),
+                    Some(file_id(
+                        "MediaDomain",
+                        "Library/SMS/Attachments/cd/02/a.pdf"
+                    ))
+                ),
+            ]
+        );
+        assert_eq!(
+            message.attachments[0].domain.as_deref(),
+            Some("MediaDomain")
+        );
+
+        let unresolved = &group.messages[0].attachments[0];
+        assert_eq!(unresolved.filename.as_deref(), Some("/tmp/gone.txt"));
+        assert_eq!((&unresolved.domain, &unresolved.id), (&None, &None));
+    }
+
+    #[test]
+    fn typedstream_text() {
+        for text in ["", "Hello", "Café 👋", &"long ".repeat(60)] {
+            assert_eq!(attributed_text(&typedstream(text)).as_deref(), Some(text));
+        }
+        // Lengths beyond the blob
+        let mut data = typedstream("Hello");
+        let text = data.windows(5).position(|x| x == b"Hello").unwrap();
+        data.truncate(text + 3);
+        assert_eq!(attributed_text(&data), None);
+        assert_eq!(attributed_text(b"streamtyped"), None);
+    }
+
+    #[test]
+    fn keyed_archive_text() {
+        let archive = archive(vec![
+            instance(3, &[("NSString", nska::tests::uid(2))]),
+            "Hello".into(),
+            class("NSAttributedString"),
+        ]);
+        let mut data = Vec::new();
+        plist::to_writer_binary(&mut data, &archive).unwrap();
+        assert_eq!(attributed_text(&data).as_deref(), Some("Hello"));
+    }
+
+    #[test]
+    fn attachment_links_are_encoded() {
+        let attachment = |mime_type: &str| Attachment {
+            name: None,
+            mime_type: Some(mime_type.to_owned()),
+            filename: None,
+            domain: None,
+            path: None,
+            id: None,
+            exported: Some("attachments/ab-my photo#1?.jpg".to_owned()),
+        };
+        let conversation = Conversation {
+            rowid: 1,
+            guid: "guid".to_owned(),
+            identifier: "+15555550100".to_owned(),
+            service: None,
+            display_name: None,
+            participants: Vec::new(),
+            messages: vec![Message {
+                rowid: 1,
+                guid: "guid".to_owned(),
+                time: None,
+                read_time: None,
+                from_me: true,
+                sender: None,
+                service: None,
+                subject: None,
+                text: None,
+                attachments: vec![attachment("image/jpeg"), attachment("text/vcard")],
+            }],
+        };
+        let mut out = Vec::new();
+        write_html(&mut out, &conversation).unwrap();
+        let html = String::from_utf8(out).unwrap();
+        let href = "attachments/ab-my%20photo%231%3F.jpg";
+        assert!(html.contains(&format!("<a href=\"{0}\"><img src=\"{0}\"", href)));
+        assert!(html.contains(&format!("<div><a href=\"{}\">attachment</a></div>", href)));
+    }
+}
@@ -0,0 +1,913 @@
+use serde::ser::{self, Serialize};
+use std::collections::HashMap;
+
+use crate::util::APPLE_EPOCH;
+
+// Encoder for NSKeyedArchives such as MBFile records.
+//
+// Fields are stored inline in the object holding them, except the ones
+// serialized with `object`, which are added to $objects and referenced by
+// UID. Structs get a $class entry of class chain [name, "NSObject"], a
+// struct named "Thing" being a direct subclass of NSObject. Structs of other
+// classes, and structs with flattened fields which serde serializes as maps,
+// name their class with a $class field serialized with `class`.
+//
+// The decoder below reads archives of any class into a plain plist::Value.
+
+const OBJECT_NEWTYPE_STRUCT_NAME: &str = "$NSKeyedObject";
+const CLASS_NEWTYPE_STRUCT_NAME: &str = "$NSKeyedClass";
+const UID_NEWTYPE_STRUCT_NAME: &str = "PLIST-UID";
+
+// serialize_with function storing a field as a separate object. A None field
+// is stored as the $null reference.
+pub fn object<S: ser::Serializer, T: Serialize>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_newtype_struct(OBJECT_NEWTYPE_STRUCT_NAME, value)
+}
+
+// Reference to the class description of name, whose $classes are the class
+// chain from name up to NSObject
+pub fn class<S: ser::Serializer>(
+    name: &str,
+    classes: &[&str],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_newtype_struct(CLASS_NEWTYPE_STRUCT_NAME, &(name, classes))
+}
+
+// serialize_with function archiving a value given by decode the way
+// Foundation does: strings, data, dates, arrays and dictionaries are objects
+// of their own, numbers and booleans are stored inline. Dictionaries with a
+// $class name are archived as objects of that class, fields included.
+pub fn foundation<S: ser::Serializer>(
+    value: &plist::Value,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        plist::Value::Boolean(_) | plist::Value::Integer(_) | plist::Value::Real(_) => {
+            value.serialize(serializer)
+        }
+        x => object(&Instance(x), serializer),
+    }
+}
+
+// Field of an object archived by foundation
+struct Field<'a>(&'a plist::Value);
+
+// Element of a collection, always a reference to an object
+struct Member<'a>(&'a plist::Value);
+
+// Content of the object archiving a decoded value
+struct Instance<'a>(&'a plist::Value);
+
+struct Class<'a>(&'a str, &'a [&'a str]);
+
+impl Serialize for Field<'_> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        foundation(self.0, serializer)
+    }
+}
+
+impl Serialize for Member<'_> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        object(&Instance(self.0), serializer)
+    }
+}
+
+impl Serialize for Class<'_> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        class(self.0, self.1, serializer)
+    }
+}
+
+impl Serialize for Instance<'_> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use plist::Value;
+        use ser::SerializeMap;
+
+        match self.0 {
+            Value::Date(date) => {
+                let time = match std::time::SystemTime::from(*date)
+                    .duration_since(std::time::UNIX_EPOCH)
+                {
+                    Ok(x) => x.as_secs_f64(),
+                    Err(e) => -e.duration().as_secs_f64(),
+                };
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry("NS.time", &(time - APPLE_EPOCH as f64))?;
+                map.serialize_entry("$class", &Class("NSDate", &["NSDate", "NSObject"]))?;
+                map.end()
+            }
+            Value::Array(a) => {
+                let objects: Vec<_> = a.iter().map(Member).collect();
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry("NS.objects", &objects)?;
+                map.serialize_entry("$class", &Class("NSArray", &["NSArray", "NSObject"]))?;
+                map.end()
+            }
+            Value::Dictionary(d) => match d.get("$class").and_then(Value::as_string) {
+                Some(name) => {
+                    let mut map = serializer.serialize_map(Some(d.len()))?;
+                    for (key, value) in d.iter().filter(|x| x.0 != "$class") {
+                        map.serialize_entry(key, &Field(value))?;
+                    }
+                    map.serialize_entry("$class", &Class(name, &[name, "NSObject"]))?;
+                    map.end()
+                }
+                None => {
+                    let keys: Vec<_> = d.keys().map(|x| Value::String(x.clone())).collect();
+                    let keys: Vec<_> = keys.iter().map(Member).collect();
+                    let objects: Vec<_> = d.values().map(Member).collect();
+                    let class = Class("NSDictionary", &["NSDictionary", "NSObject"]);
+                    let mut map = serializer.serialize_map(Some(3))?;
+                    map.serialize_entry("NS.keys", &keys)?;
+                    map.serialize_entry("NS.objects", &objects)?;
+                    map.serialize_entry("$class", &class)?;
+                    map.end()
+                }
+            },
+            x => x.serialize(serializer),
+        }
+    }
+}
+
+pub fn to_value<T: Serialize>(root: &T) -> Result<plist::Value, Error> {
+    let mut objects = vec![plist::Value::from("$null")];
+    let root = Encoder(&mut objects).add_object(root)?;
+
+    let mut top = plist::Dictionary::new();
+    top.insert("root".to_owned(), root);
+
+    let mut archive = plist::Dictionary::new();
+    archive.insert("$version".to_owned(), 100000u64.into());
+    archive.insert("$archiver".to_owned(), "NSKeyedArchiver".into());
+    archive.insert("$top".to_owned(), top.into());
+    archive.insert("$objects".to_owned(), plist::Value::Array(objects));
+    Ok(archive.into())
+}
+
+pub fn to_bytes<T: Serialize>(root: &T) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    to_value(root)?
+        .to_writer_binary(&mut out)
+        .map_err(|e| Error(e.to_string()))?;
+    Ok(out)
+}
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+fn unsupported(what: &str) -> Error {
+    Error(format!("Unable to archive {}", what))
+}
+
+// Serializes into the value stored inline, None for absent values
+struct Encoder<'a>(&'a mut Vec<plist::Value>);
+
+impl Encoder<'_> {
+    // Stores value in $objects, before any object it references
+    fn add_object<T: Serialize + ?Sized>(self, value: &T) -> Result<plist::Value, Error> {
+        let index = self.0.len();
+        self.0.push(plist::Value::Boolean(false));
+        match value.serialize(Encoder(&mut *self.0))? {
+            Some(value) => {
+                self.0[index] = value;
+                Ok(plist::Value::Uid(plist::Uid::new(index as u64)))
+            }
+            None => {
+                self.0.pop();
+                Ok(plist::Value::Uid(plist::Uid::new(0)))
+            }
+        }
+    }
+
+    // Reference to the class description of name, added if not yet archived
+    fn class_uid(self, name: &str, classes: Vec<plist::Value>) -> plist::Value {
+        let existing = self.0.iter().position(|x| {
+            x.as_dictionary()
+                .and_then(|x| x.get("$classname"))
+                .and_then(plist::Value::as_string)
+                == Some(name)
+        });
+        let index = existing.unwrap_or_else(|| {
+            let mut class = plist::Dictionary::new();
+            class.insert("$classname".to_owned(), name.into());
+            class.insert("$classes".to_owned(), plist::Value::Array(classes));
+            self.0.push(class.into());
+            self.0.len() - 1
+        });
+        plist::Value::Uid(plist::Uid::new(index as u64))
+    }
+}
+
+impl<'a> ser::Serializer for Encoder<'a> {
+    type Ok = Option<plist::Value>;
+    type Error = Error;
+    type SerializeSeq = SeqEncoder<'a>;
+    type SerializeTuple = SeqEncoder<'a>;
+    type SerializeTupleStruct = SeqEncoder<'a>;
+    type SerializeTupleVariant = ser::Impossible<Self::Ok, Error>;
+    type SerializeMap = MapEncoder<'a>;
+    type SerializeStruct = StructEncoder<'a>;
+    type SerializeStructVariant = ser::Impossible<Self::Ok, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Error> {
+        Ok(Some(v.into()))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Error> {
+        Ok(Some(v.into()))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Error> {
+        Ok(Some(v.into()))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Error> {
+        Ok(Some(v.into()))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Error> {
+        Ok(Some(v.to_string().into()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Error> {
+        Ok(Some(v.into()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Error> {
+        Ok(Some(plist::Value::Data(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        match name {
+            OBJECT_NEWTYPE_STRUCT_NAME => self.add_object(value).map(Some),
+            CLASS_NEWTYPE_STRUCT_NAME => match value.serialize(Encoder(&mut *self.0))? {
+                Some(plist::Value::Array(mut x)) => match (x.pop(), x.pop()) {
+                    (Some(plist::Value::Array(classes)), Some(plist::Value::String(name))) => {
+                        Ok(Some(self.class_uid(&name, classes)))
+                    }
+                    _ => Err(unsupported("class")),
+                },
+                _ => Err(unsupported("class")),
+            },
+            UID_NEWTYPE_STRUCT_NAME => match value.serialize(self)? {
+                Some(plist::Value::Integer(x)) => Ok(Some(plist::Value::Uid(plist::Uid::new(
+                    x.as_unsigned().ok_or_else(|| unsupported("negative UID"))?,
+                )))),
+                _ => Err(unsupported("UID")),
+            },
+            _ => value.serialize(self),
+        }
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok, Error> {
+        Err(unsupported(&format!("enum {}::{}", name, variant)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Ok(SeqEncoder(self.0, Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(unsupported(&format!("enum {}::{}", name, variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Ok(MapEncoder(self.0, plist::Dictionary::new(), None))
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Ok(StructEncoder(self.0, plist::Dictionary::new(), name))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(unsupported(&format!("enum {}::{}", name, variant)))
+    }
+}
+
+struct SeqEncoder<'a>(&'a mut Vec<plist::Value>, Vec<plist::Value>);
+
+impl SeqEncoder<'_> {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let value = value
+            .serialize(Encoder(&mut *self.0))?
+            .ok_or_else(|| unsupported("absent value in a sequence"))?;
+        self.1.push(value);
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SeqEncoder<'_> {
+    type Ok = Option<plist::Value>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(plist::Value::Array(self.1)))
+    }
+}
+
+impl ser::SerializeTuple for SeqEncoder<'_> {
+    type Ok = Option<plist::Value>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqEncoder<'_> {
+    type Ok = Option<plist::Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct MapEncoder<'a>(&'a mut Vec<plist::Value>, plist::Dictionary, Option<String>);
+
+impl ser::SerializeMap for MapEncoder<'_> {
+    type Ok = Option<plist::Value>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        match key.serialize(Encoder(&mut *self.0))? {
+            Some(plist::Value::String(key)) => {
+                self.2 = Some(key);
+                Ok(())
+            }
+            _ => Err(unsupported("map with keys other than strings")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.2.take().unwrap();
+        if let Some(value) = value.serialize(Encoder(&mut *self.0))? {
+            self.1.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(self.1.into()))
+    }
+}
+
+struct StructEncoder<'a>(&'a mut Vec<plist::Value>, plist::Dictionary, &'static str);
+
+impl ser::SerializeStruct for StructEncoder<'_> {
+    type Ok = Option<plist::Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        if let Some(value) = value.serialize(Encoder(&mut *self.0))? {
+            self.1.insert(key.to_owned(), value);
+        }
+        Ok(())
+    }
+
+    fn end(mut self) -> Result<Self::Ok, Error> {
+        if !self.1.contains_key("$class") {
+            let classes = vec![self.2.into(), "NSObject".into()];
+            let class = Encoder(&mut *self.0).class_uid(self.2, classes);
+            self.1.insert("$class".to_owned(), class);
+        }
+        Ok(Some(self.1.into()))
+    }
+}
+
+// Decodes the root object of an archive of any classes into a plist::Value.
+//
+// Foundation collections, strings, data, dates, UUIDs, URLs and attributed
+// strings become the matching plist values; other objects become dictionaries
+// of their decoded fields with their class name under "$class". An object
+// shared by several others is decoded again for each of them, and a reference
+// to an object still being decoded (a cycle) is left as {"CF$UID": n}, the
+// way XML plists write UIDs. Archives without a root give a dictionary of all
+// their top level objects.
+pub fn decode(archive: &plist::Value) -> Result<plist::Value, Error> {
+    let archive = archive
+        .as_dictionary()
+        .ok_or_else(|| Error("Expected NSKeyedArchive to be Dictionary".to_owned()))?;
+    let version = archive
+        .get("$version")
+        .and_then(plist::Value::as_unsigned_integer);
+    if version != Some(100000) {
+        return Err(Error(format!("Incorrect version: {:?}", version)));
+    }
+    let archiver = archive.get("$archiver").and_then(plist::Value::as_string);
+    if archiver != Some("NSKeyedArchiver") {
+        return Err(Error(format!("Incorrect archiver: {:?}", archiver)));
+    }
+    let objects = archive
+        .get("$objects")
+        .and_then(plist::Value::as_array)
+        .ok_or_else(|| Error("Expected $objects to be Array".to_owned()))?;
+    let top = archive
+        .get("$top")
+        .and_then(plist::Value::as_dictionary)
+        .ok_or_else(|| Error("Expected $top to be Dictionary".to_owned()))?;
+
+    let mut decoder = Decoder {
+        objects,
+        done: HashMap::new(),
+        pending: Vec::new(),
+        cycles: 0,
+    };
+    if let Some(root) = top.get("root") {
+        return decoder.value(root);
+    }
+    let mut out = plist::Dictionary::new();
+    for (key, value) in top {
+        out.insert(key.clone(), decoder.value(value)?);
+    }
+    Ok(out.into())
+}
+
+pub fn decode_bytes(data: &[u8]) -> Result<plist::Value, Error> {
+    decode(&plist::from_bytes(data).map_err(|e| Error(e.to_string()))?)
+}
+
+// Reads a decoded archive into T, Foundation objects being their plist values
+pub fn from_value<T: serde::de::DeserializeOwned>(value: &plist::Value) -> Result<T, Error> {
+    plist::from_value(value).map_err(|e| {
+        Error(format!(
+            "Error parsing {}: {}",
+            std::any::type_name::<T>(),
+            e
+        ))
+    })
+}
+
+struct Decoder<'a> {
+    objects: &'a [plist::Value],
+    // Objects already decoded, by index in $objects. Objects holding a cycle
+    // are left out, as the reference breaking it depends on where decoding
+    // started.
+    done: HashMap<u64, plist::Value>,
+    // Objects being decoded, from the root down
+    pending: Vec<u64>,
+    // References to pending objects left in place so far
+    cycles: usize,
+}
+
+impl Decoder<'_> {
+    // Decodes a value stored inline, following UIDs to their objects
+    fn value(&mut self, value: &plist::Value) -> Result<plist::Value, Error> {
+        match value {
+            plist::Value::Uid(uid) => self.object(uid.get()),
+            plist::Value::Array(a) => Ok(plist::Value::Array(
+                a.iter().map(|x| self.value(x)).collect::<Result<_, _>>()?,
+            )),
+            plist::Value::Dictionary(d) => {
+                let mut out = plist::Dictionary::new();
+                for (key, value) in d {
+                    out.insert(key.clone(), self.value(value)?);
+                }
+                Ok(out.into())
+            }
+            x => Ok(x.clone()),
+        }
+    }
+
+    fn object(&mut self, uid: u64) -> Result<plist::Value, Error> {
+        if let Some(x) = self.done.get(&uid) {
+            return Ok(x.clone());
+        }
+        if self.pending.contains(&uid) {
+            self.cycles += 1;
+            let mut reference = plist::Dictionary::new();
+            reference.insert("CF$UID".to_owned(), uid.into());
+            return Ok(reference.into());
+        }
+        let object = self
+            .objects
+            .get(uid as usize)
+            .ok_or_else(|| Error(format!("Expected objects[{}] to exist", uid)))?;
+
+        let cycles = self.cycles;
+        self.pending.push(uid);
+        let decoded = match object.as_dictionary() {
+            Some(fields) if fields.contains_key("$class") => self.instance(fields),
+            _ => self.value(object),
+        };
+        self.pending.pop();
+
+        let decoded = decoded?;
+        if self.cycles == cycles {
+            self.done.insert(uid, decoded.clone());
+        }
+        Ok(decoded)
+    }
+
+    // Field of an object, None when absent or $null
+    fn field(
+        &mut self,
+        fields: &plist::Dictionary,
+        key: &str,
+    ) -> Result<Option<plist::Value>, Error> {
+        match fields.get(key) {
+            None => Ok(None),
+            Some(plist::Value::Uid(uid)) if uid.get() == 0 => Ok(None),
+            Some(x) => self.value(x).map(Some),
+        }
+    }
+
+    fn string_field(&mut self, fields: &plist::Dictionary, key: &str) -> Result<String, Error> {
+        match self.field(fields, key)? {
+            Some(plist::Value::String(s)) => Ok(s),
+            None => Ok(String::new()),
+            x => Err(Error(format!("Expected {} to be String got {:?}", key, x))),
+        }
+    }
+
+    fn array_field(
+        &mut self,
+        fields: &plist::Dictionary,
+        key: &str,
+    ) -> Result<Vec<plist::Value>, Error> {
+        match self.field(fields, key)? {
+            Some(plist::Value::Array(a)) => Ok(a),
+            None => Ok(Vec::new()),
+            x => Err(Error(format!("Expected {} to be Array got {:?}", key, x))),
+        }
+    }
+
+    fn instance(&mut self, fields: &plist::Dictionary) -> Result<plist::Value, Error> {
+        let class = fields["$class"]
+            .as_uid()
+            .and_then(|x| self.objects.get(x.get() as usize))
+            .and_then(|x| x.as_dictionary()?.get("$classname")?.as_string())
+            .ok_or_else(|| Error("Expected NSKeyedObject.$class to be a class".to_owned()))?;
+
+        match class {
+            "NSDictionary" | "NSMutableDictionary" => {
+                let keys = self.array_field(fields, "NS.keys")?;
+                let values = self.array_field(fields, "NS.objects")?;
+                let mut out = plist::Dictionary::new();
+                for (key, value) in keys.into_iter().zip(values) {
+                    out.insert(key_string(key), value);
+                }
+                Ok(out.into())
+            }
+            "NSArray"
+            | "NSMutableArray"
+            | "NSSet"
+            | "NSMutableSet"
+            | "NSOrderedSet"
+            | "NSMutableOrderedSet" => Ok(self.array_field(fields, "NS.objects")?.into()),
+            "NSString" | "NSMutableString" => Ok(self.string_field(fields, "NS.string")?.into()),
+            "NSData" | "NSMutableData" => match self.field(fields, "NS.data")? {
+                Some(plist::Value::Data(d)) => Ok(plist::Value::Data(d)),
+                x => Err(Error(format!("Expected NS.data to be Data got {:?}", x))),
+            },
+            "NSDate" => {
+                let time = fields
+                    .get("NS.time")
+                    .and_then(plist::Value::as_real)
+                    .ok_or_else(|| Error("Expected NS.time to be Real".to_owned()))?;
+                // Dates before 1970 are as valid as later ones
+                let since_epoch = time + APPLE_EPOCH as f64;
+                let invalid = |e| Error(format!("Invalid NS.time {}: {}", time, e));
+                let offset =
+                    std::time::Duration::try_from_secs_f64(since_epoch.abs()).map_err(invalid)?;
+                let date = if since_epoch < 0.0 {
+                    std::time::SystemTime::UNIX_EPOCH.checked_sub(offset)
+                } else {
+                    std::time::SystemTime::UNIX_EPOCH.checked_add(offset)
+                };
+                let date = date.ok_or_else(|| Error(format!("Invalid NS.time {}", time)))?;
+                Ok(plist::Value::Date(date.into()))
+            }
+            "NSUUID" => match fields.get("NS.uuidbytes") {
+                Some(plist::Value::Data(d)) if d.len() == 16 => Ok(format_uuid(d).into()),
+                x => Err(Error(format!(
+                    "Expected NS.uuidbytes to be 16 bytes got {:?}",
+                    x
+                ))),
+            },
+            "NSURL" => {
+                // Relative URLs are appended to their base as is
+                let base = match self.field(fields, "NS.base")? {
+                    Some(plist::Value::String(s)) => s,
+                    _ => String::new(),
+                };
+                Ok((base + &self.string_field(fields, "NS.relative")?).into())
+            }
+            // Only the text is kept, not the attributes
+            "NSAttributedString" | "NSMutableAttributedString" => {
+                Ok(self.string_field(fields, "NSString")?.into())
+            }
+            _ => {
+                let mut out = plist::Dictionary::new();
+                out.insert("$class".to_owned(), class.into());
+                for key in fields.keys() {
+                    if key == "$class" {
+                        continue;
+                    }
+                    if let Some(value) = self.field(fields, key)? {
+                        out.insert(key.clone(), value);
+                    }
+                }
+                Ok(out.into())
+            }
+        }
+    }
+}
+
+// Upper case hex in the 8-4-4-4-12 layout of NSUUID descriptions
+pub fn format_uuid(data: &[u8]) -> String {
+    let hex = crate::util::hex(data).to_uppercase();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+// Dictionary keys are almost always strings, anything else is written out
+fn key_string(key: plist::Value) -> String {
+    match key {
+        plist::Value::String(s) => s,
+        plist::Value::Integer(i) => i.to_string(),
+        plist::Value::Real(r) => r.to_string(),
+        x => format!("{:?}", x),
+    }
+}
+
+// JSON form of a decoded value: data as hex, dates as RFC 3339
+pub fn to_json(value: &plist::Value) -> serde_json::Value {
+    use plist::Value;
+
+    match value {
+        Value::Array(a) => a.iter().map(to_json).collect(),
+        Value::Dictionary(d) => d
+            .iter()
+            .map(|(k, v)| (k.clone(), to_json(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        Value::Boolean(b) => (*b).into(),
+        Value::Data(d) => crate::util::hex(d).into(),
+        Value::Date(d) => chrono::DateTime::<chrono::Utc>::from(std::time::SystemTime::from(*d))
+            .to_rfc3339()
+            .into(),
+        Value::Real(r) => (*r).into(),
+        Value::Integer(i) => match i.as_signed() {
+            Some(x) => x.into(),
+            None => i.as_unsigned().unwrap().into(),
+        },
+        Value::String(s) => s.clone().into(),
+        Value::Uid(u) => serde_json::json!({ "CF$UID": u.get() }),
+        _ => serde_json::Value::Null,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use plist::{Dictionary, Uid, Value};
+
+    pub(crate) fn uid(x: u64) -> Value {
+        Value::Uid(Uid::new(x))
+    }
+
+    pub(crate) fn class(name: &str) -> Value {
+        let mut class = Dictionary::new();
+        class.insert("$classname".to_owned(), name.into());
+        class.insert(
+            "$classes".to_owned(),
+            Value::Array(vec![name.into(), "NSObject".into()]),
+        );
+        class.into()
+    }
+
+    pub(crate) fn instance(class: u64, fields: &[(&str, Value)]) -> Value {
+        let mut object = Dictionary::new();
+        object.insert("$class".to_owned(), uid(class));
+        for (key, value) in fields {
+            object.insert((*key).to_owned(), value.clone());
+        }
+        object.into()
+    }
+
+    // Archive of objects, following $null, whose root is the first of them
+    pub(crate) fn archive(objects: Vec<Value>) -> Value {
+        let mut top = Dictionary::new();
+        top.insert("root".to_owned(), uid(1));
+        let mut archive = Dictionary::new();
+        archive.insert("$version".to_owned(), 100000u64.into());
+        archive.insert("$archiver".to_owned(), "NSKeyedArchiver".into());
+        archive.insert("$top".to_owned(), top.into());
+        let mut all = vec![Value::from("$null")];
+        all.extend(objects);
+        archive.insert("$objects".to_owned(), Value::Array(all));
+        archive.into()
+    }
+
+    fn field<'a>(value: &'a Value, key: &str) -> &'a Value {
+        &value.as_dictionary().unwrap()[key]
+    }
+
+    #[test]
+    fn dates_before_1970() {
+        let date = |time: f64| {
+            let archive = archive(vec![
+                instance(2, &[("NS.time", Value::Real(time))]),
+                class("NSDate"),
+            ]);
+            std::time::SystemTime::from(decode(&archive).unwrap().as_date().unwrap())
+        };
+        let day = std::time::Duration::from_secs(86400);
+        assert_eq!(
+            date(-(APPLE_EPOCH as f64) - 86400.0),
+            std::time::UNIX_EPOCH - day
+        );
+        assert_eq!(date(-(APPLE_EPOCH as f64)), std::time::UNIX_EPOCH);
+        assert_eq!(
+            date(86400.0),
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(APPLE_EPOCH as u64) + day
+        );
+    }
+
+    #[test]
+    fn objects_in_a_cycle_are_decoded_where_used() {
+        // Root holds a and b, where a holds b and b holds a
+        let archive = archive(vec![
+            instance(4, &[("A", uid(2)), ("B", uid(3))]),
+            instance(4, &[("C", uid(3))]),
+            instance(4, &[("D", uid(2))]),
+            class("Thing"),
+        ]);
+        let root = decode(&archive).unwrap();
+
+        let a = field(&root, "A");
+        let reference = field(field(field(a, "C"), "D"), "CF$UID");
+        assert_eq!(reference.as_unsigned_integer(), Some(2));
+
+        // Decoding b from the root doesn't reuse the copy made inside a
+        let b = field(&root, "B");
+        let reference = field(field(field(b, "D"), "C"), "CF$UID");
+        assert_eq!(reference.as_unsigned_integer(), Some(3));
+    }
+
+    #[test]
+    fn shared_objects_are_decoded_once() {
+        let archive = archive(vec![
+            instance(3, &[("A", uid(2)), ("B", uid(2))]),
+            "shared".into(),
+            class("Thing"),
+        ]);
+        let root = decode(&archive).unwrap();
+        assert_eq!(field(&root, "A").as_string(), Some("shared"));
+        assert_eq!(field(&root, "B").as_string(), Some("shared"));
+    }
+
+    #[test]
+    fn typed_values() {
+        #[derive(serde::Deserialize)]
+        struct Thing {
+            #[serde(rename = "Name")]
+            name: String,
+            #[serde(rename = "Identifier")]
+            identifier: String,
+        }
+
+        let archive = archive(vec![
+            instance(4, &[("Name", uid(2)), ("Identifier", uid(3))]),
+            "thing".into(),
+            instance(5, &[("NS.uuidbytes", Value::Data((0..16).collect()))]),
+            class("Thing"),
+            class("NSUUID"),
+        ]);
+        let thing: Thing = from_value(&decode(&archive).unwrap()).unwrap();
+        assert_eq!(thing.name, "thing");
+        assert_eq!(thing.identifier, "00010203-0405-0607-0809-0A0B0C0D0E0F");
+    }
+}
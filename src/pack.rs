@@ -0,0 +1,421 @@
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    backup::{self, Backup, SnapshotPolicy},
+    enc_writer, manifest, manifestdb,
+    nska::format_uuid,
+    util::hex,
+};
+
+// Protection classes of the keybag of a new backup
+const CLASSES: std::ops::RangeInclusive<u32> = 1..=11;
+
+// NSFileProtectionNone, used for the Manifest.db key as iTunes does
+const MANIFEST_CLASS: u32 = 4;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+pub(crate) struct Options {
+    // Backup whose files and device information are copied before adding the tree
+    pub base: Option<Backup>,
+    // Rounds of the passphrase's first key derivation
+    pub iterations: u32,
+    // Class files of the tree are encrypted with
+    pub protection_class: u32,
+}
+
+// Creates an encrypted backup at output holding the files of source, a tree of
+// domain folders containing the files of each domain by relativePath
+pub(crate) fn pack(
+    source: &Path,
+    output: &Path,
+    password: &str,
+    options: Options,
+) -> Result<(), String> {
+    if output.exists() && output.read_dir().map_err(err)?.next().is_some() {
+        return Err(format!("Output is not empty: {}", output.display()));
+    }
+    std::fs::create_dir_all(output).map_err(err)?;
+
+    let (key_bag, class_keys) = new_key_bag(password, options.iterations)?;
+
+    let manifest_key = enc_writer::random_key().map_err(err)?;
+    let mut wrapped = [0u8; 40];
+    aes_kw::Kek::from(class_keys[&MANIFEST_CLASS])
+        .wrap(&manifest_key, &mut wrapped)
+        .map_err(err)?;
+    let mut manifest_key_data = MANIFEST_CLASS.to_le_bytes().to_vec();
+    manifest_key_data.extend_from_slice(&wrapped);
+
+    let now = plist::Value::Date(std::time::SystemTime::now().into());
+    let udid = hex(&random(20)?);
+    let base_manifest = options.base.as_ref().map(|b| &b.manifest);
+
+    let mut lockdown = plist::Dictionary::new();
+    for (key, value) in [
+        ("BuildVersion", "21A329"),
+        ("DeviceName", "iphonebackupfs"),
+        ("ProductType", "iPhone15,2"),
+        ("ProductVersion", "17.0"),
+        ("SerialNumber", "000000000000"),
+    ] {
+        lockdown.insert(key.to_owned(), value.into());
+    }
+    lockdown.insert("UniqueDeviceID".to_owned(), udid.into());
+
+    let mut manifest = plist::Dictionary::new();
+    manifest.insert(
+        "BackupKeyBag".to_owned(),
+        plist::Value::Data(key_bag.to_bytes()),
+    );
+    manifest.insert("Date".to_owned(), now.clone());
+    manifest.insert("IsEncrypted".to_owned(), true.into());
+    manifest.insert(
+        "ManifestKey".to_owned(),
+        plist::Value::Data(manifest_key_data),
+    );
+    match base_manifest {
+        Some(base) => {
+            manifest.insert("Version".to_owned(), base.version.clone().into());
+            manifest.insert(
+                "SystemDomainsVersion".to_owned(),
+                base.system_domains_version.clone().into(),
+            );
+            manifest.insert("WasPasscodeSet".to_owned(), base.was_passcode_set.into());
+            manifest.insert("Lockdown".to_owned(), base.lockdown.clone());
+            manifest.insert("Applications".to_owned(), base.applications.clone());
+        }
+        None => {
+            manifest.insert("Version".to_owned(), "10.0".into());
+            manifest.insert("SystemDomainsVersion".to_owned(), "24.0".into());
+            manifest.insert("WasPasscodeSet".to_owned(), false.into());
+            manifest.insert("Lockdown".to_owned(), lockdown.clone().into());
+            manifest.insert("Applications".to_owned(), plist::Dictionary::new().into());
+        }
+    }
+    plist::Value::from(manifest)
+        .to_file_binary(output.join("Manifest.plist"))
+        .map_err(err)?;
+
+    let mut status = plist::Dictionary::new();
+    status.insert("BackupState".to_owned(), "new".into());
+    status.insert("Date".to_owned(), now.clone());
+    status.insert("IsFullBackup".to_owned(), false.into());
+    status.insert("SnapshotState".to_owned(), "finished".into());
+    status.insert("UUID".to_owned(), format_uuid(&random(16)?).into());
+    status.insert("Version".to_owned(), "3.3".into());
+    plist::Value::from(status)
+        .to_file_binary(output.join("Status.plist"))
+        .map_err(err)?;
+
+    let base_info = options
+        .base
+        .as_ref()
+        .and_then(|b| plist::Value::from_file(b.path.join("Info.plist")).ok());
+    let info = match base_info {
+        Some(info) => info,
+        None => {
+            let mut info = plist::Dictionary::new();
+            for (key, value) in [
+                ("Build Version", "BuildVersion"),
+                ("Device Name", "DeviceName"),
+                ("Display Name", "DeviceName"),
+                ("Product Type", "ProductType"),
+                ("Product Version", "ProductVersion"),
+                ("Serial Number", "SerialNumber"),
+                ("Target Identifier", "UniqueDeviceID"),
+                ("Unique Identifier", "UniqueDeviceID"),
+            ] {
+                info.insert(key.to_owned(), lockdown[value].clone());
+            }
+            info.insert("GUID".to_owned(), hex(&random(16)?).to_uppercase().into());
+            info.insert("Last Backup Date".to_owned(), now);
+            info.insert("Target Type".to_owned(), "Device".into());
+            info.into()
+        }
+    };
+    info.to_file_xml(output.join("Info.plist")).map_err(err)?;
+
+    // Empty database, filled in through the writable vfs so no plaintext reaches the disk
+    std::fs::write(
+        output.join("Manifest.db"),
+        enc_writer::encrypt(&manifest_key, &[]),
+    )
+    .map_err(err)?;
+
+    let mut backup = Backup::open(output.to_owned(), password, SnapshotPolicy::Refuse)?;
+    backup.make_writable().map_err(err)?;
+    backup
+        .con
+        .execute_batch(
+            "CREATE TABLE Files (fileID TEXT PRIMARY KEY, domain TEXT, relativePath TEXT, flags INTEGER, file BLOB);
+            CREATE INDEX FilesDomainIdx ON Files(domain);
+            CREATE INDEX FilesRelativePathIdx ON Files(relativePath);
+            CREATE INDEX FilesFlagsIdx ON Files(flags);
+            CREATE TABLE Properties (key TEXT PRIMARY KEY, value BLOB);",
+        )
+        .map_err(err)?;
+
+    let tx = backup.con.unchecked_transaction().map_err(err)?;
+
+    if let Some(base) = &options.base {
+        eprintln!("** COPYING {}", base.path.display());
+        copy_backup(base, &backup, options.protection_class)?;
+    }
+
+    eprintln!("** PACKING {}", source.display());
+    let mut domains: Vec<_> = source
+        .read_dir()
+        .map_err(err)?
+        .collect::<Result<_, _>>()
+        .map_err(err)?;
+    domains.sort_by_key(|x| x.file_name());
+    for domain in domains {
+        let name = domain
+            .file_name()
+            .into_string()
+            .map_err(|x| format!("Domain name is not UTF-8: {}", x.to_string_lossy()))?;
+        add_tree(&backup, &name, "", &domain.path(), options.protection_class)?;
+    }
+
+    tx.commit().map_err(err)?;
+    Ok(())
+}
+
+// Copies every record of base, encrypting the files again with keys of backup.
+// Files of a class the new keybag has no key for are given default_class.
+fn copy_backup(base: &Backup, backup: &Backup, default_class: u32) -> Result<(), String> {
+    let mut sta = base
+        .con
+        .prepare("SELECT fileID, domain, relativePath, flags, file FROM Files")
+        .map_err(err)?;
+    let rows = sta
+        .query_map((), |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, i64>(3)?,
+                r.get::<_, manifestdb::MBFile>(4)?,
+            ))
+        })
+        .map_err(err)?;
+
+    for row in rows {
+        let (id, domain, path, flags, mut mbfile) = row.map_err(err)?;
+        if flags == 1 {
+            let data = match mbfile.encryption_key {
+                None if mbfile.size == 0 => Vec::new(),
+                _ => base.read_file(&id, &mbfile).ok_or_else(|| {
+                    format!("Can't read {}-{} from the base backup", domain, path)
+                })?,
+            };
+            if !CLASSES.contains(&(mbfile.protection_class as u32)) {
+                eprintln!(
+                    "warning: unknown_protection_class file_id={} class={} replacement={}",
+                    id, mbfile.protection_class, default_class
+                );
+                mbfile.protection_class = default_class as u64;
+            }
+            let times = (mbfile.last_modified, mbfile.last_status_change);
+            backup.write_blob(&id, &mut mbfile, &data).map_err(err)?;
+            (mbfile.last_modified, mbfile.last_status_change) = times;
+        }
+        backup
+            .save_record(&id, &domain, &path, flags, &mbfile)
+            .map_err(err)?;
+    }
+    Ok(())
+}
+
+// Adds the file, folder or symlink at source as domain/path, replacing the
+// content of an existing record but keeping its other fields
+fn add_tree(
+    backup: &Backup,
+    domain: &str,
+    path: &str,
+    source: &Path,
+    protection_class: u32,
+) -> Result<(), String> {
+    let meta = source.symlink_metadata().map_err(err)?;
+    let id = backup::file_id(domain, path);
+
+    let existing = match backup.record(&id) {
+        Ok((_, _, _, mbfile)) => Some(mbfile),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(err(e)),
+    };
+    let is_new = existing.is_none();
+    let mut mbfile =
+        existing.unwrap_or_else(|| manifestdb::MBFile::new(path, 0, meta.ctime() as u64));
+    mbfile.mode = meta.mode() as u64;
+
+    let flags = if meta.is_dir() {
+        2
+    } else if meta.mode() & S_IFMT == S_IFLNK {
+        let target = std::fs::read_link(source).map_err(err)?;
+        mbfile.target = Some(target.to_string_lossy().into_owned());
+        4
+    } else {
+        if is_new {
+            mbfile.protection_class = protection_class as u64;
+        }
+        let data = std::fs::read(source).map_err(err)?;
+        backup.write_blob(&id, &mut mbfile, &data).map_err(err)?;
+        1
+    };
+
+    mbfile.last_modified = meta.mtime() as u64;
+    mbfile.last_status_change = meta.ctime() as u64;
+    backup
+        .save_record(&id, domain, path, flags, &mbfile)
+        .map_err(err)?;
+
+    if meta.is_dir() {
+        let mut children: Vec<PathBuf> = source
+            .read_dir()
+            .map_err(err)?
+            .map(|x| x.map(|x| x.path()))
+            .collect::<Result<_, _>>()
+            .map_err(err)?;
+        children.sort();
+        for child in children {
+            let name = child
+                .file_name()
+                .unwrap()
+                .to_str()
+                .ok_or_else(|| format!("File name is not UTF-8: {}", child.display()))?;
+            let child_path = if path.is_empty() {
+                name.to_owned()
+            } else {
+                format!("{}/{}", path, name)
+            };
+            add_tree(backup, domain, &child_path, &child, protection_class)?;
+        }
+    }
+    Ok(())
+}
+
+// Keybag with a random key per protection class, wrapped with a key derived from
+// the password like the ones of iOS 10.2 and later
+fn new_key_bag(
+    password: &str,
+    iterations: u32,
+) -> Result<(manifest::KeyBag, std::collections::BTreeMap<u32, [u8; 32]>), String> {
+    let salt = random(20)?;
+    let dpsl = random(20)?;
+    let iter = 10000;
+
+    let mut round1 = [0u8; 32];
+    let mut kek = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password.as_bytes(), &dpsl, iterations, &mut round1);
+    pbkdf2::pbkdf2_hmac::<sha1::Sha1>(&round1, &salt, iter, &mut kek);
+    let kek = aes_kw::Kek::from(kek);
+
+    let mut class_keys = std::collections::BTreeMap::new();
+    let mut others = Vec::new();
+    for clas in CLASSES {
+        let key = enc_writer::random_key().map_err(err)?;
+        let mut wpky = vec![0u8; 40];
+        kek.wrap(&key, &mut wpky).map_err(err)?;
+        class_keys.insert(clas, key);
+        others.push(manifest::KeyBagClass {
+            uuid: random(16)?.try_into().unwrap(),
+            clas,
+            wrap: 2,
+            ktyp: 0,
+            wpky,
+        });
+    }
+
+    let key_bag = manifest::KeyBag {
+        vers: 3,
+        ktype: 1,
+        uuid: random(16)?.try_into().unwrap(),
+        hmck: random(40)?,
+        wrap: 0,
+        salt,
+        iter,
+        dpwt: 1,
+        dpic: iterations,
+        dpsl,
+        others,
+    };
+    Ok((key_bag, class_keys))
+}
+
+fn random(len: usize) -> Result<Vec<u8>, String> {
+    let mut out = vec![0; len];
+    enc_writer::random_bytes(&mut out).map_err(err)?;
+    Ok(out)
+}
+
+fn err(e: impl std::fmt::Display) -> String {
+    e.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::backup::{
+        tests::{pack_files, TempDir},
+        SnapshotPolicy,
+    };
+
+    #[test]
+    fn tree_is_packed_over_a_base_backup() {
+        let dir = TempDir::new("pack-base");
+        let mut base = pack_files(
+            &dir,
+            &[
+                ("HomeDomain", "a.txt", b"one"),
+                ("HomeDomain", "b.txt", b"two"),
+                ("HomeDomain", "c.txt", b"three"),
+            ],
+        );
+        base.make_writable().unwrap();
+        let id = backup::file_id("HomeDomain", "c.txt");
+        let (domain, path, flags, mut mbfile) = base.record(&id).unwrap();
+        mbfile.protection_class = 0;
+        base.save_record(&id, &domain, &path, flags, &mbfile)
+            .unwrap();
+
+        let source = dir.0.join("overlay");
+        std::fs::create_dir_all(source.join("HomeDomain")).unwrap();
+        std::fs::write(source.join("HomeDomain/b.txt"), b"new").unwrap();
+        std::fs::write(source.join("HomeDomain/d.txt"), b"four").unwrap();
+        let output = dir.0.join("packed");
+        let options = Options {
+            base: Some(base),
+            iterations: 1,
+            protection_class: 2,
+        };
+        pack(&source, &output, "pw", options).unwrap();
+
+        let backup = Backup::open(output, "pw", SnapshotPolicy::Refuse).unwrap();
+        let files = backup
+            .records()
+            .into_iter()
+            .filter(|(_, _, _, flags, _)| *flags == 1)
+            .map(|(id, _, path, _, mbfile)| {
+                let data = backup.read_file(&id, &mbfile).unwrap();
+                (path, (mbfile.protection_class, data))
+            })
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            files,
+            BTreeMap::from([
+                ("a.txt".to_owned(), (3, b"one".to_vec())),
+                ("b.txt".to_owned(), (3, b"new".to_vec())),
+                ("c.txt".to_owned(), (2, b"three".to_vec())),
+                ("d.txt".to_owned(), (2, b"four".to_vec())),
+            ])
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{
+    backup::{file_id, Backup},
+    sqliteview::{has_column, Snapshot},
+    util::{csv_line, APPLE_EPOCH},
+};
+
+const DOMAIN: &str = "CameraRollDomain";
+const PHOTOS_PATH: &str = "Media/PhotoData/Photos.sqlite";
+
+// ZKIND of albums made by the user
+const USER_ALBUM: i64 = 2;
+
+// Latitude and longitude of assets without a location
+const NO_LOCATION: f64 = -180.0;
+
+// A photo or video of the library
+#[derive(Debug)]
+pub(crate) struct Asset {
+    // relativePath in CameraRollDomain, under Media/DCIM
+    pub path: String,
+    pub id: String,
+    // Name it was imported or taken with, else its name in the backup
+    pub filename: String,
+    // Seconds since the epoch
+    pub created: Option<i64>,
+    pub favorite: bool,
+    pub hidden: bool,
+    // In Recently Deleted
+    pub deleted: bool,
+    pub location: Option<(f64, f64)>,
+    // Titles of the user albums holding it
+    pub albums: Vec<String>,
+    // Copy in the export, relative to it, None if it isn't in the backup
+    pub exported: Option<String>,
+}
+
+const COLUMNS: [&str; 11] = [
+    "fileID",
+    "relativePath",
+    "originalFilename",
+    "created",
+    "favorite",
+    "hidden",
+    "deleted",
+    "latitude",
+    "longitude",
+    "albums",
+    "exported",
+];
+
+impl Asset {
+    // Values in the order of COLUMNS, albums one per line
+    fn columns(&self) -> [String; 11] {
+        [
+            self.id.clone(),
+            self.path.clone(),
+            self.filename.clone(),
+            self.created
+                .and_then(|x| chrono::DateTime::from_timestamp(x, 0))
+                .map(|x| x.to_rfc3339())
+                .unwrap_or_default(),
+            self.favorite.to_string(),
+            self.hidden.to_string(),
+            self.deleted.to_string(),
+            self.location.map(|x| x.0.to_string()).unwrap_or_default(),
+            self.location.map(|x| x.1.to_string()).unwrap_or_default(),
+            self.albums.join("\n"),
+            self.exported.clone().unwrap_or_default(),
+        ]
+    }
+
+    // Folder of the export it goes in, by year and month of creation
+    fn folder(&self) -> PathBuf {
+        let state = if self.deleted {
+            "deleted"
+        } else if self.hidden {
+            "hidden"
+        } else {
+            "dates"
+        };
+        let date = self
+            .created
+            .and_then(|x| chrono::DateTime::from_timestamp(x, 0))
+            .map(|x| x.format("%Y/%m").to_string())
+            .unwrap_or_else(|| "undated".to_owned());
+        Path::new(state).join(date)
+    }
+}
+
+// Every asset of Photos.sqlite in order of creation
+pub(crate) fn assets(backup: &Backup) -> Result<Vec<Asset>, String> {
+    let con = backup.open_database(&file_id(DOMAIN, PHOTOS_PATH), Snapshot::Latest)?;
+    read_assets(&con).map_err(|e| e.to_string())
+}
+
+fn read_assets(con: &Connection) -> rusqlite::Result<Vec<Asset>> {
+    // ZASSET was ZGENERICASSET before iOS 14
+    let table = if has_column(con, "ZASSET", "Z_PK")? {
+        "ZASSET"
+    } else {
+        "ZGENERICASSET"
+    };
+    let trashed = if has_column(con, table, "ZTRASHEDSTATE")? {
+        "a.ZTRASHEDSTATE"
+    } else {
+        "0"
+    };
+    let mut sta = con.prepare(&format!(
+        "SELECT a.Z_PK, a.ZDIRECTORY, a.ZFILENAME, x.ZORIGINALFILENAME, a.ZDATECREATED, \
+         a.ZFAVORITE, a.ZHIDDEN, {}, a.ZLATITUDE, a.ZLONGITUDE FROM {} a \
+         LEFT JOIN ZADDITIONALASSETATTRIBUTES x ON x.ZASSET = a.Z_PK \
+         WHERE a.ZDIRECTORY IS NOT NULL AND a.ZFILENAME IS NOT NULL \
+         ORDER BY a.ZDATECREATED, a.Z_PK",
+        trashed, table
+    ))?;
+    let mut rows = sta.query(())?;
+
+    let mut albums = albums(con)?;
+    let mut assets = Vec::new();
+    while let Some(r) = rows.next()? {
+        let pk: i64 = r.get(0)?;
+        let directory: String = r.get(1)?;
+        let name: String = r.get(2)?;
+        let path = format!("Media/{}/{}", directory, name);
+        let latitude: Option<f64> = r.get(8)?;
+        let longitude: Option<f64> = r.get(9)?;
+        assets.push(Asset {
+            id: file_id(DOMAIN, &path),
+            path,
+            filename: path_component(
+                &r.get::<_, Option<String>>(3)?
+                    .filter(|x| !x.is_empty())
+                    .unwrap_or(name),
+            ),
+            created: r
+                .get::<_, Option<f64>>(4)?
+                .map(|x| x.floor() as i64 + APPLE_EPOCH),
+            favorite: r.get::<_, Option<bool>>(5)?.unwrap_or_default(),
+            hidden: r.get::<_, Option<bool>>(6)?.unwrap_or_default(),
+            deleted: r.get::<_, Option<i64>>(7)?.unwrap_or_default() != 0,
+            location: latitude
+                .zip(longitude)
+                .filter(|x| x.0 != NO_LOCATION && x.1 != NO_LOCATION),
+            albums: albums.remove(&pk).unwrap_or_default(),
+            exported: None,
+        });
+    }
+    Ok(assets)
+}
+
+// Titles of the user albums of each asset. Albums and assets are joined by a
+// Z_<n>ASSETS table whose name and columns change between iOS versions.
+fn albums(con: &Connection) -> rusqlite::Result<BTreeMap<i64, Vec<String>>> {
+    let join = con
+        .query_row(
+            "SELECT m.name, a.name, b.name FROM sqlite_master m, pragma_table_info(m.name) a, \
+             pragma_table_info(m.name) b WHERE m.type = 'table' \
+             AND m.name LIKE 'Z\\_%ASSETS' ESCAPE '\\' AND a.name LIKE 'Z\\_%ALBUMS' ESCAPE '\\' \
+             AND b.name LIKE 'Z\\_%ASSETS' ESCAPE '\\' AND b.name NOT LIKE 'Z\\_FOK\\_%' ESCAPE '\\'",
+            (),
+            |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?, r.get::<_, String>(2)?)),
+        )
+        .optional()?;
+    let Some((table, album, asset)) = join else {
+        return Ok(BTreeMap::new());
+    };
+
+    let trashed = if has_column(con, "ZGENERICALBUM", "ZTRASHEDSTATE")? {
+        "coalesce(g.ZTRASHEDSTATE, 0)"
+    } else {
+        "0"
+    };
+    let mut sta = con.prepare(&format!(
+        "SELECT j.{}, g.ZTITLE FROM {} j JOIN ZGENERICALBUM g ON g.Z_PK = j.{} \
+         WHERE g.ZKIND = ? AND g.ZTITLE IS NOT NULL AND {} = 0 ORDER BY g.ZTITLE",
+        asset, table, album, trashed
+    ))?;
+    let mut albums: BTreeMap<i64, Vec<String>> = BTreeMap::new();
+    let mut rows = sta.query([USER_ALBUM])?;
+    while let Some(r) = rows.next()? {
+        albums.entry(r.get(0)?).or_default().push(r.get(1)?);
+    }
+    Ok(albums)
+}
+
+// Copies the assets into output under dates/<year>/<month> by their original
+// name, or hidden/ and deleted/ for those hidden or recently deleted, with
+// their creation time as modification time. Assets of user albums are linked
+// into albums/<title>, and photos.csv lists every asset.
+pub(crate) fn export(backup: &Backup, assets: &mut [Asset], output: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(output)?;
+    let mut used = HashSet::new();
+
+    for asset in assets.iter_mut() {
+        let data = backup
+            .record(&asset.id)
+            .ok()
+            .and_then(|(_, _, _, mbfile)| backup.read_file(&asset.id, &mbfile));
+        let Some(data) = data else {
+            eprintln!(
+                "warning: missing_asset file_id={} path={}",
+                asset.id, asset.path
+            );
+            continue;
+        };
+
+        let relative = unique(&mut used, &asset.folder(), &asset.filename);
+        let target = output.join(&relative);
+        std::fs::create_dir_all(target.parent().unwrap())?;
+        let mut file = std::fs::File::create(&target)?;
+        file.write_all(&data)?;
+        if let Some(created) = asset.created.and_then(|x| u64::try_from(x).ok()) {
+            file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(created))?;
+        }
+
+        if !asset.hidden && !asset.deleted {
+            for album in &asset.albums {
+                let folder = Path::new("albums").join(path_component(album));
+                let link = output.join(unique(&mut used, &folder, &asset.filename));
+                std::fs::create_dir_all(link.parent().unwrap())?;
+                if std::fs::hard_link(&target, &link).is_err() {
+                    std::fs::copy(&target, &link)?;
+                }
+            }
+        }
+        asset.exported = Some(relative.to_string_lossy().into_owned());
+    }
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(output.join("photos.csv"))?);
+    writeln!(out, "{}", csv_line(&COLUMNS))?;
+    for asset in assets.iter() {
+        writeln!(out, "{}", csv_line(&asset.columns()))?;
+    }
+    out.flush()
+}
+
+// Album titles and original filenames can hold anything, but only one path
+// component is made of each of them
+fn path_component(name: &str) -> String {
+    match name.replace(['/', '\0'], "_") {
+        x if x == "." || x == ".." => x.replace('.', "_"),
+        x => x,
+    }
+}
+
+// Path of name in folder not taken by another asset, numbered like
+// IMG_0001 (2).HEIC if needed
+fn unique(used: &mut HashSet<PathBuf>, folder: &Path, name: &str) -> PathBuf {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    (1..)
+        .map(|n| {
+            let name = match (n, extension) {
+                (1, _) => name.to_owned(),
+                (n, Some(extension)) => format!("{} ({}).{}", stem, n, extension),
+                (n, None) => format!("{} ({})", stem, n),
+            };
+            folder.join(name)
+        })
+        .find(|x| used.insert(x.clone()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Photos.sqlite of iOS 14 and later, or before it with ZGENERICASSET, other
+    // Z_<n>ASSETS numbers and no ZTRASHEDSTATE
+    fn photos(recent: bool) -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        let (table, join, album, asset, trashed) = match recent {
+            true => (
+                "ZASSET",
+                "Z_28ASSETS",
+                "Z_28ALBUMS",
+                "Z_3ASSETS",
+                ", ZTRASHEDSTATE INTEGER",
+            ),
+            false => (
+                "ZGENERICASSET",
+                "Z_26ASSETS",
+                "Z_26ALBUMS",
+                "Z_34ASSETS",
+                "",
+            ),
+        };
+        con.execute_batch(&format!(
+            "CREATE TABLE {table} (Z_PK INTEGER PRIMARY KEY, ZDIRECTORY VARCHAR, \
+                 ZFILENAME VARCHAR, ZDATECREATED TIMESTAMP, ZFAVORITE INTEGER, ZHIDDEN INTEGER, \
+                 ZLATITUDE FLOAT, ZLONGITUDE FLOAT{trashed});
+             CREATE TABLE ZADDITIONALASSETATTRIBUTES (Z_PK INTEGER PRIMARY KEY, ZASSET INTEGER, \
+                 ZORIGINALFILENAME VARCHAR);
+             CREATE TABLE ZGENERICALBUM (Z_PK INTEGER PRIMARY KEY, ZKIND INTEGER, \
+                 ZTITLE VARCHAR{trashed});
+             CREATE TABLE {join} ({album} INTEGER, {asset} INTEGER, Z_FOK_{asset} INTEGER);
+             INSERT INTO {table} (Z_PK, ZDIRECTORY, ZFILENAME, ZDATECREATED, ZFAVORITE, \
+                 ZHIDDEN, ZLATITUDE, ZLONGITUDE) VALUES
+                 (1, 'DCIM/100APPLE', 'IMG_0001.HEIC', 700000000.5, 1, 0, 52.5, 13.25),
+                 (2, 'DCIM/100APPLE', 'IMG_0002.MOV', 600000000, 0, 1, -180, -180),
+                 (3, 'DCIM/100APPLE', 'IMG_0003.JPG', NULL, NULL, NULL, NULL, NULL),
+                 (4, NULL, 'IMG_0004.JPG', 500000000, 0, 0, NULL, NULL);
+             INSERT INTO ZADDITIONALASSETATTRIBUTES VALUES
+                 (1, 1, 'holiday/beach.heic'), (2, 2, ''), (3, 3, NULL);
+             INSERT INTO ZGENERICALBUM (Z_PK, ZKIND, ZTITLE) VALUES
+                 (1, 2, 'Trip'), (2, 1000, 'Favorites'), (3, 2, 'Old'), (4, 2, 'Beach');
+             INSERT INTO {join} VALUES (1, 1, 1), (2, 1, 2), (3, 3, 1), (4, 1, 3);"
+        ))
+        .unwrap();
+        con
+    }
+
+    #[test]
+    fn assets_of_recent_versions() {
+        let con = photos(true);
+        con.execute_batch(
+            "UPDATE ZASSET SET ZTRASHEDSTATE = 1 WHERE Z_PK = 3;
+             UPDATE ZGENERICALBUM SET ZTRASHEDSTATE = 1 WHERE ZTITLE = 'Old';",
+        )
+        .unwrap();
+
+        let assets = read_assets(&con).unwrap();
+        let summary = assets
+            .iter()
+            .map(|x| (x.path.as_str(), x.filename.as_str(), x.hidden, x.deleted))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "Media/DCIM/100APPLE/IMG_0003.JPG",
+                    "IMG_0003.JPG",
+                    false,
+                    true
+                ),
+                (
+                    "Media/DCIM/100APPLE/IMG_0002.MOV",
+                    "IMG_0002.MOV",
+                    true,
+                    false
+                ),
+                (
+                    "Media/DCIM/100APPLE/IMG_0001.HEIC",
+                    "holiday_beach.heic",
+                    false,
+                    false
+                ),
+            ]
+        );
+
+        let photo = &assets[2];
+        assert_eq!(
+            photo.id,
+            file_id(DOMAIN, "Media/DCIM/100APPLE/IMG_0001.HEIC")
+        );
+        assert!(photo.favorite);
+        assert_eq!(photo.location, Some((52.5, 13.25)));
+        assert_eq!(photo.albums, ["Beach", "Trip"]);
+        assert_eq!(photo.columns()[3], "2023-03-08T20:26:40+00:00");
+        assert_eq!(photo.folder(), Path::new("dates/2023/03"));
+
+        assert_eq!(assets[1].location, None);
+        assert_eq!(assets[1].folder(), Path::new("hidden/2020/01"));
+        // Albums in Recently Deleted are left out
+        assert!(assets[0].albums.is_empty());
+        assert_eq!(assets[0].columns()[3], "");
+        assert_eq!(assets[0].folder(), Path::new("deleted/undated"));
+    }
+
+    #[test]
+    fn assets_of_older_versions() {
+        let con = photos(false);
+        let assets = read_assets(&con).unwrap();
+        let summary = assets
+            .iter()
+            .map(|x| (x.filename.as_str(), x.deleted, x.albums.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("IMG_0003.JPG", false, vec!["Old".to_owned()]),
+                ("IMG_0002.MOV", false, vec![]),
+                (
+                    "holiday_beach.heic",
+                    false,
+                    vec!["Beach".to_owned(), "Trip".to_owned()]
+                ),
+            ]
+        );
+
+        // Without a join table assets are in no album
+        con.execute_batch("DROP TABLE Z_26ASSETS").unwrap();
+        assert!(read_assets(&con)
+            .unwrap()
+            .iter()
+            .all(|x| x.albums.is_empty()));
+    }
+
+    #[test]
+    fn path_components() {
+        assert_eq!(path_component("IMG_0001.HEIC"), "IMG_0001.HEIC");
+        assert_eq!(path_component("../../etc/passwd"), ".._.._etc_passwd");
+        assert_eq!(path_component("a\0b"), "a_b");
+        assert_eq!(path_component("."), "_");
+        assert_eq!(path_component(".."), "__");
+        assert_eq!(path_component("..."), "...");
+    }
+}
@@ -0,0 +1,154 @@
+use crate::nska;
+
+const BPLIST_MAGIC: &[u8] = b"bplist00";
+
+// Readable copies of a plist, served next to it as name.xml and name.json
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum View {
+    Xml,
+    Json,
+}
+
+impl View {
+    pub const ALL: [View; 2] = [View::Xml, View::Json];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            View::Xml => "xml",
+            View::Json => "json",
+        }
+    }
+}
+
+pub(crate) fn is_plist_name(name: &str) -> bool {
+    name.ends_with(".plist")
+}
+
+pub(crate) fn has_magic(head: &[u8]) -> bool {
+    head.starts_with(BPLIST_MAGIC)
+}
+
+pub(crate) const MAGIC_LEN: usize = BPLIST_MAGIC.len();
+
+// Converts a plist, with the NSKeyedArchives found in it decoded
+pub(crate) fn convert(data: &[u8], view: View) -> Result<Vec<u8>, String> {
+    let value = expand(plist::from_bytes(data).map_err(|e| e.to_string())?);
+
+    let mut out = Vec::new();
+    match view {
+        View::Xml => value.to_writer_xml(&mut out).map_err(|e| e.to_string())?,
+        View::Json => serde_json::to_writer_pretty(&mut out, &nska::to_json(&value))
+            .map_err(|e| e.to_string())?,
+    }
+    out.push(b'\n');
+    Ok(out)
+}
+
+// Decodes NSKeyedArchives, whether inline or in data holding a binary plist
+fn expand(value: plist::Value) -> plist::Value {
+    use plist::Value;
+
+    match value {
+        Value::Dictionary(d) if is_archive(&d) => {
+            let archive = Value::Dictionary(d);
+            match nska::decode(&archive) {
+                Ok(decoded) => expand(decoded),
+                Err(_) => archive,
+            }
+        }
+        Value::Dictionary(d) => {
+            Value::Dictionary(d.into_iter().map(|(k, v)| (k, expand(v))).collect())
+        }
+        Value::Array(a) => Value::Array(a.into_iter().map(expand).collect()),
+        Value::Data(d) if has_magic(&d) => match plist::from_bytes::<Value>(&d) {
+            Ok(nested) => expand(nested),
+            Err(_) => Value::Data(d),
+        },
+        x => x,
+    }
+}
+
+fn is_archive(d: &plist::Dictionary) -> bool {
+    d.get("$archiver").and_then(plist::Value::as_string) == Some("NSKeyedArchiver")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nska::tests::{archive, class, instance, uid};
+    use plist::{Dictionary, Value};
+
+    fn binary(value: &Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        value.to_writer_binary(&mut out).unwrap();
+        out
+    }
+
+    fn plain() -> Value {
+        let mut d = Dictionary::new();
+        d.insert("count".to_owned(), 3.into());
+        d.insert("enabled".to_owned(), true.into());
+        d.insert(
+            "items".to_owned(),
+            Value::Array(vec!["a".into(), 1.5.into()]),
+        );
+        d.insert("token".to_owned(), Value::Data(vec![0xde, 0xad]));
+        d.into()
+    }
+
+    #[test]
+    fn plists_round_trip_through_xml() {
+        let data = binary(&plain());
+        assert!(has_magic(&data));
+
+        let xml = convert(&data, View::Xml).unwrap();
+        assert!(xml.starts_with(b"<?xml"));
+        assert_eq!(plist::from_bytes::<Value>(&xml).unwrap(), plain());
+    }
+
+    #[test]
+    fn plists_are_written_as_json() {
+        let json = convert(&binary(&plain()), View::Json).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({
+                "count": 3,
+                "enabled": true,
+                "items": ["a", 1.5],
+                "token": "dead",
+            })
+        );
+    }
+
+    #[test]
+    fn nested_archives_are_expanded() {
+        let inner = archive(vec![
+            instance(2, &[("name", uid(3))]),
+            class("Person"),
+            "Ann".into(),
+        ]);
+        let outer = archive(vec![
+            instance(2, &[("payload", uid(3))]),
+            class("Envelope"),
+            Value::Data(binary(&inner)),
+        ]);
+        let mut d = Dictionary::new();
+        d.insert("inline".to_owned(), outer);
+        d.insert(
+            "broken".to_owned(),
+            Value::Data(b"bplist00 not a plist".to_vec()),
+        );
+
+        let json = convert(&binary(&d.into()), View::Json).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({
+                "inline": {
+                    "$class": "Envelope",
+                    "payload": { "$class": "Person", "name": "Ann" },
+                },
+                "broken": crate::util::hex(b"bplist00 not a plist"),
+            })
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use std::io::Write;
+
+use rusqlite::{types::ValueRef, Connection, OptionalExtension};
+
+use crate::util::{csv_line, hex, SQLITE_MAGIC};
+
+// Extensions of the databases found in backups
+const EXTENSIONS: [&str; 5] = [".db", ".sqlite", ".sqlite3", ".sqlitedb", ".storedata"];
+
+// Exports of the tables of a database, served in a name.tables folder next to
+// it as table.csv and table.jsonl
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum View {
+    Csv,
+    JsonLines,
+}
+
+impl View {
+    pub const ALL: [View; 2] = [View::Csv, View::JsonLines];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            View::Csv => "csv",
+            View::JsonLines => "jsonl",
+        }
+    }
+}
+
+// State of a database read from a backup: with its -wal applied, as SQLite
+// would see it, or only what was checkpointed into the database file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Snapshot {
+    Latest,
+    BeforeWal,
+}
+
+impl Snapshot {
+    pub fn folder_extension(&self) -> &'static str {
+        match self {
+            Snapshot::Latest => "tables",
+            Snapshot::BeforeWal => "prewal.tables",
+        }
+    }
+}
+
+pub(crate) fn is_sqlite_name(name: &str) -> bool {
+    EXTENSIONS.iter().any(|x| name.ends_with(x))
+}
+
+pub(crate) fn has_magic(head: &[u8]) -> bool {
+    head.starts_with(SQLITE_MAGIC)
+}
+
+// Tables with a / in their name can't be served as a file and are left out
+pub(crate) fn tables(con: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut sta = con.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND instr(name, '/') = 0 \
+         ORDER BY name",
+    )?;
+    let tables = sta.query_map((), |r| r.get(0))?.collect();
+    tables
+}
+
+// Columns of app databases come and go between iOS versions
+pub(crate) fn has_column(con: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    con.query_row(
+        "SELECT 1 FROM pragma_table_info(?) WHERE name = ?",
+        [table, column],
+        |_| Ok(()),
+    )
+    .optional()
+    .map(|x| x.is_some())
+}
+
+// Every row of a table, as CSV with a header line or as one JSON object per row.
+// Blobs are written in hex.
+pub(crate) fn export(con: &Connection, table: &str, view: View) -> rusqlite::Result<Vec<u8>> {
+    let mut sta = con.prepare(&format!("SELECT * FROM \"{}\"", table.replace('"', "\"\"")))?;
+    let columns: Vec<String> = sta.column_names().into_iter().map(str::to_owned).collect();
+
+    let mut out = Vec::new();
+    if let View::Csv = view {
+        writeln!(out, "{}", csv_line(&columns)).unwrap();
+    }
+
+    let mut rows = sta.query(())?;
+    while let Some(row) = rows.next()? {
+        match view {
+            View::Csv => {
+                let fields = (0..columns.len())
+                    .map(|i| {
+                        Ok(match row.get_ref(i)? {
+                            ValueRef::Null => String::new(),
+                            ValueRef::Integer(x) => x.to_string(),
+                            ValueRef::Real(x) => x.to_string(),
+                            ValueRef::Text(x) => String::from_utf8_lossy(x).into_owned(),
+                            ValueRef::Blob(x) => hex(x),
+                        })
+                    })
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                writeln!(out, "{}", csv_line(&fields)).unwrap();
+            }
+            View::JsonLines => {
+                let mut object = serde_json::Map::new();
+                for (i, name) in columns.iter().enumerate() {
+                    let value = match row.get_ref(i)? {
+                        ValueRef::Null => serde_json::Value::Null,
+                        ValueRef::Integer(x) => x.into(),
+                        ValueRef::Real(x) => x.into(),
+                        ValueRef::Text(x) => String::from_utf8_lossy(x).into(),
+                        ValueRef::Blob(x) => hex(x).into(),
+                    };
+                    object.insert(name.clone(), value);
+                }
+                serde_json::to_writer(&mut out, &object).unwrap();
+                out.push(b'\n');
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute_batch(
+            "CREATE TABLE \"odd \"\"name\"\"\" (id INTEGER, note TEXT, score REAL, data BLOB);
+             INSERT INTO \"odd \"\"name\"\"\" VALUES
+                 (1, 'a, b', 0.5, X'00ff'),
+                 (2, 'say \"hi\"', NULL, NULL),
+                 (3, 'two' || char(10) || 'lines', -1.25, X'');",
+        )
+        .unwrap();
+        con
+    }
+
+    #[test]
+    fn tables_are_exported_as_csv() {
+        let csv = export(&database(), "odd \"name\"", View::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "id,note,score,data\n\
+             1,\"a, b\",0.5,00ff\n\
+             2,\"say \"\"hi\"\"\",,\n\
+             3,\"two\nlines\",-1.25,\n"
+        );
+    }
+
+    #[test]
+    fn tables_are_exported_as_json_lines() {
+        let jsonl = export(&database(), "odd \"name\"", View::JsonLines).unwrap();
+        let rows = String::from_utf8(jsonl)
+            .unwrap()
+            .lines()
+            .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                serde_json::json!({"id": 1, "note": "a, b", "score": 0.5, "data": "00ff"}),
+                serde_json::json!({"id": 2, "note": "say \"hi\"", "score": null, "data": null}),
+                serde_json::json!({"id": 3, "note": "two\nlines", "score": -1.25, "data": ""}),
+            ]
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{
+    backup::Backup,
+    manifestdb::MBFile,
+    util::{csv_line, format_time},
+};
+
+const S_IFMT: u64 = 0o170000;
+const S_IFDIR: u64 = 0o040000;
+const S_IFLNK: u64 = 0o120000;
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Format {
+    // TSK 3 bodyfile, one line per file, for mactime
+    Bodyfile,
+    // One line per distinct time of each file, sorted by time
+    Csv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bodyfile" => Ok(Format::Bodyfile),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("Unknown timeline format: {}", s)),
+        }
+    }
+}
+
+// Times are inclusive bounds in seconds since the epoch
+#[derive(Debug, Default)]
+pub(crate) struct Filter {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // Only these domains when not empty
+    pub domains: Vec<String>,
+}
+
+impl Filter {
+    fn matches_time(&self, time: u64) -> bool {
+        self.from.is_none_or(|x| time >= x) && self.to.is_none_or(|x| time <= x)
+    }
+
+    fn matches_domain(&self, domain: &str) -> bool {
+        self.domains.is_empty() || self.domains.iter().any(|x| x == domain)
+    }
+}
+
+// Seconds since the epoch, or an RFC 3339 time
+pub(crate) fn parse_time(s: &str) -> Result<u64, String> {
+    if let Ok(x) = s.parse() {
+        return Ok(x);
+    }
+    chrono::DateTime::parse_from_rfc3339(s)
+        .map_err(|e| format!("Invalid time {}: {}", s, e))
+        .and_then(|t| u64::try_from(t.timestamp()).map_err(|e| e.to_string()))
+}
+
+struct File {
+    name: String,
+    id: String,
+    file: MBFile,
+}
+
+impl File {
+    // ls style mode prefixed with the TSK name and meta types, as in r/rrw-r--r--
+    fn mode_string(&self) -> String {
+        let kind = match self.file.mode & S_IFMT {
+            S_IFDIR => 'd',
+            S_IFLNK => 'l',
+            _ => 'r',
+        };
+        let mut out = format!("{}/{}", kind, kind);
+        for shift in [6, 3, 0] {
+            let bits = self.file.mode >> shift;
+            out.push(if bits & 4 != 0 { 'r' } else { '-' });
+            out.push(if bits & 2 != 0 { 'w' } else { '-' });
+            out.push(if bits & 1 != 0 { 'x' } else { '-' });
+        }
+        out
+    }
+
+    // Modified, accessed (never recorded), changed and born flags for time
+    fn macb(&self, time: u64) -> String {
+        let flag = |x, c| if x == time { c } else { '.' };
+        [
+            flag(self.file.last_modified, 'm'),
+            '.',
+            flag(self.file.last_status_change, 'c'),
+            flag(self.file.birth, 'b'),
+        ]
+        .iter()
+        .collect()
+    }
+
+    // Distinct times set on the file, 0 meaning unset
+    fn times(&self) -> Vec<u64> {
+        let mut times = vec![
+            self.file.last_modified,
+            self.file.last_status_change,
+            self.file.birth,
+        ];
+        times.retain(|&x| x != 0);
+        times.sort();
+        times.dedup();
+        times
+    }
+}
+
+// Writes the timeline of the files and folders of a backup to stdout. A file
+// is kept when its domain matches and, for the bodyfile, any of its times is in
+// range; the CSV only holds times in range.
+pub(crate) fn timeline(backup: &Backup, format: Format, filter: &Filter) {
+    let files: Vec<File> = backup
+        .records()
+        .into_iter()
+        .filter(|(_, domain, ..)| filter.matches_domain(domain))
+        .map(|(id, domain, path, _, file)| File {
+            name: match path.as_str() {
+                "" => domain,
+                path => format!("{}/{}", domain, path),
+            },
+            id,
+            file,
+        })
+        .collect();
+
+    match format {
+        Format::Bodyfile => {
+            let ranged = filter.from.is_some() || filter.to.is_some();
+            for f in files {
+                // Files without any time set are only kept when no range is given
+                if ranged && !f.times().into_iter().any(|x| filter.matches_time(x)) {
+                    continue;
+                }
+                // MD5|name|inode|mode_as_string|UID|GID|size|atime|mtime|ctime|crtime
+                println!(
+                    "0|{}|{}|{}|{}|{}|{}|0|{}|{}|{}",
+                    f.name.replace('|', "\\|"),
+                    f.file.inode_number,
+                    f.mode_string(),
+                    f.file.user_i_d,
+                    f.file.group_i_d,
+                    f.file.size,
+                    f.file.last_modified,
+                    f.file.last_status_change,
+                    f.file.birth,
+                );
+            }
+        }
+        Format::Csv => {
+            let mut events: Vec<(u64, &File)> = files
+                .iter()
+                .flat_map(|f| f.times().into_iter().map(move |x| (x, f)))
+                .filter(|(x, _)| filter.matches_time(*x))
+                .collect();
+            events.sort_by(|a, b| (a.0, &a.1.name).cmp(&(b.0, &b.1.name)));
+
+            println!(
+                "{}",
+                csv_line(&[
+                    "Date",
+                    "Size",
+                    "Type",
+                    "Mode",
+                    "UID",
+                    "GID",
+                    "Meta",
+                    "File Name",
+                    "fileID"
+                ])
+            );
+            for (time, f) in events {
+                println!(
+                    "{}",
+                    csv_line(&[
+                        format_time(time),
+                        f.file.size.to_string(),
+                        f.macb(time),
+                        f.mode_string(),
+                        f.file.user_i_d.to_string(),
+                        f.file.group_i_d.to_string(),
+                        f.file.inode_number.to_string(),
+                        f.name.clone(),
+                        f.id.clone(),
+                    ])
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(mode: u64, times: [u64; 3]) -> File {
+        let mut file = MBFile::new("Library/a", mode, 0);
+        (file.last_modified, file.last_status_change, file.birth) = times.into();
+        File {
+            name: "HomeDomain/Library/a".to_owned(),
+            id: "aa".to_owned(),
+            file,
+        }
+    }
+
+    #[test]
+    fn modes_are_written_like_ls() {
+        assert_eq!(file(0o100644, [0; 3]).mode_string(), "r/rrw-r--r--");
+        assert_eq!(file(0o40755, [0; 3]).mode_string(), "d/drwxr-xr-x");
+        assert_eq!(file(0o120777, [0; 3]).mode_string(), "l/lrwxrwxrwx");
+        assert_eq!(file(0o100000, [0; 3]).mode_string(), "r/r---------");
+    }
+
+    #[test]
+    fn times_are_flagged_and_unset_ones_skipped() {
+        let f = file(0o100644, [20, 20, 10]);
+        assert_eq!(f.times(), [10, 20]);
+        assert_eq!(f.macb(20), "m.c.");
+        assert_eq!(f.macb(10), "...b");
+
+        let f = file(0o100644, [30, 0, 0]);
+        assert_eq!(f.times(), [30]);
+        assert_eq!(f.macb(30), "m...");
+        assert!(file(0o100644, [0; 3]).times().is_empty());
+    }
+
+    #[test]
+    fn times_are_parsed_as_seconds_or_rfc3339() {
+        assert_eq!(parse_time("1600000000"), Ok(1_600_000_000));
+        assert_eq!(parse_time("2020-09-13T12:26:40Z"), Ok(1_600_000_000));
+        assert_eq!(parse_time("2020-09-13T14:26:40+02:00"), Ok(1_600_000_000));
+        assert!(parse_time("1969-12-31T23:59:59Z").is_err());
+        assert!(parse_time("yesterday").is_err());
+    }
+}
@@ -0,0 +1,62 @@
+// Formatting shared by the commands and the mount
+
+// Seconds between the Unix epoch and 2001-01-01, the epoch of Apple's times
+pub(crate) const APPLE_EPOCH: i64 = 978_307_200;
+
+// Start of every SQLite database file
+pub(crate) const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+pub(crate) fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Path of a URI, with everything but unreserved characters and separators
+// percent-encoded
+pub(crate) fn percent_encode(path: &str) -> String {
+    let mut out = String::new();
+    for b in path.bytes() {
+        match b {
+            b'/' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b if b.is_ascii_alphanumeric() => out.push(b as char),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+// RFC 3339 time of seconds since the epoch, as used by MBFile times
+pub(crate) fn format_time(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_else(|| secs.to_string())
+}
+
+// Times are in seconds since 2001 up to iOS 10 and in nanoseconds after, 0
+// when unset
+pub(crate) fn apple_time(time: i64) -> Option<String> {
+    if time == 0 {
+        return None;
+    }
+    let secs = if time.abs() > 100_000_000_000 {
+        time / 1_000_000_000
+    } else {
+        time
+    };
+    chrono::DateTime::from_timestamp(secs + APPLE_EPOCH, 0).map(|t| t.to_rfc3339())
+}
+
+// Fields holding separators, quotes or line breaks are quoted
+pub(crate) fn csv_line<T: AsRef<str>>(fields: &[T]) -> String {
+    fields
+        .iter()
+        .map(|x| {
+            let x = x.as_ref();
+            if x.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", x.replace('"', "\"\""))
+            } else {
+                x.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
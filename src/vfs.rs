@@ -1,8 +1,15 @@
 use std::ffi::{c_void, CStr};
 
-use libsqlite3_sys::{sqlite3_file, sqlite3_io_methods, sqlite3_vfs, SQLITE_NOTFOUND, SQLITE_OK};
+use libsqlite3_sys::{
+    sqlite3_file, sqlite3_io_methods, sqlite3_vfs, SQLITE_CANTOPEN, SQLITE_IOERR_FSYNC,
+    SQLITE_IOERR_READ, SQLITE_IOERR_SHORT_READ, SQLITE_NOTFOUND, SQLITE_OK,
+};
 
-use crate::enc_reader::{self, CbcCache};
+use crate::{
+    enc_reader::DecryptingReader,
+    enc_writer,
+    util::{hex, percent_encode},
+};
 
 static METHODS: sqlite3_io_methods = sqlite3_io_methods {
     iVersion: 3,
@@ -12,9 +19,9 @@ static METHODS: sqlite3_io_methods = sqlite3_io_methods {
     xTruncate: None,
     xSync: None,
     xFileSize: Some(file_size),
-    xLock: None,
-    xUnlock: None,
-    xCheckReservedLock: None,
+    xLock: Some(lock),
+    xUnlock: Some(lock),
+    xCheckReservedLock: Some(check_reserved_lock),
     xFileControl: Some(file_control),
     xSectorSize: None,
     xDeviceCharacteristics: Some(device_characteristics),
@@ -33,21 +40,21 @@ unsafe extern "C" fn read(
     offset: i64,
 ) -> i32 {
     let sqlfile = &mut *(mfile as *mut VfsFile);
+    let out = std::slice::from_raw_parts_mut(p_out as *mut u8, len as usize);
 
-    enc_reader::read_encrypted(
-        &sqlfile.stdfile,
-        &mut sqlfile.cbc_cache,
-        p_out as *mut _,
-        len as u64,
-        offset as u64,
-    );
-
-    SQLITE_OK
+    match sqlfile.reader.read_at(out, offset as u64) {
+        Ok(n) if n == out.len() => SQLITE_OK,
+        Ok(n) => {
+            out[n..].fill(0);
+            SQLITE_IOERR_SHORT_READ
+        }
+        Err(_) => SQLITE_IOERR_READ,
+    }
 }
 
 unsafe extern "C" fn file_size(file: *mut sqlite3_file, p_out: *mut i64) -> i32 {
     let file = &mut *(file as *mut VfsFile);
-    *p_out = file.stdfile.metadata().unwrap().len() as i64;
+    *p_out = file.reader.len() as i64;
     SQLITE_OK
 }
 
@@ -56,8 +63,126 @@ unsafe extern "C" fn file_control(_file: *mut sqlite3_file, op: i32, _p_out: *mu
     SQLITE_NOTFOUND
 }
 
-unsafe extern "C" fn device_characteristics(_file: *mut sqlite3_file) -> i32 {
-    libsqlite3_sys::SQLITE_IOCAP_IMMUTABLE
+unsafe extern "C" fn device_characteristics(file: *mut sqlite3_file) -> i32 {
+    let file = &mut *(file as *mut VfsFile);
+    if file.immutable {
+        libsqlite3_sys::SQLITE_IOCAP_IMMUTABLE
+    } else {
+        0
+    }
+}
+
+// Databases opened for writing are decrypted into memory and encrypted again
+// as a whole on every sync, as a write can change the padding of the last block
+static WRITABLE_METHODS: sqlite3_io_methods = sqlite3_io_methods {
+    iVersion: 1,
+    xClose: Some(writable_close),
+    xRead: Some(writable_read),
+    xWrite: Some(writable_write),
+    xTruncate: Some(writable_truncate),
+    xSync: Some(writable_sync),
+    xFileSize: Some(writable_file_size),
+    xLock: Some(lock),
+    xUnlock: Some(lock),
+    xCheckReservedLock: Some(check_reserved_lock),
+    xFileControl: Some(file_control),
+    xSectorSize: None,
+    xDeviceCharacteristics: Some(writable_device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+unsafe extern "C" fn writable_read(
+    file: *mut sqlite3_file,
+    p_out: *mut c_void,
+    len: i32,
+    offset: i64,
+) -> i32 {
+    let file = &mut *(file as *mut WritableFile);
+    let out = std::slice::from_raw_parts_mut(p_out as *mut u8, len as usize);
+
+    let start = std::cmp::min(offset as usize, file.data.len());
+    let end = std::cmp::min(start + out.len(), file.data.len());
+    out[..end - start].copy_from_slice(&file.data[start..end]);
+    if end - start < out.len() {
+        out[end - start..].fill(0);
+        return SQLITE_IOERR_SHORT_READ;
+    }
+    SQLITE_OK
+}
+
+unsafe extern "C" fn writable_write(
+    file: *mut sqlite3_file,
+    p_in: *const c_void,
+    len: i32,
+    offset: i64,
+) -> i32 {
+    let file = &mut *(file as *mut WritableFile);
+    let data = std::slice::from_raw_parts(p_in as *const u8, len as usize);
+
+    let end = offset as usize + data.len();
+    if end > file.data.len() {
+        file.data.resize(end, 0);
+    }
+    file.data[offset as usize..end].copy_from_slice(data);
+    file.dirty = true;
+    SQLITE_OK
+}
+
+unsafe extern "C" fn writable_truncate(file: *mut sqlite3_file, size: i64) -> i32 {
+    let file = &mut *(file as *mut WritableFile);
+    file.data.truncate(size as usize);
+    file.dirty = true;
+    SQLITE_OK
+}
+
+unsafe extern "C" fn writable_sync(file: *mut sqlite3_file, _flags: i32) -> i32 {
+    let file = &mut *(file as *mut WritableFile);
+    match file.sync() {
+        Ok(()) => SQLITE_OK,
+        Err(e) => {
+            eprintln!("Can't write {}: {}", file.path.display(), e);
+            SQLITE_IOERR_FSYNC
+        }
+    }
+}
+
+unsafe extern "C" fn writable_file_size(file: *mut sqlite3_file, p_out: *mut i64) -> i32 {
+    let file = &mut *(file as *mut WritableFile);
+    *p_out = file.data.len() as i64;
+    SQLITE_OK
+}
+
+// Connections opening the database for writing or with a -wal use exclusive
+// locking mode, and the backup is not expected to be changed by anything but
+// the mount
+unsafe extern "C" fn lock(_file: *mut sqlite3_file, _level: i32) -> i32 {
+    SQLITE_OK
+}
+
+unsafe extern "C" fn check_reserved_lock(_file: *mut sqlite3_file, p_out: *mut i32) -> i32 {
+    *p_out = 0;
+    SQLITE_OK
+}
+
+unsafe extern "C" fn writable_device_characteristics(_file: *mut sqlite3_file) -> i32 {
+    0
+}
+
+unsafe extern "C" fn writable_close(file: *mut sqlite3_file) -> i32 {
+    let file = &mut *(file as *mut WritableFile);
+    let res = match file.sync() {
+        Ok(()) => SQLITE_OK,
+        Err(_) => SQLITE_IOERR_FSYNC,
+    };
+    std::ptr::drop_in_place(&mut file.sqlfile);
+    std::ptr::drop_in_place(&mut file.path);
+    std::ptr::drop_in_place(&mut file.data);
+    res
 }
 
 unsafe extern "C" fn open(
@@ -67,19 +192,69 @@ unsafe extern "C" fn open(
     flags: i32,
     p_out_flags: *mut i32,
 ) -> i32 {
-    // TEMP JOURNAL?
-    if zname.is_null() {
+    // The -wal of a backup file is a backup file of its own, named in the URI
+    // of the database
+    let companion = flags & libsqlite3_sys::SQLITE_OPEN_WAL != 0
+        && (*vfs).pAppData.is_null()
+        && !uri_parameter(zname, c"wal").is_null();
+
+    // Temporary files and journals are left to the default vfs, which writes
+    // them to disk unencrypted. Connections to backup files set temp_store and,
+    // when writable, journal_mode to MEMORY so they are never opened.
+    if !companion && (zname.is_null() || flags & libsqlite3_sys::SQLITE_OPEN_MAIN_DB == 0) {
         let vfs = libsqlite3_sys::sqlite3_vfs_find(b"unix-none\0" as *const _ as _);
         return (&*vfs).xOpen.unwrap()(vfs, zname, file, flags, p_out_flags);
     }
 
+    // The vfs for backup files has no key of its own, each file is opened with
+    // its key in the URI
+    let (key_param, path_param, size_param) = match companion {
+        false => (c"key", None, c"size"),
+        true => (c"walkey", Some(c"wal"), c"walsize"),
+    };
+    let key = match ((*vfs).pAppData as *const [u8; 32]).as_ref() {
+        Some(key) => *key,
+        None => match uri_key(zname, key_param) {
+            Some(key) => key,
+            None => return SQLITE_CANTOPEN,
+        },
+    };
+
+    if !companion && flags & libsqlite3_sys::SQLITE_OPEN_READWRITE != 0 {
+        let path = std::path::PathBuf::from(CStr::from_ptr(zname).to_str().unwrap());
+        let Ok(data) = WritableFile::load(&path, key) else {
+            return SQLITE_CANTOPEN;
+        };
+        let file = &mut *(file as *mut WritableFile);
+        file.sqlfile.pMethods = &WRITABLE_METHODS as *const _;
+        std::ptr::write(&mut file.key, key);
+        std::ptr::write(&mut file.path, path);
+        std::ptr::write(&mut file.data, data);
+        std::ptr::write(&mut file.dirty, false);
+        if !p_out_flags.is_null() {
+            *p_out_flags = flags;
+        }
+        return SQLITE_OK;
+    }
+
+    let path = match path_param {
+        None => CStr::from_ptr(zname),
+        Some(param) => CStr::from_ptr(uri_parameter(zname, param)),
+    };
+    let path = path.to_str().unwrap();
+    let Ok(mut reader) = std::fs::File::open(path).and_then(|f| DecryptingReader::new(f, key))
+    else {
+        return SQLITE_CANTOPEN;
+    };
+    // Without the size from the MBFile the padding is served as well
+    if let Some(size) = uri_size(zname, size_param) {
+        reader.set_len(size);
+    }
     let file = &mut *(file as *mut VfsFile);
     file.sqlfile.pMethods = &METHODS as *const _;
-    std::ptr::write(
-        &mut file.stdfile,
-        std::fs::File::open(CStr::from_ptr(zname).to_str().unwrap()).unwrap(),
-    );
-    file.cbc_cache = CbcCache::new(*((*vfs).pAppData as *mut [u8; 32]), &[0; 16], 0);
+    std::ptr::write(&mut file.reader, reader.with_cache(path.as_ref()));
+    // SQLite never looks for the -wal of an immutable database
+    std::ptr::write(&mut file.immutable, uri_parameter(zname, c"wal").is_null());
     *p_out_flags = libsqlite3_sys::SQLITE_OPEN_READONLY;
     //    dbg!(flags);
     SQLITE_OK
@@ -88,29 +263,168 @@ unsafe extern "C" fn open(
 unsafe extern "C" fn close(file: *mut sqlite3_file) -> i32 {
     let file = &mut *(file as *mut VfsFile);
     std::ptr::drop_in_place(&mut file.sqlfile);
+    std::ptr::drop_in_place(&mut file.reader);
     SQLITE_OK
 }
 
+// The -wal of a backup file exists if the URI of the database names its blob
+unsafe extern "C" fn access(
+    vfs: *mut sqlite3_vfs,
+    zname: *const i8,
+    flags: i32,
+    p_out: *mut i32,
+) -> i32 {
+    if (*vfs).pAppData.is_null() && CStr::from_ptr(zname).to_bytes().ends_with(b"-wal") {
+        if let Some(path) = uri_parameter(zname, c"wal").as_ref() {
+            *p_out = std::path::Path::new(CStr::from_ptr(path).to_str().unwrap()).is_file() as i32;
+            return SQLITE_OK;
+        }
+    }
+    let dvfs = &*libsqlite3_sys::sqlite3_vfs_find(std::ptr::null());
+    dvfs.xAccess.unwrap()(vfs, zname, flags, p_out)
+}
+
+// Parameters are also found from the name of the -wal of a database
+unsafe fn uri_parameter(zname: *const i8, name: &CStr) -> *const i8 {
+    if zname.is_null() {
+        return std::ptr::null();
+    }
+    libsqlite3_sys::sqlite3_uri_parameter(zname, name.as_ptr())
+}
+
+// Key given as 64 hex digits in a parameter of the URI
+unsafe fn uri_key(zname: *const i8, name: &CStr) -> Option<[u8; 32]> {
+    let hex = uri_parameter(zname, name);
+    if hex.is_null() {
+        return None;
+    }
+    let hex = CStr::from_ptr(hex).to_str().ok()?;
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (i, x) in key.iter_mut().enumerate() {
+        *x = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+unsafe fn uri_size(zname: *const i8, name: &CStr) -> Option<u64> {
+    let size = uri_parameter(zname, name);
+    if size.is_null() {
+        return None;
+    }
+    CStr::from_ptr(size).to_str().ok()?.parse().ok()
+}
+
 #[repr(C)]
 struct VfsFile {
     sqlfile: sqlite3_file,
-    stdfile: std::fs::File,
-    cbc_cache: CbcCache,
+    reader: DecryptingReader<std::fs::File>,
+    immutable: bool,
+}
+
+#[repr(C)]
+struct WritableFile {
+    sqlfile: sqlite3_file,
+    key: [u8; 32],
+    path: std::path::PathBuf,
+    // Plaintext of the whole database
+    data: Vec<u8>,
+    dirty: bool,
+}
+
+impl WritableFile {
+    fn load(path: &std::path::Path, key: [u8; 32]) -> std::io::Result<Vec<u8>> {
+        let mut reader = DecryptingReader::new(std::fs::File::open(path)?, key)?;
+        let len = reader.padded_len().ok_or(std::io::ErrorKind::InvalidData)?;
+        reader.set_len(len);
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut reader, &mut data)?;
+        Ok(data)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        enc_writer::write_atomic(&self.path, &enc_writer::encrypt(&self.key, &self.data))?;
+        self.dirty = false;
+        Ok(())
+    }
 }
 
-pub(crate) fn register(db_key: [u8; 32]) {
+static REGISTERED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+// Registers a new vfs instance encrypting with db_key and returns its name
+pub(crate) fn register(db_key: [u8; 32]) -> &'static str {
+    let n = REGISTERED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    register_named(
+        format!("iosencryptedvfs{}", n),
+        Box::leak(Box::new(db_key)) as *mut _ as _,
+    )
+}
+
+static FILES: std::sync::OnceLock<&'static str> = std::sync::OnceLock::new();
+
+// Name of the vfs reading any file of a backup (read only), whose key is given
+// by file_uri
+pub(crate) fn files() -> &'static str {
+    FILES.get_or_init(|| register_named("iosencryptedvfs".to_owned(), std::ptr::null_mut()))
+}
+
+// Blob, key and size (from its MBFile) of a backup file
+pub(crate) struct BlobFile {
+    pub path: std::path::PathBuf,
+    pub key: [u8; 32],
+    pub size: u64,
+}
+
+// URI opening a backup file with the vfs returned by files(), along with its
+// -wal if the backup has one. Without a -wal the database is immutable. With
+// one, the connection needs exclusive locking mode so the WAL index is kept in
+// memory instead of a -shm file.
+pub(crate) fn file_uri(db: &BlobFile, wal: Option<&BlobFile>) -> String {
+    let mut uri = format!("file:{}?mode=ro", uri_escape(&db.path));
+    if wal.is_none() {
+        uri.push_str("&immutable=1");
+    }
+    uri.push_str(&format!("&key={}&size={}", hex(&db.key), db.size));
+    if let Some(wal) = wal {
+        uri.push_str(&format!(
+            "&wal={}&walkey={}&walsize={}",
+            uri_escape(&wal.path),
+            hex(&wal.key),
+            wal.size
+        ));
+    }
+    uri
+}
+
+fn uri_escape(path: &std::path::Path) -> String {
+    percent_encode(path.to_str().unwrap())
+}
+
+fn register_named(name: String, app_data: *mut c_void) -> &'static str {
     let dvfs = unsafe { &*libsqlite3_sys::sqlite3_vfs_find(std::ptr::null()) };
 
+    let name: &'static std::ffi::CStr =
+        Box::leak(std::ffi::CString::new(name).unwrap().into_boxed_c_str());
+
+    let size = std::cmp::max(
+        std::mem::size_of::<VfsFile>(),
+        std::mem::size_of::<WritableFile>(),
+    );
     let vfs = Box::leak(Box::new(sqlite3_vfs {
         iVersion: 3,
-        szOsFile: std::cmp::max(std::mem::size_of::<VfsFile>() as i32, dvfs.szOsFile),
+        szOsFile: std::cmp::max(size as i32, dvfs.szOsFile),
         mxPathname: dvfs.mxPathname,
         pNext: std::ptr::null_mut(),
-        zName: b"iosencryptedvfs\0" as *const _ as _,
-        pAppData: Box::leak(Box::new(db_key)) as *mut _ as _,
+        zName: name.as_ptr(),
+        pAppData: app_data,
         xOpen: Some(open),
-        xDelete: None,
-        xAccess: None,
+        xDelete: dvfs.xDelete,
+        xAccess: Some(access),
         xFullPathname: dvfs.xFullPathname,
         xDlOpen: dvfs.xDlOpen,
         xDlError: dvfs.xDlError,
@@ -127,6 +441,8 @@ pub(crate) fn register(db_key: [u8; 32]) {
     }));
 
     unsafe {
-        libsqlite3_sys::sqlite3_vfs_register(vfs, 1);
+        libsqlite3_sys::sqlite3_vfs_register(vfs, 0);
     }
+
+    name.to_str().unwrap()
 }